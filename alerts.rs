@@ -0,0 +1,178 @@
+// Threshold alerts: when a metric stays past a configured threshold for a while a
+// CUSTOM prometheus::alert event is fired, and again once it gets back, so ESL
+// consumers and other modules can react locally without a Prometheus server, e.g.
+//   freeswitch_core_sessions_utilization_ratio > 0.9 for 300 seconds
+// Also the periodic prometheus::snapshot event with the values of some metrics
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::ptr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use freeswitchrs::raw as fsr;
+use freeswitchrs::raw::log_level::NOTICE;
+
+use crate::registry::{glob_match, lock};
+
+pub const ALERT_SUBCLASS: &str = "prometheus::alert";
+pub const SNAPSHOT_SUBCLASS: &str = "prometheus::snapshot";
+const MODULE_NAME: &str = "mod_prometheus";
+
+pub struct Alert {
+    pub name: String,
+    expression: String,
+    metric: String,
+    op: String,
+    threshold: f64,
+    hold: Duration,
+    // Since when the condition holds, None while it does not
+    pending: Option<Instant>,
+    firing: bool,
+}
+
+impl Alert {
+    pub fn parse(name: &str, expression: &str, hold: Duration) -> Result<Alert, String> {
+        let tokens: Vec<&str> = expression.split_whitespace().collect();
+        if tokens.len() != 3 || !["<", "<=", ">", ">="].contains(&tokens[1]) {
+            return Err(format!("Expected <metric> <<|<=|>|>=> <number>: {}", expression));
+        }
+        let threshold = tokens[2].parse::<f64>().map_err(|_| format!("Invalid threshold: {}", tokens[2]))?;
+        Ok(Alert {
+            name: name.to_string(),
+            expression: expression.to_string(),
+            metric: tokens[0].to_string(),
+            op: tokens[1].to_string(),
+            threshold,
+            hold,
+            pending: None,
+            firing: false,
+        })
+    }
+
+    // Some(true) when the alert starts firing, Some(false) when it resolves. A missing
+    // metric or a NaN value never matches the condition
+    pub fn check(&mut self, values: &HashMap<String, f64>, now: Instant) -> Option<bool> {
+        let value = values.get(&self.metric).cloned().unwrap_or(f64::NAN);
+        let matches = match &self.op[..] {
+            "<" => value < self.threshold,
+            "<=" => value <= self.threshold,
+            ">" => value > self.threshold,
+            _ => value >= self.threshold,
+        };
+        if !matches {
+            self.pending = None;
+            if self.firing {
+                self.firing = false;
+                return Some(false);
+            }
+            return None;
+        }
+        let since = *self.pending.get_or_insert(now);
+        if !self.firing && now.duration_since(since) >= self.hold {
+            self.firing = true;
+            return Some(true);
+        }
+        None
+    }
+}
+
+lazy_static! {
+    static ref ALERTS: Mutex<Vec<Alert>> = {
+        Mutex::new(Vec::new())
+    };
+}
+
+// Replace the configured alerts, those left unchanged keep their state so a reload
+// neither fires them again nor loses their resolution
+pub fn configure(alerts: Vec<Alert>) {
+    let mut current = lock(&ALERTS);
+    let mut alerts = alerts;
+    for alert in alerts.iter_mut() {
+        if let Some(old) = current.iter().find(|a| a.name == alert.name && a.expression == alert.expression && a.hold == alert.hold) {
+            alert.pending = old.pending;
+            alert.firing = old.firing;
+        }
+    }
+    *current = alerts;
+}
+
+// Check every alert against the current metric values and fire the events of those
+// changing state
+pub fn evaluate(values: &HashMap<String, f64>) {
+    let now = Instant::now();
+    let mut alerts = lock(&ALERTS);
+    for alert in alerts.iter_mut() {
+        if let Some(firing) = alert.check(values, now) {
+            let value = values.get(&alert.metric).cloned().unwrap_or(f64::NAN);
+            fslog!(NOTICE, "Alert {} {}: {} ({})\n", alert.name,
+                   if firing { "firing" } else { "resolved" }, alert.expression, value);
+            fire(alert, firing, value);
+        }
+    }
+}
+
+fn fire(alert: &Alert, firing: bool, value: f64) {
+    let headers = [
+        ("Alert-Name".to_string(), alert.name.clone()),
+        ("Alert-State".to_string(), if firing { "firing" } else { "resolved" }.to_string()),
+        ("Alert-Expression".to_string(), alert.expression.clone()),
+        ("Alert-Metric".to_string(), alert.metric.clone()),
+        ("Alert-Value".to_string(), value.to_string()),
+        ("Alert-Threshold".to_string(), alert.threshold.to_string()),
+        ("Alert-For".to_string(), alert.hold.as_secs().to_string()),
+    ];
+    fire_custom(ALERT_SUBCLASS, &headers);
+}
+
+// Metrics matching any of the patterns, by name, as headers of a snapshot event
+pub fn snapshot_headers(values: &HashMap<String, f64>, patterns: &[String]) -> Vec<(String, String)> {
+    let mut headers: Vec<(String, String)> = values.iter()
+        .filter(|&(name, _)| patterns.iter().any(|p| glob_match(p.as_bytes(), name.as_bytes())))
+        .map(|(name, value)| (name.clone(), value.to_string()))
+        .collect();
+    headers.sort();
+    headers
+}
+
+pub fn snapshot(values: &HashMap<String, f64>, patterns: &[String]) {
+    fire_custom(SNAPSHOT_SUBCLASS, &snapshot_headers(values, patterns));
+}
+
+// The C strings are owned here and dropped once the event is fired, the core copies the
+// subclass and headers. A header holding a NUL byte can't be passed and is left out
+fn fire_custom(subclass: &str, headers: &[(String, String)]) {
+    let (file, func, subclass) = match (CString::new(file!()), CString::new("fire"), CString::new(subclass)) {
+        (Ok(f), Ok(n), Ok(s)) => (f, n, s),
+        _ => return,
+    };
+    let headers: Vec<(CString, CString)> = headers.iter()
+        .filter_map(|(name, value)| Some((CString::new(&name[..]).ok()?, CString::new(&value[..]).ok()?)))
+        .collect();
+    unsafe {
+        let mut event: *mut fsr::event = ptr::null_mut();
+        let status = fsr::event_create_subclass_detailed(file.as_ptr(), func.as_ptr(), line!() as i32,
+                                                         &mut event, fsr::event_types::CUSTOM, subclass.as_ptr());
+        if status != fsr::status::SUCCESS {
+            return;
+        }
+        for (name, value) in headers.iter() {
+            fsr::event_add_header_string(event, fsr::stack_t::STACK_BOTTOM, name.as_ptr(), value.as_ptr());
+        }
+        fsr::event_fire_detailed(file.as_ptr(), func.as_ptr(), line!() as i32, &mut event, ptr::null_mut());
+    }
+}
+
+pub fn reserve_subclass() {
+    unsafe {
+        fsr::event_reserve_subclass_detailed(fsr::str_to_ptr(MODULE_NAME), fsr::str_to_ptr(ALERT_SUBCLASS));
+        fsr::event_reserve_subclass_detailed(fsr::str_to_ptr(MODULE_NAME), fsr::str_to_ptr(SNAPSHOT_SUBCLASS));
+    }
+}
+
+pub fn clear() {
+    lock(&ALERTS).clear();
+    unsafe {
+        fsr::event_free_subclass_detailed(fsr::str_to_ptr(MODULE_NAME), fsr::str_to_ptr(ALERT_SUBCLASS));
+        fsr::event_free_subclass_detailed(fsr::str_to_ptr(MODULE_NAME), fsr::str_to_ptr(SNAPSHOT_SUBCLASS));
+    }
+}
@@ -0,0 +1,651 @@
+// APIs and applications
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use freeswitchrs::raw as fsr;
+use freeswitchrs::mods::*;
+use freeswitchrs::raw::log_level::{INFO, NOTICE, ERROR};
+
+use crate::config::parse_log_level;
+use crate::events::{JOURNAL, call_log_level, set_call_log_level, events_processed, capped};
+use crate::metrics::{METRICS, UserMetric, UserMetricKind, counter_get, dynamic_labelled_counter_get, gauge_get, summary_get,
+              user_metrics_get, value_get, sessions_peak_reset};
+use crate::registry::{Counter, Gauge, Summary, series_name, valid_label_name, valid_metric_name, sanitize_metric_name};
+use crate::{STATE, config_reload, current_config, current_registry, lock};
+
+lazy_static! {
+    // prom_snapshot metric values by snapshot name
+    static ref SNAPSHOTS: Mutex<HashMap<String, HashMap<String, f64>>> = {
+        Mutex::new(HashMap::new())
+    };
+    // Dialer campaigns with their own counters, up to max-campaigns
+    static ref CAMPAIGNS: Mutex<HashSet<String>> = {
+        Mutex::new(HashSet::new())
+    };
+}
+
+// Steps of the funnel of a dialer campaign, as reported with prom_campaign_event
+const CAMPAIGN_EVENTS: [&str; 5] = ["attempt", "connect", "amd_human", "amd_machine", "amd_unsure"];
+
+pub fn clear() {
+    lock(&SNAPSHOTS).clear();
+    lock(&CAMPAIGNS).clear();
+}
+
+pub fn register(mod_int: &ModInterface) {
+    /* APIs */
+    mod_int.add_raw_api("prom_metrics", "Prometheus Metrics", "Prometheus Metrics", metrics_api);
+    mod_int.add_raw_api("prom_journal", "Recent Metric-Affecting Events", "prom_journal [<count>]", journal_api);
+    mod_int.add_raw_api("prom_snapshot", "Metric Snapshots", "prom_snapshot save|diff|delete <name>", snapshot_api);
+    mod_int.add_raw_api("prom_reload", "Reload Configuration", "Reload Configuration", reload_api);
+    mod_int.add_raw_api("prom_counter_increment", "Increment Counter", "Increment Counter", counter_increment_api);
+    mod_int.add_raw_api("prom_gauge_set", "Set Gauge Value", "Set Gauge Value", gauge_set_api);
+    mod_int.add_raw_api("prom_gauge_increment", "Increase Gauge Value", "Increase Gauge Value", gauge_increment_api);
+    mod_int.add_raw_api("prom_gauge_decrement", "Decrement Gauge Value", "Decrement Gauge Value", gauge_decrement_api);
+    mod_int.add_raw_api("prom_value_set", "Set Untyped Value", "prom_value_set <name> <value>", value_set_api);
+    mod_int.add_raw_api("prom_summary_observe", "Observe Summary Value", "prom_summary_observe <summary> <value>", summary_observe_api);
+    mod_int.add_raw_api("prom_batch", "Batch Metric Updates", "prom_batch <op> <name> [<value>][; <op> <name> [<value>] ...]",
+                        batch_api);
+    mod_int.add_raw_api("prom_campaign_event", "Dialer Campaign Event",
+                        "prom_campaign_event <campaign> <attempt|connect|amd_human|amd_machine|amd_unsure>", campaign_event_api);
+    mod_int.add_raw_api("prom_status", "Exporter Status", "prom_status", status_api);
+    mod_int.add_raw_api("prom_loglevel", "Per-Call Log Level", "prom_loglevel [debug|info|notice]", loglevel_api);
+    mod_int.add_raw_api("prom_get_var", "Get Metric Value", "prom_get_var <metric>", get_var_api);
+    mod_int.add_raw_api("prom_reset_peaks", "Reset Peak Session Gauges", "prom_reset_peaks", reset_peaks_api);
+
+    /* Applications */
+    mod_int.add_raw_application("prom_gauge_increment",
+                                "Increment Gauge", "Increment Gauge",
+                                "prom_gauge_increment <gauge> [<value>]",
+                                gauge_increment_app,
+                                fsr::application_flag_enum::SUPPORT_NOMEDIA);
+    mod_int.add_raw_application("prom_get_var",
+                                "Get Metric Value", "Set a channel variable to the current value of a metric",
+                                "prom_get_var <metric> [<variable>]",
+                                get_var_app,
+                                fsr::application_flag_enum::SUPPORT_NOMEDIA);
+}
+
+// Current value of a metric by its exposed name, collected and derived metrics included.
+// Histograms and summaries by their <name>_sum and <name>_count
+pub fn metric_value(name: &str) -> Option<f64> {
+    let reg = current_registry()?;
+    let value = lock(&reg).value(name);
+    value
+}
+
+// Counters only go up, a negative increment would break rate() and is rejected
+pub fn counter_increment(name: &str, val: f64) -> Result<f64, String> {
+    if val.is_nan() || val < 0.0 {
+        lock(&METRICS.api_misuse).increment();
+        return Err(format!("Invalid increment {} of counter {}, counters can't decrease", val, name));
+    }
+    match counter_get(name) {
+        Some(c) => Ok(lock(&c).increment_by(val)),
+        None => Err("too many user metrics".to_string()),
+    }
+}
+
+// Counts a step of the funnel of a dialer campaign, e.g.
+// freeswitch_campaign_events_total{campaign="spring_sale",event="connect"}
+pub fn campaign_event(cmd: &str) -> Result<f64, String> {
+    let args: Vec<&str> = cmd.split_whitespace().collect();
+    let (campaign, event) = match &args[..] {
+        &[c, e] if CAMPAIGN_EVENTS.contains(&e) => (c, e),
+        _ => {
+            lock(&METRICS.api_misuse).increment();
+            return Err(format!("Invalid campaign event {:?}, expected <campaign> <{}>", cmd, CAMPAIGN_EVENTS.join("|")));
+        }
+    };
+    Ok(campaign_count(campaign, event))
+}
+
+// Increments the counter of a campaign event, the campaigns being capped
+pub fn campaign_count(campaign: &str, event: &str) -> f64 {
+    let campaign = capped(&CAMPAIGNS, campaign.to_string(), current_config().max_campaigns);
+    lock(&dynamic_labelled_counter_get("freeswitch_campaign_events_total", &[("campaign", &campaign), ("event", event)],
+                                 "FreeSWITCH Dialer Campaign Events")).increment()
+}
+
+// An operation of a prom_batch or metric update event, on a metric already looked up
+enum Update {
+    CounterIncrement(Arc<Mutex<Counter>>, f64),
+    GaugeSet(Arc<Mutex<Gauge>>, f64),
+    GaugeIncrement(Arc<Mutex<Gauge>>, f64),
+    GaugeDecrement(Arc<Mutex<Gauge>>, f64),
+    SummaryObserve(Arc<Mutex<Summary>>, f64),
+}
+
+// Semicolon separated "<op> <name> [<value>]" operations, op being the name of the API
+// without the prom_ prefix (counter_increment, gauge_set, value_set ...). Either all of
+// them are applied, under the registry lock so no scrape sees half of them, or none when
+// any is invalid or there is no room for the new metrics. Returns how many were applied
+pub fn batch(cmd: &str) -> Result<usize, String> {
+    // All checked before creating any metric
+    let mut ops = Vec::new();
+    for op in cmd.split(';').map(|o| o.trim()).filter(|o| !o.is_empty()) {
+        let args: Vec<&str> = op.split_whitespace().collect();
+        let name = args.get(1).cloned().unwrap_or("");
+        if !valid_metric_name(name) {
+            return Err(format!("Invalid metric name {:?} in {:?}", name, op));
+        }
+        let val = match args.get(2).map(|v| v.parse::<f64>()) {
+            Some(Ok(v)) if args.len() == 3 => v,
+            None => 1.0,
+            _ => return Err(format!("Invalid metric value in {:?}", op)),
+        };
+        ops.push(operation(args[0], name.to_string(), val)?);
+    }
+    apply(&ops)
+}
+
+// A single operation of a metric update event, on the series of the metric with the
+// labels given
+pub fn update(op: &str, name: &str, labels: &[(&str, &str)], val: f64) -> Result<(), String> {
+    if !valid_metric_name(name) {
+        return Err(format!("Invalid metric name {:?}", name));
+    }
+    if let Some(&(label, _)) = labels.iter().find(|&&(l, _)| !valid_label_name(l)) {
+        return Err(format!("Invalid label name {:?} of {}", label, name));
+    }
+    if !labels.is_empty() && op == "summary_observe" {
+        return Err(format!("Summary {} can't have labels", name));
+    }
+    apply(&[operation(op, series_name(name, labels), val)?]).map(|_| ())
+}
+
+// Checks an operation on the series of a user metric, before looking the metric up
+fn operation(op: &str, series: String, val: f64) -> Result<(UserMetricKind, &'static str, String, f64), String> {
+    let (kind, op) = match op {
+        "counter_increment" => (UserMetricKind::Counter, "counter_increment"),
+        "gauge_set" => (UserMetricKind::Gauge, "gauge_set"),
+        "gauge_increment" => (UserMetricKind::Gauge, "gauge_increment"),
+        "gauge_decrement" => (UserMetricKind::Gauge, "gauge_decrement"),
+        "summary_observe" => (UserMetricKind::Summary, "summary_observe"),
+        "value_set" => (UserMetricKind::Value, "value_set"),
+        _ => return Err(format!("Invalid operation {:?}", op)),
+    };
+    if op == "counter_increment" && (val.is_nan() || val < 0.0) {
+        lock(&METRICS.api_misuse).increment();
+        return Err(format!("Invalid increment {} of counter {}, counters can't decrease", val, series));
+    }
+    Ok((kind, op, series, val))
+}
+
+// Looks up the metrics of the operations and applies them all, or none when there is no
+// room for the new metrics
+fn apply(ops: &[(UserMetricKind, &str, String, f64)]) -> Result<usize, String> {
+    let wanted: Vec<(UserMetricKind, &str)> = ops.iter().map(|&(kind, _, ref series, _)| (kind, &series[..])).collect();
+    let metrics = user_metrics_get(&wanted).ok_or_else(|| "too many user metrics".to_string())?;
+    let updates: Vec<Update> = ops.iter().zip(metrics).map(|(&(_, op, _, val), metric)| match (op, metric) {
+        (_, UserMetric::Counter(c)) => Update::CounterIncrement(c, val),
+        (_, UserMetric::Summary(s)) => Update::SummaryObserve(s, val),
+        ("gauge_increment", UserMetric::Gauge(g)) => Update::GaugeIncrement(g, val),
+        ("gauge_decrement", UserMetric::Gauge(g)) => Update::GaugeDecrement(g, val),
+        (_, UserMetric::Gauge(g)) | (_, UserMetric::Value(g)) => Update::GaugeSet(g, val),
+    }).collect();
+    let reg = current_registry();
+    let _lock = reg.as_ref().map(|r| lock(r));
+    for update in updates.iter() {
+        match *update {
+            Update::CounterIncrement(ref c, v) => { lock(c).increment_by(v); }
+            Update::GaugeSet(ref g, v) => { lock(g).set(v); }
+            Update::GaugeIncrement(ref g, v) => { lock(g).increment_by(v); }
+            Update::GaugeDecrement(ref g, v) => { lock(g).decrement_by(v); }
+            Update::SummaryObserve(ref s, v) => { lock(s).observe(v); }
+        }
+    }
+    Ok(updates.len())
+}
+
+#[allow(unused_variables)]
+unsafe extern "C" fn batch_api(cmd: *const std::os::raw::c_char,
+                               session: *mut fsr::core_session,
+                               stream: *mut fsr::stream_handle)
+                               -> fsr::status {
+    let cmd = fsr::ptr_to_str(cmd).map(|c| c.to_string()).unwrap_or_default();
+    let out = match batch(&cmd) {
+        Ok(n) => format!("+OK {}", n),
+        Err(e) => format!("-ERR {}", e),
+    };
+    (*stream).write_function.unwrap()(stream, fsr::str_to_ptr("%s"), fsr::str_to_ptr(&out));
+    if out.starts_with("+OK") { fsr::status::SUCCESS } else { fsr::status::FALSE }
+}
+
+#[allow(unused_variables)]
+unsafe extern "C" fn campaign_event_api(cmd: *const std::os::raw::c_char,
+                                        session: *mut fsr::core_session,
+                                        stream: *mut fsr::stream_handle)
+                                        -> fsr::status {
+    let cmd = fsr::ptr_to_str(cmd).map(|c| c.to_string()).unwrap_or_default();
+    let out = match campaign_event(&cmd) {
+        Ok(v) => format!("+OK {}", v),
+        Err(e) => format!("-ERR {}", e),
+    };
+    (*stream).write_function.unwrap()(stream, fsr::str_to_ptr("%s"), fsr::str_to_ptr(&out));
+    if out.starts_with("+OK") { fsr::status::SUCCESS } else { fsr::status::FALSE }
+}
+
+fn parse_metric_api_args(cmd: *const std::os::raw::c_char,
+                         stream: Option<*mut fsr::stream_handle>)
+                         -> Option<(String, f64)> {
+    let cmdstr = match unsafe { fsr::ptr_to_str(cmd) } {
+        Some(c) => c,
+        None => {
+            if let Some(s) = stream {
+                unsafe { (*s).write_function.unwrap()(s, fsr::str_to_ptr("Invalid arguments")); }
+            } else {
+                fslog!(ERROR, "Invalid metric arguments");
+            }
+            return None;
+        }
+    };
+    let args: Vec<&str> = cmdstr.split(' ').collect();
+    let name = args[0];
+    if !valid_metric_name(name) {
+        let err = format!("-ERR Invalid metric name {:?}, metric names must match [a-zA-Z_:][a-zA-Z0-9_:]* (e.g. {})",
+                          name, sanitize_metric_name(name));
+        if let Some(s) = stream {
+            unsafe { (*s).write_function.unwrap()(s, fsr::str_to_ptr("%s"), fsr::str_to_ptr(&err)); }
+        } else {
+            fslog!(ERROR, "{}", err);
+        }
+        return None;
+    }
+    let val = match args.get(1).map(|v| v.parse::<f64>()) {
+        Some(Ok(v)) => v,
+        Some(Err(_)) => {
+            if let Some(s) = stream {
+                unsafe { (*s).write_function.unwrap()(s, fsr::str_to_ptr("Invalid metric value")); }
+            } else {
+                fslog!(ERROR, "Invalid metric value");
+            }
+            return None;
+        }
+        None => 1.0,
+    };
+    Some((name.to_string(), val))
+}
+
+#[allow(unused_variables)]
+unsafe extern "C" fn counter_increment_api(cmd: *const std::os::raw::c_char,
+                                           session: *mut fsr::core_session,
+                                           stream: *mut fsr::stream_handle)
+                                           -> fsr::status {
+    let (name, val) = match parse_metric_api_args(cmd, Some(stream)) {
+        Some(args) => args,
+        None => return fsr::status::FALSE,
+    };
+    let v = match counter_increment(&name, val) {
+        Ok(v) => v,
+        Err(e) => {
+            let err = format!("-ERR {}", e);
+            (*stream).write_function.unwrap()(stream, fsr::str_to_ptr("%s"), fsr::str_to_ptr(&err));
+            return fsr::status::FALSE;
+        }
+    };
+    let out = format!("+OK {}", v);
+    (*stream).write_function.unwrap()(stream, fsr::str_to_ptr(&out));
+    fsr::status::SUCCESS
+}
+
+// Metrics in the exposition format, mod_xml_rpc serves it at /txtapi/prom_metrics
+// so it can be scraped without opening another port
+#[allow(unused_variables)]
+unsafe extern "C" fn metrics_api(cmd: *const std::os::raw::c_char,
+                                 session: *mut fsr::core_session,
+                                 stream: *mut fsr::stream_handle)
+                                 -> fsr::status {
+    let reg = match current_registry() {
+        Some(r) => r,
+        None => {
+            (*stream).write_function.unwrap()(stream, fsr::str_to_ptr("-ERR module not loaded\n"));
+            return fsr::status::SUCCESS;
+        }
+    };
+    let out = lock(&reg).render();
+    (*stream).write_function.unwrap()(stream, fsr::str_to_ptr("%s"), fsr::str_to_ptr(&out));
+    fsr::status::SUCCESS
+}
+
+// Journal of the last metric-affecting events, optionally only the last <count> ones
+#[allow(unused_variables)]
+unsafe extern "C" fn journal_api(cmd: *const std::os::raw::c_char,
+                                 session: *mut fsr::core_session,
+                                 stream: *mut fsr::stream_handle)
+                                 -> fsr::status {
+    if current_config().journal_size == 0 {
+        (*stream).write_function.unwrap()(stream, fsr::str_to_ptr("-ERR journal disabled, set journal-size to enable it\n"));
+        return fsr::status::SUCCESS;
+    }
+    let journal = lock(&JOURNAL);
+    let count = match fsr::ptr_to_str(cmd) {
+        Some(c) => c.trim().parse::<usize>().unwrap_or(journal.len()),
+        None => journal.len(),
+    };
+    let mut out = String::new();
+    for line in journal.iter().skip(journal.len().saturating_sub(count)) {
+        out.push_str(line);
+        out.push('\n');
+    }
+    (*stream).write_function.unwrap()(stream, fsr::str_to_ptr("%s"), fsr::str_to_ptr(&out));
+    fsr::status::SUCCESS
+}
+
+// Save the current metric values under a name and later print what changed since, e.g.
+// to check which metrics a test call moved
+#[allow(unused_variables)]
+unsafe extern "C" fn snapshot_api(cmd: *const std::os::raw::c_char,
+                                  session: *mut fsr::core_session,
+                                  stream: *mut fsr::stream_handle)
+                                  -> fsr::status {
+    let cmdstr = fsr::ptr_to_str(cmd).map(|c| c.to_string()).unwrap_or(String::new());
+    let args: Vec<&str> = cmdstr.split_whitespace().collect();
+    if args.len() != 2 {
+        (*stream).write_function.unwrap()(stream, fsr::str_to_ptr("-USAGE: prom_snapshot save|diff|delete <name>\n"));
+        return fsr::status::SUCCESS;
+    }
+    let reg = match current_registry() {
+        Some(r) => r,
+        None => {
+            (*stream).write_function.unwrap()(stream, fsr::str_to_ptr("-ERR module not loaded\n"));
+            return fsr::status::SUCCESS;
+        }
+    };
+    let mut snapshots = lock(&SNAPSHOTS);
+    let out = match args[0] {
+        "save" => {
+            let values = lock(&reg).values();
+            snapshots.insert(args[1].to_string(), values.into_iter().collect());
+            "+OK\n".to_string()
+        }
+        "diff" => {
+            match snapshots.get(args[1]) {
+                Some(saved) => {
+                    let mut out = String::new();
+                    for (name, value) in lock(&reg).values() {
+                        let delta = value - saved.get(&name).cloned().unwrap_or(0 as f64);
+                        if delta != 0 as f64 {
+                            out.push_str(&format!("{} {} ({:+})\n", name, value, delta));
+                        }
+                    }
+                    out
+                }
+                None => format!("-ERR no snapshot named {}\n", args[1]),
+            }
+        }
+        "delete" => {
+            match snapshots.remove(args[1]) {
+                Some(_) => "+OK\n".to_string(),
+                None => format!("-ERR no snapshot named {}\n", args[1]),
+            }
+        }
+        _ => "-USAGE: prom_snapshot save|diff|delete <name>\n".to_string(),
+    };
+    (*stream).write_function.unwrap()(stream, fsr::str_to_ptr("%s"), fsr::str_to_ptr(&out));
+    fsr::status::SUCCESS
+}
+
+#[allow(unused_variables)]
+unsafe extern "C" fn reload_api(cmd: *const std::os::raw::c_char,
+                                session: *mut fsr::core_session,
+                                stream: *mut fsr::stream_handle)
+                                -> fsr::status {
+    config_reload();
+    (*stream).write_function.unwrap()(stream, fsr::str_to_ptr("+OK\n"));
+    fsr::status::SUCCESS
+}
+
+// Where the metrics are served, what is registered and when they were last scraped, the
+// first things to check when no data shows up in Grafana
+#[allow(unused_variables)]
+unsafe extern "C" fn status_api(cmd: *const std::os::raw::c_char,
+                                session: *mut fsr::core_session,
+                                stream: *mut fsr::stream_handle)
+                                -> fsr::status {
+    let out = match *lock(&STATE) {
+        Some(ref state) => {
+            let mut out = String::new();
+            if state.servers.is_empty() {
+                out.push_str("listener: disabled (prom_metrics API only)\n");
+            }
+            for server in state.servers.iter() {
+                match server.address() {
+                    Some(addr) => out.push_str(&format!("listener: {} (proxy-protocol: {})\n", addr,
+                                                        if server.proxy_protocol() { "on" } else { "off" })),
+                    None => out.push_str("listener: not running\n"),
+                }
+            }
+            out.push_str("tls: not supported\nauth: none\n");
+            let (metrics, last_scrape) = {
+                let r = lock(&state.registry);
+                (r.metric_count(), r.last_scrape())
+            };
+            out.push_str(&format!("metrics: {}\n", metrics));
+            out.push_str(&format!("event bindings: {} ({} disabled)\n", state.event_node_ids.len(),
+                                  current_config().disabled_bindings.len()));
+            out.push_str(&format!("events processed: {}\n", events_processed()));
+            match last_scrape.and_then(|t| SystemTime::now().duration_since(t).ok()) {
+                Some(ago) => out.push_str(&format!("last scrape: {} seconds ago\n", ago.as_secs())),
+                None => out.push_str("last scrape: never\n"),
+            }
+            for (client, time) in state.workers.metrics().clients() {
+                let ago = SystemTime::now().duration_since(time).map(|d| d.as_secs()).unwrap_or(0);
+                out.push_str(&format!("scrape client: {} {} seconds ago\n", client, ago));
+            }
+            out
+        }
+        None => "-ERR module not loaded\n".to_string(),
+    };
+    (*stream).write_function.unwrap()(stream, fsr::str_to_ptr("%s"), fsr::str_to_ptr(&out));
+    fsr::status::SUCCESS
+}
+
+// Shows or changes the level of the per-call log lines until the next reload
+#[allow(unused_variables)]
+unsafe extern "C" fn loglevel_api(cmd: *const std::os::raw::c_char,
+                                  session: *mut fsr::core_session,
+                                  stream: *mut fsr::stream_handle)
+                                  -> fsr::status {
+    let level = fsr::ptr_to_str(cmd).map(|c| c.trim().to_string()).unwrap_or(String::new());
+    let out = if level.is_empty() {
+        format!("+OK {}\n", log_level_name(call_log_level()))
+    } else {
+        match parse_log_level(&level) {
+            Some(l) => {
+                set_call_log_level(l);
+                format!("+OK {}\n", level)
+            }
+            None => "-USAGE: prom_loglevel [debug|info|notice]\n".to_string(),
+        }
+    };
+    (*stream).write_function.unwrap()(stream, fsr::str_to_ptr("%s"), fsr::str_to_ptr(&out));
+    fsr::status::SUCCESS
+}
+
+fn log_level_name(level: fsr::log_level::Type) -> &'static str {
+    match level {
+        INFO => "info",
+        NOTICE => "notice",
+        _ => "debug",
+    }
+}
+
+#[allow(unused_variables)]
+unsafe extern "C" fn gauge_set_api(cmd: *const std::os::raw::c_char,
+                                   session: *mut fsr::core_session,
+                                   stream: *mut fsr::stream_handle)
+                                   -> fsr::status {
+    let (name, val) = match parse_metric_api_args(cmd, Some(stream)) {
+        Some(args) => args,
+        None => return fsr::status::FALSE,
+    };
+    let gauge = match gauge_get(&name) {
+        Some(g) => g,
+        None => {
+            (*stream).write_function.unwrap()(stream, fsr::str_to_ptr("-ERR too many user metrics"));
+            return fsr::status::FALSE;
+        }
+    };
+    let v = lock(&gauge).set(val);
+    let out = format!("+OK {}", v);
+    (*stream).write_function.unwrap()(stream, fsr::str_to_ptr(&out));
+    fsr::status::SUCCESS
+}
+
+#[allow(unused_variables)]
+unsafe extern "C" fn value_set_api(cmd: *const std::os::raw::c_char,
+                                   session: *mut fsr::core_session,
+                                   stream: *mut fsr::stream_handle)
+                                   -> fsr::status {
+    let (name, val) = match parse_metric_api_args(cmd, Some(stream)) {
+        Some(args) => args,
+        None => return fsr::status::FALSE,
+    };
+    let value = match value_get(&name) {
+        Some(v) => v,
+        None => {
+            (*stream).write_function.unwrap()(stream, fsr::str_to_ptr("-ERR too many user metrics"));
+            return fsr::status::FALSE;
+        }
+    };
+    let v = lock(&value).set(val);
+    let out = format!("+OK {}", v);
+    (*stream).write_function.unwrap()(stream, fsr::str_to_ptr(&out));
+    fsr::status::SUCCESS
+}
+
+#[allow(unused_variables)]
+unsafe extern "C" fn gauge_increment_api(cmd: *const std::os::raw::c_char,
+                                         session: *mut fsr::core_session,
+                                         stream: *mut fsr::stream_handle)
+                                         -> fsr::status {
+    let (name, val) = match parse_metric_api_args(cmd, Some(stream)) {
+        Some(args) => args,
+        None => return fsr::status::FALSE,
+    };
+    let gauge = match gauge_get(&name) {
+        Some(g) => g,
+        None => {
+            (*stream).write_function.unwrap()(stream, fsr::str_to_ptr("-ERR too many user metrics"));
+            return fsr::status::FALSE;
+        }
+    };
+    let v = lock(&gauge).increment_by(val);
+    let out = format!("+OK {}", v);
+    (*stream).write_function.unwrap()(stream, fsr::str_to_ptr(&out));
+    fsr::status::SUCCESS
+}
+
+#[allow(unused_variables)]
+unsafe extern "C" fn gauge_decrement_api(cmd: *const std::os::raw::c_char,
+                                         session: *mut fsr::core_session,
+                                         stream: *mut fsr::stream_handle)
+                                         -> fsr::status {
+    let (name, val) = match parse_metric_api_args(cmd, Some(stream)) {
+        Some(args) => args,
+        None => return fsr::status::FALSE,
+    };
+    let gauge = match gauge_get(&name) {
+        Some(g) => g,
+        None => {
+            (*stream).write_function.unwrap()(stream, fsr::str_to_ptr("-ERR too many user metrics"));
+            return fsr::status::FALSE;
+        }
+    };
+    let v = lock(&gauge).decrement_by(val);
+    let out = format!("+OK {}", v);
+    (*stream).write_function.unwrap()(stream, fsr::str_to_ptr(&out));
+    fsr::status::SUCCESS
+}
+
+// Adds an observation (e.g. a duration) to a summary, replies with the observation count
+#[allow(unused_variables)]
+unsafe extern "C" fn summary_observe_api(cmd: *const std::os::raw::c_char,
+                                         session: *mut fsr::core_session,
+                                         stream: *mut fsr::stream_handle)
+                                         -> fsr::status {
+    let (name, val) = match parse_metric_api_args(cmd, Some(stream)) {
+        Some(args) => args,
+        None => return fsr::status::FALSE,
+    };
+    let summary = match summary_get(&name) {
+        Some(s) => s,
+        None => {
+            (*stream).write_function.unwrap()(stream, fsr::str_to_ptr("-ERR too many user metrics"));
+            return fsr::status::FALSE;
+        }
+    };
+    let mut s = lock(&summary);
+    s.observe(val);
+    let out = format!("+OK {}", s.count());
+    (*stream).write_function.unwrap()(stream, fsr::str_to_ptr(&out));
+    fsr::status::SUCCESS
+}
+
+// Starts the peak active session gauges over from the sessions active now, e.g. at the
+// start of a billing period
+#[allow(unused_variables)]
+unsafe extern "C" fn reset_peaks_api(cmd: *const std::os::raw::c_char,
+                                     session: *mut fsr::core_session,
+                                     stream: *mut fsr::stream_handle)
+                                     -> fsr::status {
+    sessions_peak_reset();
+    (*stream).write_function.unwrap()(stream, fsr::str_to_ptr("+OK\n"));
+    fsr::status::SUCCESS
+}
+
+// Replies with the bare value so it can be used in the dialplan as ${prom_get_var(<metric>)}
+#[allow(unused_variables)]
+unsafe extern "C" fn get_var_api(cmd: *const std::os::raw::c_char,
+                                 session: *mut fsr::core_session,
+                                 stream: *mut fsr::stream_handle)
+                                 -> fsr::status {
+    let name = fsr::ptr_to_str(cmd).map(|c| c.trim().to_string()).unwrap_or(String::new());
+    let out = if name.is_empty() {
+        "-USAGE: prom_get_var <metric>".to_string()
+    } else {
+        match metric_value(&name) {
+            Some(v) => v.to_string(),
+            None => format!("-ERR no metric named {}", name),
+        }
+    };
+    (*stream).write_function.unwrap()(stream, fsr::str_to_ptr("%s"), fsr::str_to_ptr(&out));
+    fsr::status::SUCCESS
+}
+
+// Sets <variable> (the metric name by default) to the metric value, e.g. to play a busy
+// message when freeswitch_sessions_current is over a limit
+#[allow(unused_variables)]
+unsafe extern "C" fn get_var_app(session: *mut fsr::core_session,
+                                 data: *const std::os::raw::c_char) {
+    let data = fsr::ptr_to_str(data).map(|d| d.to_string()).unwrap_or(String::new());
+    let args: Vec<&str> = data.split_whitespace().collect();
+    if args.is_empty() || args.len() > 2 {
+        fslog!(ERROR, "Usage: prom_get_var <metric> [<variable>]\n");
+        return;
+    }
+    let variable = args.get(1).cloned().unwrap_or(args[0]);
+    match metric_value(args[0]) {
+        Some(v) => {
+            let channel = fsr::core_session_get_channel(session);
+            fsr::channel_set_variable_var_check(channel, fsr::str_to_ptr(variable), fsr::str_to_ptr(&v.to_string()),
+                                                fsr::bool_t::TRUE);
+        }
+        None => fslog!(ERROR, "Can't get {}, no metric with that name\n", args[0]),
+    }
+}
+
+#[allow(unused_variables)]
+unsafe extern "C" fn gauge_increment_app(session: *mut fsr::core_session,
+                                         data: *const std::os::raw::c_char) {
+    if let Some((name, val)) = parse_metric_api_args(data, None) {
+        if let Some(gauge) = gauge_get(&name) {
+            let v = lock(&gauge).increment_by(val);
+            fslog!(INFO, "Incremented gauge {} to {}", name, v);
+        } else {
+            fslog!(ERROR, "Can't increment gauge {}, too many user metrics", name);
+        }
+    }
+}
@@ -0,0 +1,894 @@
+// Module settings, read from the <settings> of prometheus.conf.xml
+use std::env;
+use std::net::{IpAddr, Ipv6Addr};
+use std::time::Duration;
+
+use freeswitchrs::raw as fsr;
+use freeswitchrs::raw::log_level::{DEBUG, INFO, NOTICE, WARNING, ERROR};
+
+use crate::discovery::http_get;
+
+pub static LISTENING_DEFAULT_PORT: &str = "9282";
+pub static LISTENING_ENV_PORT: &str = "MOD_PROMETHEUS_PORT";
+pub static ENV_PREFIX: &str = "MOD_PROMETHEUS_";
+pub static CONFIG_FILE: &str = "prometheus.conf";
+
+// Settings read from prometheus.conf.xml, see load_config()
+pub struct Config {
+    // Minimum billsec for a gateway call to be counted as billable
+    pub billable_min_seconds: u64,
+    // Answered calls billed below short_call_seconds or above long_call_seconds are counted
+    // as short (e.g. fraud, false answer supervision) or long (e.g. stuck) calls
+    pub short_call_seconds: u64,
+    pub long_call_seconds: u64,
+    // Serve the metrics on our own port, can be disabled when scraping
+    // through the prom_metrics API served by mod_xml_rpc instead
+    pub http_listener: bool,
+    // Addresses (host and optional port) to listen on, all serving the same metrics.
+    // Empty to listen on all the addresses
+    pub listen_addresses: Vec<(String, Option<u16>)>,
+    // Ports tried in order when the listen port is in use (e.g. by another FreeSWITCH instance)
+    pub listen_port_range: Vec<u16>,
+    // Append the time of the last change to counter and gauge samples
+    pub metric_timestamps: bool,
+    // Expose the creation time of the counters as <name>_created gauges
+    pub created_metrics: bool,
+    // Attach the Unique-ID and SIP Call-ID of a call to the call duration histogram buckets
+    // as OpenMetrics exemplars, served in the OpenMetrics format to the scrapers accepting it
+    pub exemplars: bool,
+    // Replaces the "freeswitch_" prefix of all metrics when set
+    pub metric_prefix: Option<String>,
+    // Number of metric-affecting events kept for prom_journal, 0 disables the journal
+    pub journal_size: usize,
+    // Listener behind a proxy: PROXY protocol and trusted X-Forwarded-For sources
+    pub proxy_protocol: bool,
+    pub trusted_proxies: Vec<IpAddr>,
+    // FreeSWITCH ACL the (real) client address must be allowed by to get the metrics
+    pub http_acl: Option<String>,
+    // Threads of the async runtime serving the scrapes of all the listeners. Set on load,
+    // changes need a module reload
+    pub http_workers: usize,
+    // Connections open at once, any more get a 503, and how long to wait on a slow client
+    pub http_max_connections: usize,
+    pub http_read_timeout: Duration,
+    pub http_write_timeout: Duration,
+    // Log every scrape with the client, response size and duration at DEBUG
+    pub http_access_log: bool,
+    // Limit of metrics created through the APIs, 0 for no limit, and whether to evict the
+    // least recently used one (or reject the new one) when it's reached
+    pub max_user_metrics: usize,
+    pub evict_user_metrics: bool,
+    // User metrics not used for this long are removed, 0 to keep them forever
+    pub user_metrics_ttl: Duration,
+    // File the user metrics are saved to on unload and restored from on load
+    pub user_metrics_file: Option<String>,
+    // File the built-in counters are checkpointed to every counters_checkpoint_interval
+    // and restored from on load, so counters survive FreeSWITCH restarts
+    pub counters_file: Option<String>,
+    pub counters_checkpoint_interval: Duration,
+    // The peak active session gauges start over from the current values this often, 0 to
+    // only reset them with the prom_reset_peaks API
+    pub peak_reset_interval: Duration,
+    // A prometheus::snapshot event with the metrics matching snapshot_metrics is fired this
+    // often, 0 to not fire it
+    pub snapshot_interval: Duration,
+    pub snapshot_metrics: Vec<String>,
+    // Configuration document fetched on load and reload, applied over the local file
+    pub config_url: Option<String>,
+    // Event headers naming the tenant of a session (e.g. variable_domain_name), the first
+    // one present wins. Empty to not have per tenant session metrics
+    pub tenant_headers: Vec<String>,
+    // Tenants with their own metrics, sessions of any other tenant are counted as "other"
+    pub max_tenants: usize,
+    // Histogram of the billsec of the sessions of each accountcode, for up to max_accountcodes
+    // accountcodes, any other one is counted as "other"
+    pub billsec_histograms: bool,
+    pub max_accountcodes: usize,
+    // MOS and packet loss histograms per far-end network, the remote media IP masked with
+    // remote_network_mask (IPv4) or remote_network_mask_v6 bits, for up to
+    // max_remote_networks networks, any other one is counted as "other"
+    pub remote_network_histograms: bool,
+    pub remote_network_mask: u8,
+    pub remote_network_mask_v6: u8,
+    pub max_remote_networks: usize,
+    // Wait time histograms per queue, for up to max_queues queues, any other one is counted as
+    // "other". Besides mod_callcenter queues, channels carrying the queue_variable channel
+    // variable are observed when bridged, from queue_start_variable or from their answer
+    pub max_queues: usize,
+    pub queue_variable: Option<String>,
+    pub queue_start_variable: Option<String>,
+    // Dialer campaigns counted by prom_campaign_event, any other one is counted as "other"
+    pub max_campaigns: usize,
+    // Channel variables holding the answering machine detection result of a session (e.g.
+    // amd_result of mod_amd), the first one set wins, and the one naming its campaign
+    pub amd_variables: Vec<String>,
+    pub campaign_variable: Option<String>,
+    // Hangup causes (or originate dispositions) of the sessions refused because the switch
+    // was over max-sessions or sessions-per-second
+    pub limit_hangup_causes: Vec<String>,
+    // Channel-Name patterns (* and ? wildcards) of the internal channels, e.g. loopback/*, left
+    // out of the channel event metrics or, with internal_channels_as_other, counted as sessions
+    // of the other direction so they don't skew the inbound and outbound ones
+    pub internal_channels: Vec<String>,
+    pub internal_channels_as_other: bool,
+    // Limit realm and resource pairs with their own usage and rejection metrics, any
+    // other one is counted as "other"
+    pub max_limit_resources: usize,
+    // Dialplan applications with their own execution counters, any other one is counted
+    // as "other"
+    pub max_applications: usize,
+    // API commands with their own execution counters, and background job commands with their
+    // own job counters, any other one is counted as "other"
+    pub max_api_commands: usize,
+    // Sound languages with their own playback series, any other one is counted as "other"
+    pub max_sound_languages: usize,
+    // Valet parking lots with their own parked calls gauge, any other one is counted as "other"
+    pub max_valet_lots: usize,
+    // Channel variable holding the number of routes tried for a call (e.g. set by an LCR
+    // dialplan), counting its bridge applications otherwise
+    pub route_attempts_variable: Option<String>,
+    // Fraud signals: outbound calls to the fraud_prefixes (high-rate destinations), inbound
+    // calls of a user with more than fraud_max_user_calls calls up (0 to not check), and
+    // outbound calls to the international prefixes outside of the business hours (start
+    // and end hour, local time, None to not check)
+    pub fraud_prefixes: Vec<String>,
+    pub fraud_max_user_calls: usize,
+    pub international_prefixes: Vec<String>,
+    pub business_hours: Option<(u32, u32)>,
+    // Quantiles of the summaries (built-in and prom_summary_observe ones), computed over
+    // the observations of the last summary_window
+    pub summary_quantiles: Vec<f64>,
+    pub summary_window: Duration,
+    // Expose the billed duration of calls as summaries
+    pub duration_summaries: bool,
+    // Service discovery: file_sd JSON file and Consul agent URL (http://<host>:<port>) the
+    // endpoint is announced to, sd_address being the host:port announced (by default the
+    // FreeSWITCH hostname and the port listened on) under the sd_service name
+    pub sd_file: Option<String>,
+    pub consul_url: Option<String>,
+    pub sd_address: Option<String>,
+    pub sd_service: String,
+    // Level of the per-call log lines (log-verbosity: debug, info or notice)
+    pub call_log_level: fsr::log_level::Type,
+    // Built-in event bindings not to bind (e.g. channel_hangup_complete), their metrics
+    // stay at zero. Bindings are set up on load, changes need a module reload
+    pub disabled_bindings: Vec<String>,
+    // Glob patterns of metrics not to expose, including those of disabled metric groups
+    pub deny_metrics: Vec<String>,
+    // <derived-metrics> name, help and expression of each metric
+    pub derived_metrics: Vec<(String, String, String)>,
+    // <alerts> name, expression and for (seconds) of each alert
+    pub alerts: Vec<(String, String, Duration)>,
+    // <routes> name and destination number prefixes of each route group
+    pub routes: Vec<(String, Vec<String>)>,
+    // <countries> name and calling code prefixes of each country calls are bucketed into
+    pub countries: Vec<(String, Vec<String>)>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            billable_min_seconds: 30,
+            short_call_seconds: 6,
+            long_call_seconds: 14400,
+            http_listener: true,
+            listen_addresses: Vec::new(),
+            listen_port_range: Vec::new(),
+            metric_timestamps: false,
+            created_metrics: false,
+            exemplars: false,
+            metric_prefix: None,
+            journal_size: 0,
+            proxy_protocol: false,
+            trusted_proxies: Vec::new(),
+            http_acl: None,
+            http_workers: 2,
+            http_max_connections: 64,
+            http_read_timeout: Duration::from_secs(5),
+            http_write_timeout: Duration::from_secs(5),
+            http_access_log: false,
+            max_user_metrics: 0,
+            evict_user_metrics: true,
+            user_metrics_ttl: Duration::from_secs(0),
+            user_metrics_file: None,
+            counters_file: None,
+            counters_checkpoint_interval: Duration::from_secs(60),
+            peak_reset_interval: Duration::from_secs(86400),
+            snapshot_interval: Duration::from_secs(0),
+            snapshot_metrics: ["freeswitch_sessions_active_*", "freeswitch_sessions_per_second_*",
+                               "freeswitch_calls_active", "freeswitch_registrations_active"]
+                              .iter().map(|p| p.to_string()).collect(),
+            config_url: None,
+            tenant_headers: Vec::new(),
+            max_tenants: 100,
+            billsec_histograms: false,
+            max_accountcodes: 100,
+            remote_network_histograms: false,
+            remote_network_mask: 24,
+            remote_network_mask_v6: 48,
+            max_remote_networks: 100,
+            max_queues: 100,
+            queue_variable: None,
+            queue_start_variable: None,
+            max_campaigns: 100,
+            amd_variables: vec!["amd_result".to_string(), "avmd_detect".to_string()],
+            campaign_variable: None,
+            limit_hangup_causes: vec!["SWITCH_CONGESTION".to_string()],
+            internal_channels: Vec::new(),
+            internal_channels_as_other: false,
+            max_limit_resources: 100,
+            max_applications: 100,
+            max_api_commands: 100,
+            max_sound_languages: 20,
+            max_valet_lots: 100,
+            route_attempts_variable: None,
+            fraud_prefixes: Vec::new(),
+            fraud_max_user_calls: 0,
+            international_prefixes: vec!["00".to_string(), "+".to_string()],
+            business_hours: None,
+            summary_quantiles: vec![0.5, 0.9, 0.99],
+            summary_window: Duration::from_secs(600),
+            duration_summaries: false,
+            sd_file: None,
+            consul_url: None,
+            sd_address: None,
+            sd_service: "freeswitch".to_string(),
+            call_log_level: DEBUG,
+            disabled_bindings: Vec::new(),
+            deny_metrics: Vec::new(),
+            derived_metrics: Vec::new(),
+            alerts: Vec::new(),
+            routes: Vec::new(),
+            countries: Vec::new(),
+        }
+    }
+}
+
+// Built-in metrics by group, so whole groups can be disabled at once
+fn metric_group_patterns(group: &str) -> Option<&'static [&'static str]> {
+    match group {
+        "sessions" => Some(&["freeswitch_sessions_*", "freeswitch_inbound_*", "freeswitch_outbound_*",
+                              "freeswitch_calls_*", "freeswitch_channels_*", "freeswitch_bridges_*"]),
+        "registrations" => Some(&["freeswitch_registration*", "freeswitch_gateway_registered",
+                                  "freeswitch_gateway_registration_retries_total"]),
+        "gateways" => Some(&["freeswitch_gateway_*"]),
+        "playbacks" => Some(&["freeswitch_playbacks_*"]),
+        "recordings" => Some(&["freeswitch_recordings_*"]),
+        "transfers" => Some(&["freeswitch_transfers_*"]),
+        "valet" => Some(&["freeswitch_valet_*"]),
+        "queues" => Some(&["freeswitch_queue_*"]),
+        "campaigns" => Some(&["freeswitch_campaign_*"]),
+        "bgapi" => Some(&["freeswitch_bgapi_*"]),
+        "api" => Some(&["freeswitch_api_*"]),
+        "scheduler" => Some(&["freeswitch_scheduler_*"]),
+        "esl" => Some(&["freeswitch_esl_*"]),
+        "tenants" => Some(&["freeswitch_tenant_*", "freeswitch_accountcode_*"]),
+        "modules" => Some(&["freeswitch_modules_*", "freeswitch_reloadxml_total"]),
+        "transcoding" => Some(&["freeswitch_transcoding_*"]),
+        "media" => Some(&["freeswitch_sofia_media_timeouts_total", "freeswitch_sofia_rtp_*", "freeswitch_media_*",
+                          "freeswitch_rtcp_*", "freeswitch_remote_network_*"]),
+        "limits" => Some(&["freeswitch_limit_*"]),
+        "applications" => Some(&["freeswitch_applications_*"]),
+        "core" => Some(&["freeswitch_core_*"]),
+        "fraud" => Some(&["freeswitch_fraud_*"]),
+        "routes" => Some(&["freeswitch_route_*"]),
+        "countries" => Some(&["freeswitch_country_*"]),
+        "module" => Some(&["freeswitch_event_lag_*", "freeswitch_prometheus_*"]),
+        _ => None,
+    }
+}
+
+// A boolean the way the core switch_true() reads it: yes, on, true, t, enabled, active,
+// allow in any case, or a number with a non-zero integer part
+pub fn switch_true(value: &str) -> bool {
+    let value = value.trim();
+    ["yes", "on", "true", "t", "enabled", "active", "allow"].iter().any(|t| value.eq_ignore_ascii_case(t)) ||
+        value.parse::<f64>().is_ok_and(|n| n.trunc() != 0.0)
+}
+
+// Levels the per-call log lines can be emitted at
+pub fn parse_log_level(name: &str) -> Option<fsr::log_level::Type> {
+    match name.trim() {
+        "debug" => Some(DEBUG),
+        "info" => Some(INFO),
+        "notice" => Some(NOTICE),
+        _ => None,
+    }
+}
+
+// <host>, <host>:<port>, an IPv6 literal or [<IPv6 literal>]:<port>. IPv6 hosts are
+// returned without the brackets
+fn parse_listen_address(value: &str) -> Option<(String, Option<u16>)> {
+    if value.starts_with('[') {
+        let end = value.find(']')?;
+        let host = &value[1..end];
+        if host.parse::<Ipv6Addr>().is_err() {
+            return None;
+        }
+        return match &value[end + 1..] {
+            "" => Some((host.to_string(), None)),
+            port if port.starts_with(':') => port[1..].parse::<u16>().ok().map(|p| (host.to_string(), Some(p))),
+            _ => None,
+        };
+    }
+    if value.parse::<IpAddr>().is_ok() {
+        return Some((value.to_string(), None));
+    }
+    let mut parts = value.rsplitn(2, ':');
+    let last = parts.next().unwrap_or("");
+    match parts.next() {
+        None if !last.is_empty() => Some((last.to_string(), None)),
+        Some(host) if !host.is_empty() => last.parse::<u16>().ok().map(|p| (host.to_string(), Some(p))),
+        _ => None,
+    }
+}
+
+impl Config {
+    fn set(&mut self, name: &str, value: &str) {
+        match name {
+            "billable-min-seconds" => {
+                if let Ok(v) = value.parse::<u64>() {
+                    self.billable_min_seconds = v;
+                } else {
+                    fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value);
+                }
+            }
+            "short-call-seconds" => {
+                if let Ok(v) = value.parse::<u64>() {
+                    self.short_call_seconds = v;
+                } else {
+                    fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value);
+                }
+            }
+            "long-call-seconds" => {
+                if let Ok(v) = value.parse::<u64>() {
+                    self.long_call_seconds = v;
+                } else {
+                    fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value);
+                }
+            }
+            "enable-http-listener" => {
+                self.http_listener = switch_true(value);
+            }
+            "listen-address" => {
+                match parse_listen_address(value.trim()) {
+                    Some(addr) => self.listen_addresses.push(addr),
+                    None => fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value),
+                }
+            }
+            "listen-port-range" => {
+                let bounds: Vec<Option<u16>> = value.splitn(2, '-').map(|p| p.trim().parse::<u16>().ok()).collect();
+                match (bounds.first(), bounds.get(1)) {
+                    (Some(&Some(first)), Some(&Some(last))) if first <= last => {
+                        self.listen_port_range = (first..last + 1).collect();
+                    }
+                    _ => fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value),
+                }
+            }
+            "metric-timestamps" => {
+                self.metric_timestamps = switch_true(value);
+            }
+            "created-metrics" => {
+                self.created_metrics = switch_true(value);
+            }
+            "exemplars" => {
+                self.exemplars = switch_true(value);
+            }
+            "journal-size" => {
+                if let Ok(v) = value.parse::<usize>() {
+                    self.journal_size = v;
+                } else {
+                    fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value);
+                }
+            }
+            "proxy-protocol" => {
+                self.proxy_protocol = switch_true(value);
+            }
+            "trusted-proxies" => {
+                for addr in value.split(',') {
+                    match addr.trim().parse::<IpAddr>() {
+                        Ok(a) => self.trusted_proxies.push(a),
+                        Err(_) => fslog!(WARNING, "Invalid address in {}: {:?}\n", name, addr),
+                    }
+                }
+            }
+            "http-acl" => {
+                if !value.is_empty() {
+                    self.http_acl = Some(value.to_string());
+                }
+            }
+            "http-workers" => {
+                match value.parse::<usize>() {
+                    Ok(v) if v > 0 => self.http_workers = v,
+                    _ => fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value),
+                }
+            }
+            "http-max-connections" => {
+                match value.parse::<usize>() {
+                    Ok(v) if v > 0 => self.http_max_connections = v,
+                    _ => fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value),
+                }
+            }
+            "http-read-timeout" | "http-write-timeout" => {
+                let timeout = match value.parse::<u64>() {
+                    Ok(v) if v > 0 => Duration::from_secs(v),
+                    _ => {
+                        fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value);
+                        return;
+                    }
+                };
+                if name == "http-read-timeout" {
+                    self.http_read_timeout = timeout;
+                } else {
+                    self.http_write_timeout = timeout;
+                }
+            }
+            "http-access-log" => {
+                self.http_access_log = switch_true(value);
+            }
+            "max-user-metrics" => {
+                if let Ok(v) = value.parse::<usize>() {
+                    self.max_user_metrics = v;
+                } else {
+                    fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value);
+                }
+            }
+            "user-metrics-overflow" => {
+                match value {
+                    "evict" => self.evict_user_metrics = true,
+                    "reject" => self.evict_user_metrics = false,
+                    _ => fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value),
+                }
+            }
+            "user-metrics-ttl" => {
+                if let Ok(v) = value.parse::<u64>() {
+                    self.user_metrics_ttl = Duration::from_secs(v * 60);
+                } else {
+                    fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value);
+                }
+            }
+            "user-metrics-file" => {
+                if !value.is_empty() {
+                    self.user_metrics_file = Some(value.to_string());
+                }
+            }
+            "counters-file" => {
+                if !value.is_empty() {
+                    self.counters_file = Some(value.to_string());
+                }
+            }
+            "counters-checkpoint-interval" => {
+                match value.parse::<u64>() {
+                    Ok(v) if v > 0 => self.counters_checkpoint_interval = Duration::from_secs(v),
+                    _ => fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value),
+                }
+            }
+            "peak-reset-interval" => {
+                if let Ok(v) = value.parse::<u64>() {
+                    self.peak_reset_interval = Duration::from_secs(v);
+                } else {
+                    fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value);
+                }
+            }
+            "snapshot-interval" => {
+                if let Ok(v) = value.parse::<u64>() {
+                    self.snapshot_interval = Duration::from_secs(v);
+                } else {
+                    fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value);
+                }
+            }
+            "config-url" => {
+                if value.starts_with("http://") {
+                    self.config_url = Some(value.to_string());
+                } else {
+                    fslog!(WARNING, "Invalid value for {}: {:?}, only http:// URLs are supported\n", name, value);
+                }
+            }
+            "snapshot-metrics" => {
+                self.snapshot_metrics = value.split(',').map(|p| p.trim().to_string()).filter(|p| !p.is_empty()).collect();
+            }
+            "tenant-header" => {
+                if !value.is_empty() {
+                    self.tenant_headers.push(value.to_string());
+                }
+            }
+            "max-tenants" => {
+                if let Ok(v) = value.parse::<usize>() {
+                    self.max_tenants = v;
+                } else {
+                    fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value);
+                }
+            }
+            "billsec-histograms" => {
+                self.billsec_histograms = switch_true(value);
+            }
+            "max-accountcodes" => {
+                if let Ok(v) = value.parse::<usize>() {
+                    self.max_accountcodes = v;
+                } else {
+                    fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value);
+                }
+            }
+            "remote-network-histograms" => {
+                self.remote_network_histograms = switch_true(value);
+            }
+            "remote-network-mask" => {
+                match value.parse::<u8>() {
+                    Ok(v) if v <= 32 => self.remote_network_mask = v,
+                    _ => fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value),
+                }
+            }
+            "remote-network-mask-v6" => {
+                match value.parse::<u8>() {
+                    Ok(v) if v <= 128 => self.remote_network_mask_v6 = v,
+                    _ => fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value),
+                }
+            }
+            "max-remote-networks" => {
+                if let Ok(v) = value.parse::<usize>() {
+                    self.max_remote_networks = v;
+                } else {
+                    fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value);
+                }
+            }
+            "max-queues" => {
+                if let Ok(v) = value.parse::<usize>() {
+                    self.max_queues = v;
+                } else {
+                    fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value);
+                }
+            }
+            "queue-variable" => {
+                if !value.is_empty() {
+                    self.queue_variable = Some(value.to_string());
+                }
+            }
+            "queue-start-variable" => {
+                if !value.is_empty() {
+                    self.queue_start_variable = Some(value.to_string());
+                }
+            }
+            "max-campaigns" => {
+                if let Ok(v) = value.parse::<usize>() {
+                    self.max_campaigns = v;
+                } else {
+                    fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value);
+                }
+            }
+            "amd-variables" => {
+                self.amd_variables = value.split(',').map(|v| v.trim().to_string()).filter(|v| !v.is_empty()).collect();
+            }
+            "campaign-variable" => {
+                if !value.is_empty() {
+                    self.campaign_variable = Some(value.to_string());
+                }
+            }
+            "limit-hangup-causes" => {
+                self.limit_hangup_causes = value.split(',').map(|c| c.trim().to_string()).filter(|c| !c.is_empty()).collect();
+            }
+            "internal-channel" => {
+                if !value.is_empty() {
+                    self.internal_channels.push(value.to_string());
+                }
+            }
+            "internal-channels-as-other" => {
+                self.internal_channels_as_other = switch_true(value);
+            }
+            "max-limit-resources" => {
+                if let Ok(v) = value.parse::<usize>() {
+                    self.max_limit_resources = v;
+                } else {
+                    fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value);
+                }
+            }
+            "max-applications" => {
+                if let Ok(v) = value.parse::<usize>() {
+                    self.max_applications = v;
+                } else {
+                    fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value);
+                }
+            }
+            "max-api-commands" => {
+                if let Ok(v) = value.parse::<usize>() {
+                    self.max_api_commands = v;
+                } else {
+                    fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value);
+                }
+            }
+            "max-sound-languages" => {
+                if let Ok(v) = value.parse::<usize>() {
+                    self.max_sound_languages = v;
+                } else {
+                    fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value);
+                }
+            }
+            "max-valet-lots" => {
+                if let Ok(v) = value.parse::<usize>() {
+                    self.max_valet_lots = v;
+                } else {
+                    fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value);
+                }
+            }
+            "route-attempts-variable" => {
+                if !value.is_empty() {
+                    self.route_attempts_variable = Some(value.to_string());
+                }
+            }
+            "fraud-prefixes" => {
+                self.fraud_prefixes = value.split(',').map(|p| p.trim().to_string()).filter(|p| !p.is_empty()).collect();
+            }
+            "fraud-max-user-calls" => {
+                if let Ok(v) = value.parse::<usize>() {
+                    self.fraud_max_user_calls = v;
+                } else {
+                    fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value);
+                }
+            }
+            "international-prefixes" => {
+                self.international_prefixes = value.split(',').map(|p| p.trim().to_string()).filter(|p| !p.is_empty()).collect();
+            }
+            "business-hours" => {
+                let hours: Vec<Option<u32>> = value.splitn(2, '-').map(|h| h.trim().parse::<u32>().ok()).collect();
+                match (hours.first().cloned().unwrap_or(None), hours.get(1).cloned().unwrap_or(None)) {
+                    (Some(start), Some(end)) if start < end && end <= 24 => self.business_hours = Some((start, end)),
+                    _ => fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value),
+                }
+            }
+            "summary-quantiles" => {
+                let quantiles: Vec<Option<f64>> = value.split(',').map(|q| q.trim().parse::<f64>().ok()).collect();
+                if quantiles.iter().all(|q| q.is_some_and(|q| (0.0..=1.0).contains(&q))) {
+                    self.summary_quantiles = quantiles.into_iter().map(|q| q.unwrap()).collect();
+                } else {
+                    fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value);
+                }
+            }
+            "summary-window" => {
+                match value.parse::<u64>() {
+                    Ok(v) if v > 0 => self.summary_window = Duration::from_secs(v),
+                    _ => fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value),
+                }
+            }
+            "duration-summaries" => {
+                self.duration_summaries = switch_true(value);
+            }
+            "sd-file" => {
+                if !value.is_empty() {
+                    self.sd_file = Some(value.to_string());
+                }
+            }
+            "consul-url" => {
+                if !value.is_empty() {
+                    self.consul_url = Some(value.to_string());
+                }
+            }
+            "sd-address" => {
+                if !value.is_empty() {
+                    self.sd_address = Some(value.to_string());
+                }
+            }
+            "sd-service" => {
+                if !value.is_empty() {
+                    self.sd_service = value.to_string();
+                }
+            }
+            "disabled-metric-groups" => {
+                for group in value.split(',') {
+                    match metric_group_patterns(group.trim()) {
+                        Some(patterns) => self.deny_metrics.extend(patterns.iter().map(|p| p.to_string())),
+                        None => fslog!(WARNING, "Unknown metric group in {}: {:?}\n", name, group),
+                    }
+                }
+            }
+            "log-verbosity" => {
+                match parse_log_level(value) {
+                    Some(level) => self.call_log_level = level,
+                    None => fslog!(WARNING, "Invalid value for {}: {:?}\n", name, value),
+                }
+            }
+            "disabled-bindings" => {
+                self.disabled_bindings.extend(value.split(',').map(|b| b.trim().to_string()).filter(|b| !b.is_empty()));
+            }
+            "deny-metric" => {
+                self.deny_metrics.push(value.to_string());
+            }
+            "metric-prefix" => {
+                if !value.is_empty() {
+                    self.metric_prefix = Some(value.to_string());
+                }
+            }
+            _ => fslog!(WARNING, "Ignoring unknown parameter {}\n", name),
+        }
+    }
+
+    // Every <settings> param can be set as MOD_PROMETHEUS_<NAME>, the param name upper-cased
+    // with dashes as underscores (e.g. MOD_PROMETHEUS_LISTEN_ADDRESS), overriding the XML.
+    // Params given several times in the XML take a comma separated list, which replaces the
+    // XML values except for deny-metric patterns that add up. MOD_PROMETHEUS_BINDADDR is
+    // an alias of listen-address
+    pub fn set_from_env<I: Iterator<Item = (String, String)>>(&mut self, vars: I) {
+        for (var, value) in vars {
+            if !var.starts_with(ENV_PREFIX) || var == LISTENING_ENV_PORT {
+                continue;
+            }
+            let name = match &var[ENV_PREFIX.len()..] {
+                "BINDADDR" => "listen-address".to_string(),
+                n => n.to_lowercase().replace('_', "-"),
+            };
+            let values: Vec<&str> = match &name[..] {
+                "listen-address" => {
+                    self.listen_addresses.clear();
+                    value.split(',').collect()
+                }
+                "tenant-header" => {
+                    self.tenant_headers.clear();
+                    value.split(',').collect()
+                }
+                "internal-channel" => {
+                    self.internal_channels.clear();
+                    value.split(',').collect()
+                }
+                "disabled-bindings" => {
+                    self.disabled_bindings.clear();
+                    vec![&value[..]]
+                }
+                "trusted-proxies" => {
+                    self.trusted_proxies.clear();
+                    vec![&value[..]]
+                }
+                "deny-metric" => value.split(',').collect(),
+                _ => vec![&value[..]],
+            };
+            for v in values.iter().map(|v| v.trim()).filter(|v| !v.is_empty()) {
+                self.set(&name, v);
+            }
+        }
+    }
+}
+
+// Parse prometheus.conf.xml, a missing file just means defaults, then apply the
+// MOD_PROMETHEUS_* environment variables on top
+pub fn load_config() -> Config {
+    let mut config = Config::default();
+    read_config_file(&mut config);
+    let url = env::var(format!("{}CONFIG_URL", ENV_PREFIX)).ok().or(config.config_url.clone());
+    if let Some(url) = url {
+        read_remote_config(&mut config, &url);
+    }
+    config.set_from_env(env::vars());
+    config
+}
+
+fn read_config_file(config: &mut Config) {
+    unsafe {
+        let mut cfg: fsr::xml_t = std::ptr::null_mut();
+        let xml = fsr::xml_open_cfg(fsr::str_to_ptr(CONFIG_FILE), &mut cfg, std::ptr::null_mut());
+        if xml.is_null() {
+            fslog!(NOTICE, "Could not open {}, using default settings\n", CONFIG_FILE);
+            return;
+        }
+        read_config(config, cfg);
+        fsr::xml_free(xml);
+    }
+}
+
+// Central configuration for a fleet of switches: the same <configuration> as the local
+// file, bare or in a mod_xml_curl style <document>. Its params override the local ones,
+// its sections replace the local ones. The local configuration is kept when it can't be fetched
+fn read_remote_config(config: &mut Config, url: &str) {
+    let body = match http_get(url) {
+        Ok(b) => b,
+        Err(e) => {
+            fslog!(ERROR, "Failed to fetch the configuration from {}: {}, using the local one\n", url, e);
+            return;
+        }
+    };
+    // Can't be handed to the XML parser as a C string, and converting it would panic
+    if body.contains('\0') {
+        fslog!(ERROR, "Invalid configuration fetched from {} (NUL byte), using the local one\n", url);
+        return;
+    }
+    unsafe {
+        let xml = fsr::xml_parse_str_dynamic(fsr::str_to_ptr(&body) as *mut _, fsr::bool_t::TRUE);
+        if xml.is_null() {
+            fslog!(ERROR, "Invalid configuration fetched from {}, using the local one\n", url);
+            return;
+        }
+        let mut cfg = xml;
+        if fsr::ptr_to_str((*xml).name).is_some_and(|n| n == "document") {
+            let section = fsr::xml_find_child(xml, fsr::str_to_ptr("section"), fsr::str_to_ptr("name"),
+                                              fsr::str_to_ptr("configuration"));
+            cfg = if section.is_null() {
+                section
+            } else {
+                fsr::xml_find_child(section, fsr::str_to_ptr("configuration"), fsr::str_to_ptr("name"),
+                                    fsr::str_to_ptr(CONFIG_FILE))
+            };
+        }
+        if cfg.is_null() {
+            fslog!(ERROR, "No {} configuration fetched from {}, using the local one\n", CONFIG_FILE, url);
+        } else {
+            read_config(config, cfg);
+            fslog!(NOTICE, "Applied the configuration fetched from {}\n", url);
+        }
+        fsr::xml_free(xml);
+    }
+}
+
+// Params, derived metrics, alerts and prefix tables of a <configuration>, a section
+// present replaces what was read before
+unsafe fn read_config(config: &mut Config, cfg: fsr::xml_t) {
+    let settings = fsr::xml_child(cfg, fsr::str_to_ptr("settings"));
+    if !settings.is_null() {
+        let mut param = fsr::xml_child(settings, fsr::str_to_ptr("param"));
+        while !param.is_null() {
+            let name = fsr::ptr_to_str(fsr::xml_attr_soft(param, fsr::str_to_ptr("name")));
+            let value = fsr::ptr_to_str(fsr::xml_attr_soft(param, fsr::str_to_ptr("value")));
+            if let (Some(name), Some(value)) = (name, value) {
+                config.set(&name, &value);
+            }
+            param = (*param).next;
+        }
+    }
+    let derived = fsr::xml_child(cfg, fsr::str_to_ptr("derived-metrics"));
+    if !derived.is_null() {
+        config.derived_metrics.clear();
+        let mut metric = fsr::xml_child(derived, fsr::str_to_ptr("metric"));
+        while !metric.is_null() {
+            let name = fsr::ptr_to_str(fsr::xml_attr_soft(metric, fsr::str_to_ptr("name")));
+            let help = fsr::ptr_to_str(fsr::xml_attr_soft(metric, fsr::str_to_ptr("help")));
+            let expr = fsr::ptr_to_str(fsr::xml_attr_soft(metric, fsr::str_to_ptr("expression")));
+            if let (Some(name), Some(expr)) = (name, expr) {
+                let help = help.map(|h| h.to_string()).unwrap_or(name.to_string());
+                config.derived_metrics.push((name.to_string(), help, expr.to_string()));
+            }
+            metric = (*metric).next;
+        }
+    }
+    let alerts = fsr::xml_child(cfg, fsr::str_to_ptr("alerts"));
+    if !alerts.is_null() {
+        config.alerts.clear();
+        let mut alert = fsr::xml_child(alerts, fsr::str_to_ptr("alert"));
+        while !alert.is_null() {
+            let name = fsr::ptr_to_str(fsr::xml_attr_soft(alert, fsr::str_to_ptr("name")));
+            let expr = fsr::ptr_to_str(fsr::xml_attr_soft(alert, fsr::str_to_ptr("expression")));
+            let hold = fsr::ptr_to_str(fsr::xml_attr_soft(alert, fsr::str_to_ptr("for")));
+            if let (Some(name), Some(expr)) = (name, expr) {
+                match hold.as_ref().map_or(Ok(0), |h| if h.is_empty() { Ok(0) } else { h.parse::<u64>() }) {
+                    Ok(secs) => config.alerts.push((name.to_string(), expr.to_string(), Duration::from_secs(secs))),
+                    Err(_) => fslog!(WARNING, "Invalid for of alert {}: {:?}\n", name, hold),
+                }
+            }
+            alert = (*alert).next;
+        }
+    }
+    if !fsr::xml_child(cfg, fsr::str_to_ptr("routes")).is_null() {
+        config.routes = read_prefix_table(cfg, "routes", "route");
+    }
+    if !fsr::xml_child(cfg, fsr::str_to_ptr("countries")).is_null() {
+        config.countries = read_prefix_table(cfg, "countries", "country");
+    }
+}
+
+// <section><entry name="..." prefixes="a,b,c"/></section> as names and their prefixes
+unsafe fn read_prefix_table(cfg: fsr::xml_t, section: &str, entry: &str) -> Vec<(String, Vec<String>)> {
+    let mut table = Vec::new();
+    let section = fsr::xml_child(cfg, fsr::str_to_ptr(section));
+    if section.is_null() {
+        return table;
+    }
+    let mut node = fsr::xml_child(section, fsr::str_to_ptr(entry));
+    while !node.is_null() {
+        let name = fsr::ptr_to_str(fsr::xml_attr_soft(node, fsr::str_to_ptr("name")));
+        let prefixes = fsr::ptr_to_str(fsr::xml_attr_soft(node, fsr::str_to_ptr("prefixes")));
+        if let (Some(name), Some(prefixes)) = (name, prefixes) {
+            let prefixes: Vec<String> = prefixes.split(',').map(|p| p.trim().to_string()).filter(|p| !p.is_empty()).collect();
+            if name.is_empty() || prefixes.is_empty() {
+                fslog!(WARNING, "Ignoring {} {:?} without a name or prefixes\n", entry, name);
+            } else {
+                table.push((name.to_string(), prefixes));
+            }
+        }
+        node = (*node).next;
+    }
+    table
+}
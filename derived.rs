@@ -0,0 +1,117 @@
+// Derived metrics: gauges computed at scrape time out of the values of other
+// metrics, configured as simple expressions such as
+//   freeswitch_sessions_answered_total / freeswitch_sessions_created_total
+//   rate(freeswitch_sessions_created_total, 300)
+use std::collections::{HashMap, VecDeque};
+use std::f64;
+use std::time::{Duration, Instant};
+
+enum Operand {
+    Metric(String),
+    Number(f64),
+}
+
+enum Expression {
+    Binary(Operand, char, Operand),
+    // Per second increase of the metric over the window, out of the samples taken at every scrape
+    Rate(String, Duration, VecDeque<(Instant, f64)>),
+}
+
+pub struct Derived {
+    pub name: String,
+    pub help: String,
+    expr: Expression,
+}
+
+impl Operand {
+    fn parse(token: &str) -> Operand {
+        match token.parse::<f64>() {
+            Ok(n) => Operand::Number(n),
+            Err(_) => Operand::Metric(token.to_string()),
+        }
+    }
+
+    fn value(&self, values: &HashMap<String, f64>) -> f64 {
+        match *self {
+            Operand::Number(n) => n,
+            Operand::Metric(ref m) => values.get(m).cloned().unwrap_or(f64::NAN),
+        }
+    }
+}
+
+impl Derived {
+    pub fn parse(name: &str, help: &str, expression: &str) -> Result<Derived, String> {
+        let expression = expression.trim();
+        let expr = if expression.starts_with("rate(") && expression.ends_with(')') {
+            let args: Vec<&str> = expression[5..expression.len() - 1].split(',').map(|a| a.trim()).collect();
+            if args.len() != 2 {
+                return Err(format!("rate() expects a metric and a window in seconds: {}", expression));
+            }
+            let window = args[1].parse::<u64>().map_err(|_| format!("Invalid rate window: {}", args[1]))?;
+            Expression::Rate(args[0].to_string(), Duration::from_secs(window), VecDeque::new())
+        } else {
+            let tokens: Vec<&str> = expression.split_whitespace().collect();
+            if tokens.len() != 3 || tokens[1].len() != 1 || !"+-*/".contains(tokens[1]) {
+                return Err(format!("Expected <a> <+|-|*|/> <b>: {}", expression));
+            }
+            Expression::Binary(Operand::parse(tokens[0]), tokens[1].chars().next().unwrap(), Operand::parse(tokens[2]))
+        };
+        Ok(Derived { name: name.to_string(), help: help.to_string(), expr })
+    }
+
+    // Metrics the expression is computed from
+    pub fn metrics(&self) -> Vec<&str> {
+        match self.expr {
+            Expression::Binary(ref a, _, ref b) => {
+                [a, b].iter().filter_map(|o| match **o {
+                    Operand::Metric(ref m) => Some(&m[..]),
+                    Operand::Number(_) => None,
+                }).collect()
+            }
+            Expression::Rate(ref metric, _, _) => vec![&metric[..]],
+        }
+    }
+
+    // Value at scrape time, a rate takes a sample of its metric
+    pub fn evaluate(&mut self, values: &HashMap<String, f64>) -> f64 {
+        if let Expression::Rate(ref metric, window, ref mut samples) = self.expr {
+            if let Some(value) = values.get(metric) {
+                let now = Instant::now();
+                samples.push_back((now, *value));
+                while samples.len() > 2 && now.duration_since(samples[1].0) >= window {
+                    samples.pop_front();
+                }
+            }
+        }
+        self.value(values)
+    }
+
+    // NaN when the value can't be computed (unknown metric, division by zero, not enough samples).
+    // Takes no sample, reading a rate doesn't change the next scrape
+    pub fn value(&self, values: &HashMap<String, f64>) -> f64 {
+        match self.expr {
+            Expression::Binary(ref a, op, ref b) => {
+                let (a, b) = (a.value(values), b.value(values));
+                match op {
+                    '+' => a + b,
+                    '-' => a - b,
+                    '*' => a * b,
+                    _ => if b == 0 as f64 { f64::NAN } else { a / b },
+                }
+            }
+            Expression::Rate(ref metric, _, ref samples) => {
+                let value = match values.get(metric) {
+                    Some(v) => *v,
+                    None => return f64::NAN,
+                };
+                let (then, old) = match samples.front() {
+                    Some(&s) => s,
+                    None => return f64::NAN,
+                };
+                let elapsed = then.elapsed();
+                let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+                if secs == 0 as f64 { f64::NAN } else { (value - old) / secs }
+            }
+        }
+    }
+}
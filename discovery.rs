@@ -0,0 +1,201 @@
+// Service discovery: announce the metrics endpoint on load and withdraw it on unload,
+// either as a Prometheus file_sd JSON file or as a Consul agent service, so dynamically
+// provisioned media servers get scraped without editing the Prometheus configuration
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use freeswitchrs::raw as fsr;
+use freeswitchrs::raw::log_level::{NOTICE, ERROR};
+
+use crate::config::Config;
+use crate::lock;
+
+// Time an HTTP exchange may take in total, from connecting to reading the last byte
+static HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Largest response accepted, a remote configuration or a Consul answer is a few KB
+static MAX_RESPONSE_SIZE: usize = 1024 * 1024;
+
+// What was announced, withdrawn on unload even if the configuration changed meanwhile
+struct Registration {
+    file: Option<String>,
+    // Consul agent URL and service ID
+    consul: Option<(String, String)>,
+}
+
+lazy_static! {
+    static ref REGISTRATION: Mutex<Option<Registration>> = {
+        Mutex::new(None)
+    };
+}
+
+// Announce host:port, the configured sd-address or the FreeSWITCH hostname and the port
+// listened on
+pub fn register(config: &Config, listening: Option<SocketAddr>) {
+    if config.sd_file.is_none() && config.consul_url.is_none() {
+        return;
+    }
+    let target = match (config.sd_address.clone(), listening) {
+        (Some(a), _) => a,
+        (None, Some(addr)) => format!("{}:{}", hostname(), addr.port()),
+        (None, None) => {
+            fslog!(ERROR, "Not registering for service discovery, set sd-address when the HTTP listener is disabled\n");
+            return;
+        }
+    };
+    let mut registration = Registration { file: None, consul: None };
+    if let Some(ref path) = config.sd_file {
+        let json = format!("[{{\"targets\": [\"{}\"], \"labels\": {{\"service\": \"{}\"}}}}]\n",
+                           json_escape(&target), json_escape(&config.sd_service));
+        match write_file(path, &json) {
+            Ok(_) => {
+                fslog!(NOTICE, "Wrote {} to {}\n", target, path);
+                registration.file = Some(path.clone());
+            }
+            Err(e) => fslog!(ERROR, "Failed to write {}: {}\n", path, e),
+        }
+    }
+    if let Some(ref url) = config.consul_url {
+        let id = format!("{}-{}", config.sd_service, target.replace(':', "-"));
+        let (host, port) = match target.rfind(':') {
+            Some(p) => (&target[..p], target[p + 1..].parse::<u16>().unwrap_or(0)),
+            None => (&target[..], 0),
+        };
+        let body = format!("{{\"ID\": \"{}\", \"Name\": \"{}\", \"Address\": \"{}\", \"Port\": {}, \"Tags\": [\"prometheus\"], \
+                            \"Check\": {{\"HTTP\": \"http://{}/metrics\", \"Interval\": \"30s\"}}}}",
+                           json_escape(&id), json_escape(&config.sd_service), json_escape(host), port, json_escape(&target));
+        match consul_put(url, "/v1/agent/service/register", &body) {
+            Ok(_) => {
+                fslog!(NOTICE, "Registered {} in Consul as {}\n", target, id);
+                registration.consul = Some((url.clone(), id));
+            }
+            Err(e) => fslog!(ERROR, "Failed to register {} in Consul at {}: {}\n", target, url, e),
+        }
+    }
+    *lock(&REGISTRATION) = Some(registration);
+}
+
+pub fn deregister() {
+    let registration = match lock(&REGISTRATION).take() {
+        Some(r) => r,
+        None => return,
+    };
+    if let Some(path) = registration.file {
+        if let Err(e) = fs::remove_file(&path) {
+            fslog!(ERROR, "Failed to remove {}: {}\n", path, e);
+        }
+    }
+    if let Some((url, id)) = registration.consul {
+        if let Err(e) = consul_put(&url, &format!("/v1/agent/service/deregister/{}", id), "") {
+            fslog!(ERROR, "Failed to deregister {} from Consul at {}: {}\n", id, url, e);
+        }
+    }
+}
+
+fn hostname() -> String {
+    unsafe { fsr::ptr_to_str(fsr::core_get_variable(fsr::str_to_ptr("hostname"))) }
+        .map(|h| h.to_string())
+        .unwrap_or_else(|| "localhost".to_string())
+}
+
+// Written aside and renamed so Prometheus never reads a partial file
+fn write_file(path: &str, contents: &str) -> ::std::io::Result<()> {
+    let tmp = format!("{}.tmp", path);
+    File::create(&tmp).and_then(|mut f| f.write_all(contents.as_bytes()))
+                      .and_then(|_| fs::rename(&tmp, path))
+}
+
+fn json_escape(value: &str) -> String {
+    let mut out = String::new();
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// PUT to the Consul agent HTTP API, only plain http://host:port URLs are supported
+fn consul_put(url: &str, path: &str, body: &str) -> Result<(), String> {
+    let authority = match url.trim_end_matches('/').split_once("http://").map(|(_, a)| a) {
+        Some(a) if !a.contains('/') => a,
+        _ => return Err(format!("expected http://<host>:<port>, got {:?}", url)),
+    };
+    http_request("PUT", authority, path, Some(("application/json", body))).map(|_| ())
+}
+
+// GET of a plain http://host[:port]/path URL, the response body on a 200
+pub fn http_get(url: &str) -> Result<String, String> {
+    let (authority, path) = match url.split_once("http://").map(|(_, rest)| rest) {
+        Some(rest) => match rest.find('/') {
+            Some(p) => (&rest[..p], &rest[p..]),
+            None => (rest, "/"),
+        },
+        None => return Err(format!("expected http://<host>[:<port>]/<path>, got {:?}", url)),
+    };
+    http_request("GET", authority, path, None)
+}
+
+// HTTP/1.0 so the response is never chunked. Bounded in time and size, a stuck or
+// misbehaving server can't hold the caller
+fn http_request(method: &str, authority: &str, path: &str, body: Option<(&str, &str)>) -> Result<String, String> {
+    let deadline = Instant::now() + HTTP_TIMEOUT;
+    let address = if authority.contains(':') { authority.to_string() } else { format!("{}:80", authority) };
+    let mut error = format!("no address for {}", address);
+    let mut stream = None;
+    for addr in address.to_socket_addrs().map_err(|e| e.to_string())? {
+        match TcpStream::connect_timeout(&addr, remaining(deadline)?) {
+            Ok(s) => {
+                stream = Some(s);
+                break;
+            }
+            Err(e) => error = e.to_string(),
+        }
+    }
+    let mut stream = stream.ok_or(error)?;
+    let mut request = format!("{} {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n", method, path, authority);
+    if let Some((content_type, body)) = body {
+        request.push_str(&format!("Content-Type: {}\r\nContent-Length: {}\r\n\r\n{}", content_type, body.len(), body));
+    } else {
+        request.push_str("\r\n");
+    }
+    stream.set_write_timeout(Some(remaining(deadline)?)).map_err(|e| e.to_string())?;
+    stream.write_all(request.as_bytes()).map_err(|e| e.to_string())?;
+    let mut response = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        stream.set_read_timeout(Some(remaining(deadline)?)).map_err(|e| e.to_string())?;
+        match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => response.extend_from_slice(&buf[..n]),
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.to_string()),
+        }
+        if response.len() > MAX_RESPONSE_SIZE {
+            return Err(format!("response larger than {} bytes", MAX_RESPONSE_SIZE));
+        }
+    }
+    let response = String::from_utf8(response).map_err(|_| "response is not valid UTF-8".to_string())?;
+    let status = response.lines().next().unwrap_or("");
+    if !status.starts_with("HTTP/1.1 200") && !status.starts_with("HTTP/1.0 200") {
+        return Err(format!("unexpected response {:?}", status));
+    }
+    Ok(response.split_once("\r\n\r\n").map_or("", |(_, body)| body).to_string())
+}
+
+// Time left until the deadline, an error once it passed
+fn remaining(deadline: Instant) -> Result<Duration, String> {
+    let now = Instant::now();
+    if now >= deadline {
+        Err(format!("timed out after {} seconds", HTTP_TIMEOUT.as_secs()))
+    } else {
+        Ok(deadline - now)
+    }
+}
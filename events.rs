@@ -0,0 +1,2028 @@
+// Event handlers keeping the metrics up to date
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use freeswitchrs::raw as fsr;
+use freeswitchrs::raw::log_level::{DEBUG, INFO, WARNING, ERROR};
+
+use crate::metrics::{METRICS, BILLSEC_BUCKETS, MOS_BUCKETS, LOSS_BUCKETS, WAIT_BUCKETS,
+              dynamic_labelled_counter_get, dynamic_labelled_gauge_get, dynamic_labelled_histogram_get, metric_name_part, sessions_peak_update};
+use crate::api;
+use crate::config::switch_true;
+use crate::registry::{Gauge, Histogram, glob_match, record_changes};
+use crate::{STATE, config_reload_later, current_config, lock};
+
+lazy_static! {
+    pub static ref JOURNAL: Mutex<VecDeque<String>> = {
+        Mutex::new(VecDeque::new())
+    };
+    // When each channel entered its current call state (microseconds since the epoch),
+    // by Unique-ID
+    static ref CALL_STATES: Mutex<HashMap<String, u64>> = {
+        Mutex::new(HashMap::new())
+    };
+    // When each call was parked (microseconds since the epoch) and its lot, by Unique-ID
+    static ref PARKED: Mutex<HashMap<String, (u64, String)>> = {
+        Mutex::new(HashMap::new())
+    };
+    // Task-ID of the scheduler tasks waiting to run
+    static ref SCHEDULER_TASKS: Mutex<HashSet<String>> = {
+        Mutex::new(HashSet::new())
+    };
+    // Tenants with their own metrics, up to max-tenants
+    static ref TENANTS: Mutex<HashSet<String>> = {
+        Mutex::new(HashSet::new())
+    };
+    // Accountcodes with their own billsec histogram, up to max-accountcodes
+    static ref ACCOUNTCODES: Mutex<HashSet<String>> = {
+        Mutex::new(HashSet::new())
+    };
+    // Whether each module seen loading or unloading is loaded, by name
+    static ref MODULES: Mutex<HashMap<String, bool>> = {
+        Mutex::new(HashMap::new())
+    };
+    // Limit realm and resource pairs with their own metrics, up to max-limit-resources
+    static ref LIMIT_RESOURCES: Mutex<HashSet<String>> = {
+        Mutex::new(HashSet::new())
+    };
+    // Dialplan applications with their own execution counters, up to max-applications
+    static ref APPLICATIONS: Mutex<HashSet<String>> = {
+        Mutex::new(HashSet::new())
+    };
+    // Valet parking lots with their own parked calls gauge, up to max-valet-lots
+    static ref VALET_LOTS: Mutex<HashSet<String>> = {
+        Mutex::new(HashSet::new())
+    };
+    // Sound languages with their own playback series, up to max-sound-languages
+    static ref SOUND_LANGUAGES: Mutex<HashSet<String>> = {
+        Mutex::new(HashSet::new())
+    };
+    // API commands with their own execution counters, up to max-api-commands
+    static ref API_COMMANDS: Mutex<HashSet<String>> = {
+        Mutex::new(HashSet::new())
+    };
+    // Background API job commands with their own counters, up to max-api-commands
+    static ref BGAPI_COMMANDS: Mutex<HashSet<String>> = {
+        Mutex::new(HashSet::new())
+    };
+    // Background jobs queued and not done yet, by command line, with the time they were queued
+    static ref BGAPI_QUEUED: Mutex<VecDeque<(String, u64)>> = {
+        Mutex::new(VecDeque::new())
+    };
+    // Hangup events recently processed, by Unique-ID and event type
+    static ref HANGUPS_SEEN: Mutex<RecentHangups> = {
+        Mutex::new(RecentHangups::new(MAX_HANGUP_CALLS_SEEN))
+    };
+    // Name of every built-in event binding, bound or disabled
+    static ref BINDINGS: Mutex<HashSet<&'static str>> = {
+        Mutex::new(HashSet::new())
+    };
+    // Unique-ID of the sessions currently transcoding
+    static ref TRANSCODING: Mutex<HashSet<String>> = {
+        Mutex::new(HashSet::new())
+    };
+    // Far-end networks with their own quality histograms, up to max-remote-networks
+    static ref REMOTE_NETWORKS: Mutex<HashSet<String>> = {
+        Mutex::new(HashSet::new())
+    };
+    // Bridge applications run by each call up, and whether one succeeded, by Unique-ID
+    static ref BRIDGE_ATTEMPTS: Mutex<HashMap<String, (u32, bool)>> = {
+        Mutex::new(HashMap::new())
+    };
+    // Queues with their own wait time histograms, up to max-queues
+    static ref QUEUES: Mutex<HashSet<String>> = {
+        Mutex::new(HashSet::new())
+    };
+    // Call center agents logged in, by name
+    static ref AGENTS: Mutex<HashMap<String, AgentTime>> = {
+        Mutex::new(HashMap::new())
+    };
+    // Media mode gauge of each answered session, by Unique-ID
+    static ref MEDIA_MODES: Mutex<HashMap<String, &'static Arc<Mutex<Gauge>>>> = {
+        Mutex::new(HashMap::new())
+    };
+    // Whether the media of each answered session is encrypted, by Unique-ID
+    static ref MEDIA_SESSIONS: Mutex<HashMap<String, bool>> = {
+        Mutex::new(HashMap::new())
+    };
+    // Loss fraction and jitter (seconds) of the last RTCP receiver report of each session
+    // up, by Unique-ID
+    static ref RTCP_REPORTS: Mutex<HashMap<String, (f64, f64)>> = {
+        Mutex::new(HashMap::new())
+    };
+    // Unique-ID of the calls up of each user (Caller-Username), for the fraud signals
+    static ref USER_CALLS: Mutex<HashMap<String, HashSet<String>>> = {
+        Mutex::new(HashMap::new())
+    };
+    // Route group of each call up, by Unique-ID
+    static ref CALL_ROUTES: Mutex<HashMap<String, String>> = {
+        Mutex::new(HashMap::new())
+    };
+    // When the inbound and outbound sessions of the last CPS_WINDOW seconds were created
+    // (microseconds since the epoch)
+    static ref SESSION_CREATES: Mutex<(VecDeque<u64>, VecDeque<u64>)> = {
+        Mutex::new((VecDeque::new(), VecDeque::new()))
+    };
+}
+
+// Seconds the sessions per second gauges are averaged over
+const CPS_WINDOW: u64 = 10;
+
+// Calls whose hangup events are remembered to drop duplicates
+const MAX_HANGUP_CALLS_SEEN: usize = 50000;
+// Background jobs remembered until done, the oldest ones are never timed past it
+const MAX_BGAPI_QUEUED: usize = 10000;
+
+// API commands whose first argument is counted along, as it picks what they do (e.g. show
+// channels, much more expensive than show codec)
+const SUBCOMMAND_APIS: [&str; 3] = ["show", "sofia", "fsctl"];
+
+// Fired by ESL clients to update user metrics
+const UPDATE_SUBCLASS: &str = "prometheus::update";
+
+// Hangup events recently delivered, by Unique-ID and event type, along with the
+// Event-Sequence of the first delivery and of the last duplicate one. Every binding of the
+// event gets the same delivery, only another delivery of the same hangup is a duplicate.
+// The oldest calls are forgotten first
+struct RecentHangups {
+    seen: HashMap<(String, u32), (u64, u64)>,
+    order: VecDeque<(String, u32)>,
+    // Two hangup events per call, HANGUP and HANGUP_COMPLETE
+    max: usize,
+}
+
+impl RecentHangups {
+    fn new(calls: usize) -> RecentHangups {
+        RecentHangups { seen: HashMap::new(), order: VecDeque::new(), max: 2 * calls }
+    }
+
+    // Whether the delivery of the event is a duplicate to drop, counting each duplicate
+    // delivery once whatever the number of bindings
+    fn duplicate(&mut self, uuid: &str, event: fsr::event_types, sequence: u64) -> bool {
+        let key = (uuid.to_string(), event as u32);
+        if let Some(&mut (first, ref mut last_duplicate)) = self.seen.get_mut(&key) {
+            if sequence == first {
+                return false;
+            }
+            if sequence != *last_duplicate {
+                *last_duplicate = sequence;
+                lock(&METRICS.events_duplicate).increment();
+            }
+            return true;
+        }
+        if self.order.len() >= self.max {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.seen.insert(key.clone(), (sequence, sequence));
+        self.order.push_back(key);
+        false
+    }
+}
+
+// Status and state of a call center agent, and the queue and time (microseconds since the
+// epoch) its time was last credited to
+struct AgentTime {
+    status: String,
+    state: String,
+    queue: String,
+    since: u64,
+}
+
+impl AgentTime {
+    // Agents seen for the first time are most likely logged in and waiting for calls
+    fn new(now: u64) -> AgentTime {
+        AgentTime { status: "Available".to_string(), state: "Waiting".to_string(), queue: "unknown".to_string(),
+                    since: now }
+    }
+
+    fn activity(&self) -> Option<&'static str> {
+        match &self.status[..] {
+            "On Break" => Some("break"),
+            s if s.starts_with("Available") => match &self.state[..] {
+                "Waiting" => Some("available"),
+                "Receiving" | "In a queue call" => Some("on_call"),
+                "Idle" => Some("wrap_up"),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // Adds the time since the last credit to the counter of the current activity
+    fn credit(&mut self, now: u64) {
+        if now <= self.since {
+            return;
+        }
+        if let Some(activity) = self.activity() {
+            let queue = capped(&QUEUES, self.queue.clone(), current_config().max_queues);
+            lock(&dynamic_labelled_counter_get("freeswitch_queue_agent_seconds_total", &[("queue", &queue), ("activity", activity)],
+                                               "FreeSWITCH Call Center Agent Time per State")).increment_by((now - self.since) as f64 / 1_000_000.0);
+        }
+        self.since = now;
+    }
+}
+
+// Level of the per-call log lines, DEBUG so they don't flood the logs at high CPS unless
+// raised with log-verbosity or prom_loglevel
+static CALL_LOG_LEVEL: AtomicUsize = AtomicUsize::new(DEBUG as usize);
+// Events processed since load, each one once whatever the number of bindings
+static EVENTS_PROCESSED: AtomicUsize = AtomicUsize::new(0);
+
+pub fn events_processed() -> usize {
+    EVENTS_PROCESSED.load(Ordering::Relaxed)
+}
+
+pub fn call_log_level() -> fsr::log_level::Type {
+    CALL_LOG_LEVEL.load(Ordering::Relaxed) as fsr::log_level::Type
+}
+
+pub fn set_call_log_level(level: fsr::log_level::Type) {
+    CALL_LOG_LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+// What the handlers need from an event, so they can be driven by synthetic events
+// in the tests as well as by the FreeSWITCH ones
+pub trait EventData {
+    fn header<'a>(&'a self, name: &str) -> Option<Cow<'a, str>>;
+    fn body<'a>(&'a self) -> Option<Cow<'a, str>>;
+}
+
+impl EventData for freeswitchrs::Event {
+    fn header<'a>(&'a self, name: &str) -> Option<Cow<'a, str>> {
+        freeswitchrs::Event::header(self, name)
+    }
+
+    fn body<'a>(&'a self) -> Option<Cow<'a, str>> {
+        freeswitchrs::Event::body(self)
+    }
+}
+
+pub type Handler = Box<dyn Fn(&dyn EventData) + Send + Sync>;
+
+// Where the handlers get bound
+pub trait EventBinder {
+    fn bind(&mut self, event: fsr::event_types, subclass: Option<&str>, handler: Handler);
+}
+
+// Binds to the FreeSWITCH event system, keeping the ids in the module state so
+// they are unbound on unload
+pub struct CoreEventBinder;
+
+impl EventBinder for CoreEventBinder {
+    fn bind(&mut self, event: fsr::event_types, subclass: Option<&str>, handler: Handler) {
+        let id = freeswitchrs::event_bind("mod_prometheus", event, subclass, move |e| handler(e));
+        if let Some(ref mut state) = *lock(&STATE) {
+            state.event_node_ids.push(id);
+        }
+    }
+}
+
+// HANGUPS_SEEN is kept, hangups can be delivered again around a reload
+pub fn clear() {
+    lock(&JOURNAL).clear();
+    lock(&CALL_STATES).clear();
+    lock(&TRANSCODING).clear();
+    lock(&MEDIA_SESSIONS).clear();
+    lock(&MEDIA_MODES).clear();
+    lock(&RTCP_REPORTS).clear();
+    lock(&REMOTE_NETWORKS).clear();
+    lock(&QUEUES).clear();
+    lock(&BRIDGE_ATTEMPTS).clear();
+    lock(&AGENTS).clear();
+    lock(&PARKED).clear();
+    lock(&SCHEDULER_TASKS).clear();
+    lock(&MODULES).clear();
+    lock(&TENANTS).clear();
+    lock(&ACCOUNTCODES).clear();
+    lock(&LIMIT_RESOURCES).clear();
+    lock(&APPLICATIONS).clear();
+    lock(&API_COMMANDS).clear();
+    lock(&SOUND_LANGUAGES).clear();
+    lock(&VALET_LOTS).clear();
+    lock(&BGAPI_QUEUED).clear();
+    lock(&BGAPI_COMMANDS).clear();
+    lock(&BINDINGS).clear();
+    lock(&USER_CALLS).clear();
+    lock(&CALL_ROUTES).clear();
+    *lock(&SESSION_CREATES) = (VecDeque::new(), VecDeque::new());
+    EVENTS_PROCESSED.store(0, Ordering::Relaxed);
+}
+
+pub fn bind_events(binder: &mut dyn EventBinder) {
+    let mut binder = EventTypesBinder { binder, bound: Vec::new() };
+    bind_handlers(&mut binder);
+    binder.bind_stats();
+}
+
+// Binds on behalf of the handlers, remembering the events bound so that the statistics
+// common to all the events are kept by a single binding per event rather than by every one
+struct EventTypesBinder<'a> {
+    binder: &'a mut dyn EventBinder,
+    bound: Vec<(fsr::event_types, Option<String>)>,
+}
+
+impl<'a> EventBinder for EventTypesBinder<'a> {
+    fn bind(&mut self, event: fsr::event_types, subclass: Option<&str>, handler: Handler) {
+        let key = (event, subclass.map(|s| s.to_string()));
+        if !self.bound.contains(&key) {
+            self.bound.push(key);
+        }
+        self.binder.bind(event, subclass, handler);
+    }
+}
+
+impl<'a> EventTypesBinder<'a> {
+    // Bound last, the events have been through the handlers already
+    fn bind_stats(self) {
+        for (event, subclass) in self.bound {
+            self.binder.bind(event, subclass.as_ref().map(|s| &s[..]), Box::new(move |e| {
+                if event_wanted(event, e) {
+                    EVENTS_PROCESSED.fetch_add(1, Ordering::Relaxed);
+                    event_lag_update(e);
+                }
+            }));
+        }
+    }
+}
+
+fn bind_handlers(binder: &mut dyn EventBinder) {
+    // Heartbeat counts
+    bind_event(binder, "heartbeat", fsr::event_types::HEARTBEAT, None, |_| {
+        lock(&METRICS.heartbeats).increment();
+    });
+
+    // New channel created
+    bind_event(binder, "channel_create", fsr::event_types::CHANNEL_CREATE, None, |e| {
+        lock(&METRICS.sessions_created).increment();
+        lock(&METRICS.channels_active).increment();
+        if is_b_leg(e) {
+            lock(&METRICS.sessions_b_leg).increment();
+        } else {
+            lock(&METRICS.calls).increment();
+            lock(&METRICS.calls_active).increment();
+        }
+        if let Some(direction) = e.header("Call-Direction") {
+            if direction == "inbound" {
+                lock(&METRICS.sessions_active_inbound).increment();
+                lock(&METRICS.sessions_inbound_created).increment();
+                let total = lock(&METRICS.sessions_inbound_created).value();
+                let asr = lock(&METRICS.sessions_inbound_answered).value() / total;
+                lock(&METRICS.sessions_inbound_asr).set(asr);
+            } else if direction == "outbound" {
+                lock(&METRICS.sessions_active_outbound).increment();
+                lock(&METRICS.sessions_outbound_created).increment();
+                let total = lock(&METRICS.sessions_outbound_created).value();
+                let asr = lock(&METRICS.sessions_outbound_answered).value() / total;
+                lock(&METRICS.sessions_outbound_asr).set(asr);
+            } else {
+                lock(&METRICS.sessions_active_other).increment();
+                lock(&METRICS.sessions_other_created).increment();
+            }
+        } else {
+            lock(&METRICS.sessions_active_other).increment();
+            lock(&METRICS.sessions_other_created).increment();
+        }
+        sessions_peak_update();
+    });
+
+    // Sessions created per direction for the sessions per second gauges, and calls
+    // (A-legs, e.g. the originate attempts of a dialer) per direction
+    bind_event(binder, "session_rate", fsr::event_types::CHANNEL_CREATE, None, |e| {
+        let time = event_time(e).unwrap_or_else(now_micros);
+        let mut creates = lock(&SESSION_CREATES);
+        let (times, calls) = match e.header("Call-Direction") {
+            Some(ref d) if d == "inbound" => (&mut creates.0, &METRICS.calls_inbound),
+            Some(ref d) if d == "outbound" => (&mut creates.1, &METRICS.calls_outbound),
+            _ => return,
+        };
+        times.push_back(time);
+        while times.front().is_some_and(|t| *t + CPS_WINDOW * 1_000_000 < time) {
+            times.pop_front();
+        }
+        if !is_b_leg(e) {
+            lock(calls).increment();
+        }
+    });
+
+    // Suspicious calls, counted by signal: outbound calls to high-rate destinations or to
+    // international destinations outside of the business hours, and inbound calls of a
+    // user having too many calls up at once
+    bind_event(binder, "fraud_signals", fsr::event_types::CHANNEL_CREATE, None, |e| {
+        let config = current_config();
+        let signal = |name: &str| {
+            lock(&dynamic_labelled_counter_get("freeswitch_fraud_signals_total", &[("signal", name)], "FreeSWITCH Fraud Signals")).increment();
+        };
+        match e.header("Call-Direction") {
+            Some(ref d) if d == "outbound" => {
+                let destination = e.header("Caller-Destination-Number").unwrap_or(Cow::Borrowed(""));
+                if config.fraud_prefixes.iter().any(|p| destination.starts_with(&p[..])) {
+                    signal("high_rate_destination");
+                }
+                if let Some((start, end)) = config.business_hours {
+                    // Event-Date-Local is YYYY-MM-DD hh:mm:ss
+                    let hour = e.header("Event-Date-Local").and_then(|t| t.get(11..13).and_then(|h| h.parse::<u32>().ok()));
+                    if hour.is_some_and(|h| h < start || h >= end) &&
+                       config.international_prefixes.iter().any(|p| destination.starts_with(&p[..])) {
+                        signal("after_hours_international");
+                    }
+                }
+            }
+            Some(ref d) if d == "inbound" && config.fraud_max_user_calls > 0 => {
+                let (user, uuid) = match (e.header("Caller-Username"), e.header("Unique-ID")) {
+                    (Some(u), Some(id)) => (u, id),
+                    _ => return,
+                };
+                let mut users = lock(&USER_CALLS);
+                let calls = users.entry(user.to_string()).or_default();
+                calls.insert(uuid.to_string());
+                if calls.len() > config.fraud_max_user_calls {
+                    signal("user_concurrent_calls");
+                }
+            }
+            _ => {}
+        }
+    });
+
+    bind_event(binder, "fraud_user_calls", fsr::event_types::CHANNEL_DESTROY, None, |e| {
+        let (user, uuid) = match (e.header("Caller-Username"), e.header("Unique-ID")) {
+            (Some(u), Some(id)) => (u, id),
+            _ => return,
+        };
+        let mut users = lock(&USER_CALLS);
+        let empty = match users.get_mut(&user[..]) {
+            Some(calls) => {
+                calls.remove(&uuid[..]);
+                calls.is_empty()
+            }
+            None => return,
+        };
+        if empty {
+            users.remove(&user[..]);
+        }
+    });
+
+    // Calls (A-legs) per route group, by the number dialed when they were created as
+    // transfers can change it later on
+    bind_event(binder, "route_calls", fsr::event_types::CHANNEL_CREATE, None, |e| {
+        let config = current_config();
+        if config.routes.is_empty() || is_b_leg(e) {
+            return;
+        }
+        let uuid = match e.header("Unique-ID") {
+            Some(u) => u,
+            None => return,
+        };
+        let destination = e.header("Caller-Destination-Number").unwrap_or(Cow::Borrowed(""));
+        let route = prefix_group(&config.routes, &destination);
+        lock(&dynamic_labelled_counter_get("freeswitch_route_created_total", &[("route", &route)], "FreeSWITCH Calls Created per Route")).increment();
+        lock(&CALL_ROUTES).insert(uuid.to_string(), route);
+    });
+
+    // Calls (A-legs) per country of the calling and of the called number, bucketed by
+    // calling code to keep per number cardinality out
+    bind_event(binder, "country_calls", fsr::event_types::CHANNEL_CREATE, None, |e| {
+        let config = current_config();
+        if config.countries.is_empty() || is_b_leg(e) {
+            return;
+        }
+        for &(header, what) in [("Caller-Caller-ID-Number", "calling"), ("Caller-Destination-Number", "called")].iter() {
+            if let Some(number) = e.header(header) {
+                let country = prefix_group(&config.countries, &number);
+                lock(&dynamic_labelled_counter_get("freeswitch_country_calls_total", &[("country", &country), ("result", what)],
+                                                   "FreeSWITCH Calls per Country of the Calling or Called Number")).increment();
+            }
+        }
+    });
+
+    bind_event(binder, "route_answer", fsr::event_types::CHANNEL_ANSWER, None, |e| {
+        let route = match e.header("Unique-ID").and_then(|u| lock(&CALL_ROUTES).get(&u[..]).cloned()) {
+            Some(r) => r,
+            None => return,
+        };
+        lock(&dynamic_labelled_counter_get("freeswitch_route_answered_total", &[("route", &route)], "FreeSWITCH Calls Answered per Route")).increment();
+    });
+
+    bind_event(binder, "route_hangup", fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, |e| {
+        let route = match e.header("Unique-ID").and_then(|u| lock(&CALL_ROUTES).remove(&u[..])) {
+            Some(r) => r,
+            None => return,
+        };
+        if e.header("Caller-Channel-Answered-Time").is_some_and(|t| t != "0") {
+            lock(&dynamic_labelled_counter_get("freeswitch_route_duration_seconds_total", &[("route", &route)],
+                                               "FreeSWITCH Calls Billed Duration per Route")).increment_by(billed_seconds(e).unwrap_or(0.0));
+        }
+    });
+
+    // Encrypted (SRTP, either SDES or DTLS) vs plain RTP media of the answered sessions.
+    // Sessions without negotiated RTP (no rtp_use_codec_name, e.g. loopback) are left out
+    bind_event(binder, "media_encryption", fsr::event_types::CHANNEL_ANSWER, None, |e| {
+        let uuid = match e.header("Unique-ID") {
+            Some(u) => u,
+            None => return,
+        };
+        if e.header("variable_rtp_use_codec_name").is_none() {
+            return;
+        }
+        let srtp = e.header("variable_rtp_secure_media_confirmed").is_some_and(|v| switch_true(&v)) ||
+                   e.header("variable_rtp_has_crypto").is_some_and(|v| !v.is_empty());
+        if lock(&MEDIA_SESSIONS).insert(uuid.to_string(), srtp).is_some() {
+            return;
+        }
+        if srtp {
+            lock(&METRICS.media_srtp_sessions).increment();
+            lock(&METRICS.media_srtp_sessions_active).increment();
+        } else {
+            lock(&METRICS.media_rtp_sessions).increment();
+            lock(&METRICS.media_rtp_sessions_active).increment();
+        }
+    });
+
+    // Media mode of the answered sessions: bypass media (RTP flows between the endpoints),
+    // proxy media (RTP relayed as is) or full media (RTP terminated, decoded when needed)
+    bind_event(binder, "media_mode", fsr::event_types::CHANNEL_ANSWER, None, |e| {
+        let uuid = match e.header("Unique-ID") {
+            Some(u) => u,
+            None => return,
+        };
+        let enabled = |name: &str| e.header(name).is_some_and(|v| switch_true(&v));
+        let gauge = if enabled("variable_bypass_media") {
+            &METRICS.sessions_active_media_bypass
+        } else if enabled("variable_proxy_media") {
+            &METRICS.sessions_active_media_proxy
+        } else {
+            &METRICS.sessions_active_media_full
+        };
+        if lock(&MEDIA_MODES).insert(uuid.to_string(), gauge).is_none() {
+            lock(gauge).increment();
+        }
+    });
+
+    // Channel answered
+    bind_event(binder, "channel_answer", fsr::event_types::CHANNEL_ANSWER, None, |e| {
+        lock(&METRICS.sessions_answered).increment();
+        // Set once the session got early media, "0" otherwise
+        let early_media = e.header("Caller-Channel-Progress-Media-Time").is_some_and(|t| t != "0");
+        if let Some(direction) = e.header("Call-Direction") {
+            if direction == "inbound" {
+                lock(&METRICS.sessions_inbound_answered).increment();
+                let answered = lock(&METRICS.sessions_inbound_answered).value();
+                let asr = answered / lock(&METRICS.sessions_inbound_created).value();
+                lock(&METRICS.sessions_inbound_asr).set(asr);
+                if early_media {
+                    lock(&METRICS.sessions_inbound_answered_early_media).increment();
+                } else {
+                    lock(&METRICS.sessions_inbound_answered_direct).increment();
+                }
+            } else if direction == "outbound" {
+                lock(&METRICS.sessions_outbound_answered).increment();
+                let answered = lock(&METRICS.sessions_outbound_answered).value();
+                let asr = answered / lock(&METRICS.sessions_outbound_created).value();
+                lock(&METRICS.sessions_outbound_asr).set(asr);
+                if early_media {
+                    lock(&METRICS.sessions_outbound_answered_early_media).increment();
+                } else {
+                    lock(&METRICS.sessions_outbound_answered_direct).increment();
+                }
+            } else {
+                lock(&METRICS.sessions_other_answered).increment();
+            }
+        } else {
+            lock(&METRICS.sessions_other_answered).increment();
+        }
+        transcoding_update(e);
+    });
+
+    // Codecs changed mid-call (e.g. re-INVITE)
+    bind_event(binder, "codec", fsr::event_types::CODEC, None, |e| {
+        transcoding_update(e);
+    });
+
+    // Channel hangup
+    bind_event(binder, "channel_hangup", fsr::event_types::CHANNEL_HANGUP, None, |e| {
+        if let Some(direction) = e.header("Call-Direction") {
+            if direction == "inbound" {
+                lock(&METRICS.sessions_inbound_call_hangup).increment();
+            } else if direction == "outbound" {
+                lock(&METRICS.sessions_outbound_call_hangup).increment();
+            }
+        }
+        if let Some(answer_timestamp) = e.header("Caller-Channel-Answered-Time") {
+            if let Ok(myts) = answer_timestamp.parse::<i64>() {
+
+                if myts == 0 { // no timestamp in hangup event means call failure
+                    match e.header("Call-Direction") {
+                        Some(ref d) if d == "inbound" => lock(&METRICS.sessions_inbound_failed).increment(),
+                        Some(ref d) if d == "outbound" => lock(&METRICS.sessions_outbound_failed).increment(),
+                        _ => lock(&METRICS.sessions_other_failed).increment(),
+                    };
+                    lock(&METRICS.sessions_failed).increment();
+                }
+            }
+        } else {
+            let b = e.body().unwrap_or(Cow::Borrowed("<No Body>"));
+            fslog!(WARNING, "Received channel hangup event with no call answer time information: {:?}\n", b);
+            lock(&METRICS.events_dropped).increment();
+        }
+    });
+
+    // Channel hangup complete
+    bind_event(binder, "channel_hangup_complete", fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, |e| {
+        let mut callid = String::new();
+        let mut uuid = String::new();
+        let mut direction = String::new();
+
+        if let Some(sip_callid) = e.header("variable_sip_call_id"){
+            callid = sip_callid.to_string();
+        }
+        if let Some(unique_id )= e.header("Unique-ID") {
+            uuid = unique_id.to_string();
+        }
+        if let Some(call_direction) = e.header("Call-Direction") {
+            direction = call_direction.to_string();
+        }
+        fslog!(call_log_level(), "callid:{:#?} uniqueId:{:#?} {:#?} CHANNEL_HANGUP_COMPLETE\n", callid, uuid, direction);
+
+        if let Some(hup_cause) = e.header("Hangup-Cause") {
+
+            fslog!(call_log_level(), "callid:{:#?} uniqueId:{:#?} {:#?} CHANNEL_HANGUP_COMPLETE hupCause:{:#?}\n", callid, uuid, direction, hup_cause.clone());
+
+            if hup_cause == "NORMAL_CLEARING" {  // NORMAL_CLEARING or ORIGINATOR_CANCEL or NO_USER_RESPONSE
+                match billed_seconds(e) {
+                    Some(bill_seconds) => {
+                        if direction == "outbound" {
+                            lock(&METRICS.sessions_outbound_call_duration_total).increment_by(bill_seconds);
+                            lock(&METRICS.sessions_outbound_call_hangup_complete).increment();
+
+                            let total_seconds = lock(&METRICS.sessions_outbound_call_duration_total).value();
+                            let total_hup = lock(&METRICS.sessions_outbound_call_hangup_complete).value();
+                            let acd_out = total_seconds / total_hup;
+
+                            lock(&METRICS.sessions_outbound_acd).set(acd_out);
+
+                            fslog!(call_log_level(), "callid:{:#?} uniqueId:{:#?} {:#?} bill:{:#?} sec. totalHup:{:#?} total:{:#?} sec. acd:{:#?} \n",
+                                callid, uuid, direction, bill_seconds, total_hup, total_seconds, acd_out);
+
+                        } else if direction == "inbound" {
+
+                            lock(&METRICS.sessions_inbound_call_duration_total).increment_by(bill_seconds);
+                            lock(&METRICS.sessions_inbound_call_hangup_complete).increment();
+
+                            let total_seconds = lock(&METRICS.sessions_inbound_call_duration_total).value();
+                            let total_hup = lock(&METRICS.sessions_inbound_call_hangup_complete).value();
+                            let acd_in = total_seconds / total_hup;
+
+                            lock(&METRICS.sessions_inbound_acd).set(acd_in);
+
+                            fslog!(call_log_level(), "callid:{:#?} uniqueId:{:#?} {:#?} bill:{:#?} sec. totalHup:{:#?} total:{:#?} sec. acd:{:#?} \n",
+                                callid, uuid, direction, bill_seconds, total_hup, total_seconds, acd_in);
+                        }
+                    }
+                    None if e.header("variable_billsec").is_some() => {
+                        fslog!(ERROR, "callid:{:#?} uniqueId:{:#?} {:#?} CHANNEL_HANGUP_COMPLETE error parsing variable_billsec header\n",callid, uuid, direction);
+                        lock(&METRICS.callback_errors).increment();
+                    }
+                    None => {
+                        fslog!(ERROR, "callid:{:#?} uniqueId:{:#?} {:#?} CHANNEL_HANGUP_COMPLETE without variable_billsec header\n",callid, uuid, direction);
+                        lock(&METRICS.callback_errors).increment();
+                    }
+                }
+            }
+        } else {
+            fslog!(ERROR, "callid:{:#?} uniqueId:{:#?} {:#?} CHANNEL_HANGUP_COMPLETE without Hangup-Cause header\n",callid, uuid, direction);
+            lock(&METRICS.callback_errors).increment();
+        }
+    });
+
+    // Channel destroyed
+    bind_event(binder, "channel_destroy", fsr::event_types::CHANNEL_DESTROY, None, |e| {
+        lock(&METRICS.sessions_destroyed).increment();
+        gauge_decrement(&METRICS.channels_active);
+        if !is_b_leg(e) {
+            gauge_decrement(&METRICS.calls_active);
+        }
+        if let Some(uuid) = e.header("Unique-ID") {
+            lock(&CALL_STATES).remove(&uuid[..]);
+            if lock(&TRANSCODING).remove(&uuid[..]) {
+                gauge_decrement(&METRICS.transcoding_sessions_active);
+            }
+            lock(&RTCP_REPORTS).remove(&uuid[..]);
+            if let Some(gauge) = lock(&MEDIA_MODES).remove(&uuid[..]) {
+                gauge_decrement(gauge);
+            }
+            match lock(&MEDIA_SESSIONS).remove(&uuid[..]) {
+                Some(true) => gauge_decrement(&METRICS.media_srtp_sessions_active),
+                Some(false) => gauge_decrement(&METRICS.media_rtp_sessions_active),
+                None => {}
+            }
+        }
+        if let Some(direction) = e.header("Call-Direction") {
+            if direction == "inbound" {
+                gauge_decrement(&METRICS.sessions_active_inbound);
+            } else if direction == "outbound" {
+                gauge_decrement(&METRICS.sessions_active_outbound);
+            } else {
+                gauge_decrement(&METRICS.sessions_active_other);
+            }
+        } else {
+            gauge_decrement(&METRICS.sessions_active_other);
+        }
+    });
+
+    // Provisional responses: CHANNEL_PROGRESS is a 180 Ringing, CHANNEL_PROGRESS_MEDIA
+    // a 183 Session Progress (early media)
+    bind_event(binder, "channel_progress", fsr::event_types::CHANNEL_PROGRESS, None, |e| {
+        match e.header("Call-Direction") {
+            Some(ref d) if d == "inbound" => lock(&METRICS.sessions_inbound_progress).increment(),
+            Some(ref d) if d == "outbound" => lock(&METRICS.sessions_outbound_progress).increment(),
+            _ => return,
+        };
+    });
+
+    bind_event(binder, "channel_progress_media", fsr::event_types::CHANNEL_PROGRESS_MEDIA, None, |e| {
+        match e.header("Call-Direction") {
+            Some(ref d) if d == "inbound" => lock(&METRICS.sessions_inbound_progress_media).increment(),
+            Some(ref d) if d == "outbound" => lock(&METRICS.sessions_outbound_progress_media).increment(),
+            _ => return,
+        };
+    });
+
+    // Transfers done through the dialplan applications, blind (transfer, deflect sending a
+    // REFER, redirect sending a 302) or attended (att_xfer)
+    bind_event(binder, "transfer", fsr::event_types::CHANNEL_EXECUTE_COMPLETE, None, |e| {
+        let kind = match e.header("Application") {
+            Some(ref a) if a == "transfer" || a == "deflect" || a == "redirect" => "blind",
+            Some(ref a) if a == "att_xfer" => "attended",
+            _ => return,
+        };
+        let failed = if kind == "attended" {
+            // The consultation call att_xfer originated must have been answered
+            e.header("variable_originate_disposition").is_none_or(|d| d != "SUCCESS" && d != "ANSWER")
+        } else {
+            e.header("Application-Response").is_some_and(|r| r.starts_with("-ERR"))
+        };
+        let result = if failed { "failure" } else { "success" };
+        lock(&dynamic_labelled_counter_get("freeswitch_transfers_total", &[("kind", kind), ("result", result)],
+                                           "FreeSWITCH Transfers per Kind and Result")).increment();
+    });
+
+    // Dialplan applications executed, and the ones that failed (replied -ERR, e.g. a
+    // transfer to a missing extension or a playback of a missing file), per application
+    bind_event(binder, "application_execute", fsr::event_types::CHANNEL_EXECUTE, None, |e| {
+        if let Some(app) = e.header("Application") {
+            lock(&dynamic_labelled_counter_get("freeswitch_applications_executed_total", &[("application", &application(&app))],
+                                               "FreeSWITCH Dialplan Application Executions")).increment();
+        }
+    });
+    bind_event(binder, "application_failure", fsr::event_types::CHANNEL_EXECUTE_COMPLETE, None, |e| {
+        let app = match e.header("Application") {
+            Some(a) => a,
+            None => return,
+        };
+        if e.header("Application-Response").is_some_and(|r| r.starts_with("-ERR")) {
+            lock(&dynamic_labelled_counter_get("freeswitch_applications_failed_total", &[("application", &application(&app))],
+                                               "FreeSWITCH Dialplan Application Failures")).increment();
+        }
+    });
+
+    // Bridges that failed, per originate disposition (the cause of the last leg tried when
+    // failing over) or, without one, the hangup cause of the last bridge. A dialplan
+    // failing over to its next route with another bridge counts each failed one
+    bind_event(binder, "bridge_failure", fsr::event_types::CHANNEL_EXECUTE_COMPLETE, None, |e| {
+        match e.header("Application") {
+            Some(ref a) if a == "bridge" => {}
+            _ => return,
+        }
+        let cause = match e.header("variable_originate_disposition") {
+            Some(ref d) if d == "SUCCESS" || d == "ANSWER" => return,
+            Some(d) => d,
+            None => match e.header("variable_last_bridge_hangup_cause") {
+                Some(c) => c,
+                None => return,
+            },
+        };
+        lock(&dynamic_labelled_counter_get("freeswitch_bridges_failed_total", &[("cause", &cause)],
+                                           "FreeSWITCH Failed Bridges per Cause")).increment();
+    });
+
+    // Bridge attempts per call, a dialplan advancing to its next route after a failed bridge
+    // runs another one. Counted once the call is over, in route_advance
+    bind_event(binder, "bridge_attempts", fsr::event_types::CHANNEL_EXECUTE_COMPLETE, None, |e| {
+        let uuid = match (e.header("Application"), e.header("Unique-ID")) {
+            (Some(ref a), Some(u)) if a == "bridge" => u,
+            _ => return,
+        };
+        let bridged = e.header("variable_originate_disposition").is_some_and(|d| d == "SUCCESS" || d == "ANSWER");
+        let mut attempts = lock(&BRIDGE_ATTEMPTS);
+        let attempt = attempts.entry(uuid.to_string()).or_default();
+        attempt.0 += 1;
+        attempt.1 |= bridged;
+    });
+
+    bind_event(binder, "route_advance", fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, |e| {
+        let counted = e.header("Unique-ID").and_then(|u| lock(&BRIDGE_ATTEMPTS).remove(&u[..]));
+        let (attempts, bridged) = match current_config().route_attempts_variable.as_ref()
+                                      .and_then(|v| e.header(&format!("variable_{}", v)))
+                                      .and_then(|a| a.parse::<u32>().ok()) {
+            Some(a) => (a, counted.is_some_and(|c| c.1) || e.header("variable_last_bridge_to").is_some()),
+            None => match counted {
+                Some(c) => c,
+                None => return,
+            },
+        };
+        if attempts == 0 {
+            return;
+        }
+        lock(&METRICS.calls_bridge_attempts).increment_by(attempts as f64);
+        if attempts > 1 {
+            lock(&METRICS.calls_route_advanced).increment();
+        }
+        if bridged {
+            lock(&METRICS.calls_attempts_to_success).observe(attempts as f64);
+        }
+    });
+
+    // Usage of each limit resource as reported by the limit backend (hash, db ...) when a
+    // call takes it, along with its maximum
+    bind_event(binder, "limit_usage", fsr::event_types::CUSTOM, Some("limit::usage"), |e| {
+        let (realm, resource, usage) = match (e.header("realm"), e.header("key"),
+                                              e.header("usage").and_then(|u| u.parse::<f64>().ok())) {
+            (Some(r), Some(k), Some(u)) => (r, k, u),
+            _ => {
+                let b = e.body().unwrap_or(Cow::Borrowed("<No Body>"));
+                fslog!(WARNING, "Received limit usage event with no realm, key or usage: {:?}\n", b);
+                lock(&METRICS.events_dropped).increment();
+                return;
+            }
+        };
+        let (realm, resource) = limit_resource(&realm, &resource);
+        let labels = [("realm", &realm[..]), ("resource", &resource[..])];
+        lock(&dynamic_labelled_gauge_get("freeswitch_limit_usage", &labels,
+                                         "FreeSWITCH Limit Resource Usage")).set(usage);
+        if let Some(max) = e.header("max").and_then(|m| m.parse::<f64>().ok()) {
+            lock(&dynamic_labelled_gauge_get("freeswitch_limit_max", &labels,
+                                             "FreeSWITCH Limit Resource Maximum")).set(max);
+        }
+    });
+
+    // Calls turned away by the limit application: over the limit it transfers the call to
+    // the given extension (limit_exceeded by default) or hangs it up when that is "!"
+    bind_event(binder, "limit_rejected", fsr::event_types::CHANNEL_EXECUTE_COMPLETE, None, |e| {
+        match e.header("Application") {
+            Some(ref a) if a == "limit" => {}
+            _ => return,
+        }
+        let data = e.header("Application-Data").map(|d| d.to_string()).unwrap_or(String::new());
+        // <backend> <realm> <resource> [<max>[/<interval>]] [<number> [<dialplan> [<context>]]]
+        let args: Vec<&str> = data.split_whitespace().collect();
+        if args.len() < 3 {
+            return;
+        }
+        let rejected = match args.get(4).cloned().unwrap_or("limit_exceeded") {
+            "!" => e.header("Channel-Call-State").is_some_and(|s| s == "HANGUP"),
+            exten => e.header("Caller-Destination-Number").is_some_and(|d| d == exten),
+        };
+        if rejected {
+            let (realm, resource) = limit_resource(args[1], args[2]);
+            lock(&dynamic_labelled_counter_get("freeswitch_limit_rejected_total", &[("realm", &realm), ("resource", &resource)],
+                                               "FreeSWITCH Calls Rejected per Limit Resource")).increment();
+        }
+    });
+
+    // Call setup phases: creation to RINGING, time RINGING and time in EARLY media
+    bind_event(binder, "channel_callstate", fsr::event_types::CHANNEL_CALLSTATE, None, |e| {
+        let (uuid, state, now) = match (e.header("Unique-ID"), e.header("Channel-Call-State"), event_time(e)) {
+            (Some(u), Some(s), Some(t)) => (u, s, t),
+            _ => {
+                let b = e.body().unwrap_or(Cow::Borrowed("<No Body>"));
+                fslog!(WARNING, "Received channel callstate event with no call state or time: {:?}\n", b);
+                lock(&METRICS.events_dropped).increment();
+                return;
+            }
+        };
+        let (time_to_ring, ring, early_media) = match e.header("Call-Direction") {
+            Some(ref d) if d == "inbound" => (&METRICS.sessions_inbound_time_to_ring, &METRICS.sessions_inbound_ring,
+                                              &METRICS.sessions_inbound_early_media),
+            Some(ref d) if d == "outbound" => (&METRICS.sessions_outbound_time_to_ring, &METRICS.sessions_outbound_ring,
+                                               &METRICS.sessions_outbound_early_media),
+            _ => return,
+        };
+        let seconds = |since: u64| now.saturating_sub(since) as f64 / 1_000_000.0;
+        let mut states = lock(&CALL_STATES);
+        if let Some(since) = states.get(&uuid[..]).cloned() {
+            match e.header("Original-Channel-Call-State") {
+                Some(ref s) if s == "RINGING" => observe_call(ring, seconds(since), e),
+                Some(ref s) if s == "EARLY" => observe_call(early_media, seconds(since), e),
+                _ => {}
+            }
+        }
+        if state == "RINGING" {
+            if let Some(created) = e.header("Caller-Channel-Created-Time").and_then(|t| t.parse::<u64>().ok()) {
+                observe_call(time_to_ring, seconds(created), e);
+            }
+        }
+        if state == "HANGUP" || state == "DOWN" {
+            states.remove(&uuid[..]);
+        } else {
+            states.insert(uuid.to_string(), now);
+        }
+    });
+
+    // Time to answer per direction: agent answer speed for inbound calls, carrier setup
+    // time for outbound ones
+    bind_event(binder, "answer_delay", fsr::event_types::CHANNEL_ANSWER, None, |e| {
+        let histogram = match e.header("Call-Direction") {
+            Some(ref d) if d == "inbound" => &METRICS.sessions_inbound_answer_delay,
+            Some(ref d) if d == "outbound" => &METRICS.sessions_outbound_answer_delay,
+            _ => return,
+        };
+        let time = |h: &str| e.header(h).and_then(|t| t.parse::<u64>().ok()).filter(|t| *t > 0);
+        if let (Some(created), Some(answered)) = (time("Caller-Channel-Created-Time"), time("Caller-Channel-Answered-Time")) {
+            observe_call(histogram, answered.saturating_sub(created) as f64 / 1_000_000.0, e);
+        }
+    });
+
+    // Two legs bridged together, fired once per bridge
+    bind_event(binder, "channel_bridge", fsr::event_types::CHANNEL_BRIDGE, None, |_| {
+        lock(&METRICS.bridges).increment();
+        lock(&METRICS.bridges_active).increment();
+    });
+
+    bind_event(binder, "channel_unbridge", fsr::event_types::CHANNEL_UNBRIDGE, None, |_| {
+        gauge_decrement(&METRICS.bridges_active);
+    });
+
+    // FIXME: Registrations are bound to be outdated on restart (registrations are in the db)
+    // so we should fetch them on module load to get the counters initialized
+
+    // Registration attempts
+    bind_event(binder, "register_attempt", fsr::event_types::CUSTOM, Some("sofia::register_attempt"), |_| {
+        lock(&METRICS.registration_attempts).increment();
+    });
+
+    // Registration failures
+    bind_event(binder, "register_failure", fsr::event_types::CUSTOM, Some("sofia::register_failure"), |_| {
+        lock(&METRICS.registration_failures).increment();
+    });
+
+    // Registration counters
+    bind_event(binder, "register", fsr::event_types::CUSTOM, Some("sofia::register"), |e| {
+        lock(&METRICS.registrations).increment();
+        lock(&METRICS.registrations_active).increment();
+        // Sofia flags NATed contacts in the status (e.g. "Registered(UDP-NAT)") and the
+        // contact it stores (fs_nat=yes)
+        let contact = e.header("contact");
+        if e.header("status").is_some_and(|s| s.contains("NAT")) ||
+           contact.as_ref().is_some_and(|c| c.contains("fs_nat=yes")) {
+            lock(&METRICS.registrations_nat).increment();
+        }
+        // The registration came from another address than the one in the Contact, so
+        // replies go to the received/rport address
+        if let (Some(contact), Some(ip)) = (contact, e.header("network-ip")) {
+            if let Some((host, port)) = contact_address(&contact) {
+                let port_rewritten = match (port, e.header("network-port")) {
+                    (Some(p), Some(n)) => p != n,
+                    _ => false,
+                };
+                if host != ip || port_rewritten {
+                    lock(&METRICS.registrations_rewritten).increment();
+                }
+            }
+        }
+    });
+
+    // Registrations per SIP transport, sofia puts it in the status (e.g. "Registered(TLS-NAT)"),
+    // the Contact transport parameter being the fallback
+    bind_event(binder, "register_transport", fsr::event_types::CUSTOM, Some("sofia::register"), |e| {
+        let from_status = e.header("status").and_then(|s| {
+            s.find('(').map(|open| s[open + 1..].split(['-', ')']).next().unwrap_or("").to_string())
+        });
+        let transport = match from_status.filter(|t| !t.is_empty()) {
+            Some(t) => Some(t),
+            None => e.header("contact").map(|c| uri_transport(&c)),
+        };
+        if let Some(transport) = transport {
+            lock(&dynamic_labelled_counter_get("freeswitch_registrations_transport_total", &[("transport", &sip_transport(&transport))],
+                                               "FreeSWITCH Registrations per SIP Transport")).increment();
+        }
+    });
+
+    bind_event(binder, "unregister", fsr::event_types::CUSTOM, Some("sofia::unregister"), |_| {
+        gauge_decrement(&METRICS.registrations_active);
+    });
+
+    bind_event(binder, "expire", fsr::event_types::CUSTOM, Some("sofia::expire"), |_| {
+        gauge_decrement(&METRICS.registrations_active);
+    });
+
+    // Outbound gateway (trunk) registration state, gateways not configured
+    // with register=true report the NOREG state and are ignored
+    bind_event(binder, "gateway_state", fsr::event_types::CUSTOM, Some("sofia::gateway_state"), |e| {
+        if let (Some(gateway), Some(state)) = (e.header("Gateway"), e.header("State")) {
+            if state == "NOREG" {
+                return;
+            }
+            let labels = [("gateway", &gateway[..])];
+            let registered = if state == "REGED" { 1.0 } else { 0.0 };
+            lock(&dynamic_labelled_gauge_get("freeswitch_gateway_registered", &labels,
+                                             "FreeSWITCH Gateway Registration State")).set(registered);
+            if state == "FAIL_WAIT" {
+                lock(&dynamic_labelled_counter_get("freeswitch_gateway_registration_retries_total", &labels,
+                                                   "FreeSWITCH Gateway Registration Retries")).increment();
+            }
+        } else {
+            let b = e.body().unwrap_or(Cow::Borrowed("<No Body>"));
+            fslog!(WARNING, "Received gateway state event with no gateway or state: {:?}\n", b);
+            lock(&METRICS.events_dropped).increment();
+        }
+    });
+
+    // Gateways pinged with OPTIONS (ping=<seconds>), registered or not: sofia reports the
+    // Ping-Status going UP or DOWN with gateway state events, along with the round-trip time
+    // of the last ping (Ping-Time, milliseconds) where the FreeSWITCH version includes it
+    bind_event(binder, "gateway_ping", fsr::event_types::CUSTOM, Some("sofia::gateway_state"), |e| {
+        let (gateway, status) = match (e.header("Gateway"), e.header("Ping-Status")) {
+            (Some(g), Some(s)) => (g, s),
+            _ => return,
+        };
+        if status != "UP" && status != "DOWN" {
+            return;
+        }
+        let labels = [("gateway", &gateway[..])];
+        lock(&dynamic_labelled_gauge_get("freeswitch_gateway_ping_up", &labels, "FreeSWITCH Gateway OPTIONS Ping Status")).set(if status == "UP" { 1.0 } else { 0.0 });
+        if status == "DOWN" {
+            lock(&dynamic_labelled_counter_get("freeswitch_gateway_ping_failures_total", &labels,
+                                               "FreeSWITCH Gateway OPTIONS Ping Failures")).increment();
+        }
+        if let Some(ms) = e.header("Ping-Time").and_then(|t| t.parse::<f64>().ok()) {
+            lock(&dynamic_labelled_gauge_get("freeswitch_gateway_ping_rtt_seconds", &labels,
+                                             "FreeSWITCH Gateway OPTIONS Ping Round-Trip Time")).set(ms / 1000.0);
+        }
+    });
+
+    // Answered vs billable (billsec over billable-min-seconds) outbound calls per gateway,
+    // a gateway answering many calls that are not billable suggests false answer supervision
+    bind_event(binder, "gateway_billing", fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, |e| {
+        let gateway = match e.header("variable_sip_gateway_name") {
+            Some(g) => g,
+            None => return,
+        };
+        let answered = match e.header("Caller-Channel-Answered-Time") {
+            Some(t) => t != "0",
+            None => false,
+        };
+        if !answered {
+            return;
+        }
+        let labels = [("gateway", &gateway[..])];
+        lock(&dynamic_labelled_counter_get("freeswitch_gateway_answered_calls_total", &labels,
+                                           "FreeSWITCH Gateway Answered Calls")).increment();
+        let billsec = e.header("variable_billsec").and_then(|b| b.parse::<u64>().ok()).unwrap_or(0);
+        if billsec > current_config().billable_min_seconds {
+            lock(&dynamic_labelled_counter_get("freeswitch_gateway_billable_calls_total", &labels,
+                                               "FreeSWITCH Gateway Calls Billed Above The Minimum Duration")).increment();
+        }
+    });
+
+    // Answered calls much shorter or longer than usual, per direction and gateway, for
+    // fraud and carrier quality monitoring
+    bind_event(binder, "call_length", fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, |e| {
+        if e.header("Caller-Channel-Answered-Time").is_none_or(|t| t == "0") {
+            return;
+        }
+        let billsec = match billed_seconds(e) {
+            Some(b) => b,
+            None => return,
+        };
+        let config = current_config();
+        let length = if billsec < config.short_call_seconds as f64 {
+            "short"
+        } else if billsec > config.long_call_seconds as f64 {
+            "long"
+        } else {
+            return;
+        };
+        let direction = match e.header("Call-Direction") {
+            Some(ref d) if d == "inbound" => "inbound",
+            Some(ref d) if d == "outbound" => "outbound",
+            _ => "other",
+        };
+        lock(&dynamic_labelled_counter_get("freeswitch_sessions_unusual_length_calls_total", &[("direction", direction), ("length", length)],
+                                           "FreeSWITCH Answered Sessions Billed Below short-call-seconds or Above long-call-seconds")).increment();
+        if let Some(gateway) = e.header("variable_sip_gateway_name") {
+            lock(&dynamic_labelled_counter_get("freeswitch_gateway_unusual_length_calls_total", &[("gateway", &gateway), ("length", length)],
+                                               "FreeSWITCH Gateway Calls Billed Below short-call-seconds or Above long-call-seconds")).increment();
+        }
+    });
+
+    // Final SIP response of outbound calls by code and gateway (empty when the call didn't
+    // go through one), so carrier 403/503 spikes can be alerted on
+    bind_event(binder, "sip_responses", fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, |e| {
+        if e.header("Call-Direction").is_none_or(|d| d != "outbound") {
+            return;
+        }
+        let code = match e.header("variable_sip_term_status").or_else(|| e.header("variable_sip_invite_failure_status")) {
+            Some(c) => c,
+            None => return,
+        };
+        let gateway = e.header("variable_sip_gateway_name").unwrap_or(Cow::Borrowed(""));
+        lock(&dynamic_labelled_counter_get("freeswitch_outbound_sip_responses_total", &[("code", &code), ("gateway", &gateway)],
+                                           "FreeSWITCH Outbound Calls Final SIP Responses")).increment();
+    });
+
+    // Inbound calls the caller hung up on while they were ringing or in early media, before
+    // anyone answered: the abandoned calls of a contact center
+    bind_event(binder, "abandoned_calls", fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, |e| {
+        let set = |h: &str| e.header(h).is_some_and(|t| t != "0");
+        if e.header("Call-Direction").is_none_or(|d| d != "inbound") ||
+           e.header("Hangup-Cause").is_none_or(|c| c != "ORIGINATOR_CANCEL") ||
+           set("Caller-Channel-Answered-Time") ||
+           !(set("Caller-Channel-Progress-Time") || set("Caller-Channel-Progress-Media-Time")) {
+            return;
+        }
+        lock(&METRICS.sessions_inbound_abandoned).increment();
+        let abandoned = lock(&METRICS.sessions_inbound_abandoned).value();
+        let created = lock(&METRICS.sessions_inbound_created).value();
+        if created > 0 as f64 {
+            lock(&METRICS.sessions_inbound_abandonment_rate).set(abandoned / created);
+        }
+    });
+
+    // Billed duration of answered calls, as summaries
+    bind_event(binder, "billsec_summaries", fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, |e| {
+        if !current_config().duration_summaries || e.header("Caller-Channel-Answered-Time").is_none_or(|t| t == "0") {
+            return;
+        }
+        let billsec = match billed_seconds(e) {
+            Some(b) => b,
+            None => return,
+        };
+        match e.header("Call-Direction") {
+            Some(ref d) if d == "inbound" => lock(&METRICS.sessions_inbound_billsec).observe(billsec),
+            Some(ref d) if d == "outbound" => lock(&METRICS.sessions_outbound_billsec).observe(billsec),
+            _ => {}
+        }
+    });
+
+    // Sessions per tenant for multi-tenant billing dashboards, counted once they are over
+    // as the variables naming the tenant may only be set by the dialplan
+    bind_event(binder, "tenant_sessions", fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, |e| {
+        let tenant = match session_tenant(e) {
+            Some(t) => t,
+            None => return,
+        };
+        let counter = |what: &str, help: &str| {
+            dynamic_labelled_counter_get(&format!("freeswitch_tenant_sessions_{}", what), &[("tenant", &tenant)], help)
+        };
+        lock(&counter("created_total", "FreeSWITCH Sessions Created per Tenant")).increment();
+        if e.header("Caller-Channel-Answered-Time").is_some_and(|t| t != "0") {
+            lock(&counter("answered_total", "FreeSWITCH Sessions Answered per Tenant")).increment();
+            let billsec = billed_seconds(e).unwrap_or(0.0);
+            lock(&counter("duration_seconds_total", "FreeSWITCH Sessions Billed Duration per Tenant"))
+                .increment_by(billsec);
+        } else {
+            lock(&counter("failed_total", "FreeSWITCH Sessions Failed per Tenant")).increment();
+        }
+    });
+
+    // SIP sessions per transport, counted once they are over as sofia sets the variables
+    // along the way: the Via protocol of inbound calls, the transport parameter of the
+    // contacted URI otherwise. Non-SIP sessions are not counted
+    bind_event(binder, "session_transport", fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, |e| {
+        let transport = match e.header("variable_sip_via_protocol") {
+            Some(p) => p.to_string(),
+            None => match e.header("variable_sip_contact_uri").or_else(|| e.header("variable_sip_req_uri")) {
+                Some(uri) => uri_transport(&uri),
+                None => return,
+            },
+        };
+        lock(&dynamic_labelled_counter_get("freeswitch_sessions_transport_total", &[("transport", &sip_transport(&transport))],
+                                           "FreeSWITCH SIP Sessions per Transport")).increment();
+    });
+
+    // Billsec of answered sessions per accountcode, to cross-check CDR billing totals
+    bind_event(binder, "accountcode_billsec", fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, |e| {
+        let config = current_config();
+        if !config.billsec_histograms || e.header("Caller-Channel-Answered-Time").is_none_or(|t| t == "0") {
+            return;
+        }
+        let (accountcode, billsec) = match (e.header("variable_accountcode"), billed_seconds(e)) {
+            (Some(a), Some(b)) => (capped(&ACCOUNTCODES, a.to_string(), config.max_accountcodes), b),
+            _ => return,
+        };
+        let histogram = dynamic_labelled_histogram_get("freeswitch_accountcode_billsec_seconds", &[("accountcode", &accountcode)],
+                                                       "FreeSWITCH Billed Session Duration per Accountcode", &BILLSEC_BUCKETS);
+        observe_call(&histogram, billsec, e);
+    });
+
+    // Sessions refused over the core limits, from the hangup cause of the channel or, for the
+    // A-leg of a bridge whose B-leg could not even be created, from its originate disposition
+    bind_event(binder, "limit_refusals", fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, |e| {
+        let config = current_config();
+        let refused = |name: &str| e.header(name).is_some_and(|c| config.limit_hangup_causes.iter().any(|l| *l == c));
+        if refused("Hangup-Cause") || refused("variable_originate_disposition") {
+            lock(&METRICS.core_sessions_refused).increment();
+        }
+    });
+
+    // Media problems per SIP profile, apart from the generic failures as one-way audio
+    // incidents need their own alerts: media timeouts, RTP flushes (packets piling up
+    // faster than they are read) and answered sessions that never got any RTP
+    bind_event(binder, "media_problems", fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, |e| {
+        let profile = match e.header("variable_sofia_profile_name") {
+            Some(p) => p,
+            None => return,
+        };
+        let labels = [("profile", &profile[..])];
+        if e.header("Hangup-Cause").is_some_and(|c| c == "MEDIA_TIMEOUT") {
+            lock(&dynamic_labelled_counter_get("freeswitch_sofia_media_timeouts_total", &labels,
+                                               "FreeSWITCH Sofia Profile Media Timeout Hangups")).increment();
+        }
+        let packets = |name: &str| e.header(name).and_then(|v| v.parse::<u64>().ok());
+        if packets("variable_rtp_audio_in_flush_packet_count").is_some_and(|p| p > 0) {
+            lock(&dynamic_labelled_counter_get("freeswitch_sofia_rtp_flushed_sessions_total", &labels,
+                                               "FreeSWITCH Sofia Profile Sessions with RTP Flushes")).increment();
+        }
+        let answered = e.header("Caller-Channel-Answered-Time").is_some_and(|t| t != "0");
+        if answered && packets("variable_rtp_audio_in_media_packet_count") == Some(0) {
+            lock(&dynamic_labelled_counter_get("freeswitch_sofia_rtp_no_media_sessions_total", &labels,
+                                               "FreeSWITCH Sofia Profile Answered Sessions without Inbound RTP")).increment();
+        }
+    });
+
+    // RTCP receiver reports sent by the far end during calls (RTCP enabled, e.g. with
+    // rtcp_audio_interval_msec), so quality degrades visibly while calls are up rather than
+    // only once they hang up. The fraction lost is in 1/256 and the jitter in RTP timestamp
+    // units, seconds at the RTP clock rate
+    bind_event(binder, "rtcp_report", fsr::event_types::RECV_RTCP_MESSAGE, None, |e| {
+        let uuid = match e.header("Unique-ID") {
+            Some(u) => u,
+            None => return,
+        };
+        let value = |name: &str| e.header(name).and_then(|v| v.parse::<f64>().ok());
+        let (fraction, jitter) = match (value("Source0-Fraction"), value("Source0-Jitter")) {
+            (Some(f), Some(j)) => (f / 256.0, j / value("RTP-Rate").filter(|r| *r > 0 as f64).unwrap_or(8000.0)),
+            _ => return,
+        };
+        lock(&METRICS.rtcp_loss).observe(fraction);
+        lock(&METRICS.rtcp_jitter).observe(jitter);
+        lock(&RTCP_REPORTS).insert(uuid.to_string(), (fraction, jitter));
+    });
+
+    // Inbound audio quality per far-end network, from the RTP statistics of the session
+    bind_event(binder, "remote_network_quality", fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, |e| {
+        let config = current_config();
+        if !config.remote_network_histograms {
+            return;
+        }
+        let network = match e.header("variable_remote_media_ip").and_then(|ip| ip.parse::<IpAddr>().ok()) {
+            Some(ip) => remote_network(ip, config.remote_network_mask, config.remote_network_mask_v6),
+            None => return,
+        };
+        let value = |name: &str| e.header(name).and_then(|v| v.parse::<f64>().ok());
+        let (mos, loss) = match (value("variable_rtp_audio_in_mos"), value("variable_rtp_audio_in_jitter_loss_rate")) {
+            (Some(m), Some(l)) if m > 0 as f64 => (m, l / 100.0),
+            _ => return,
+        };
+        let network = capped(&REMOTE_NETWORKS, network, config.max_remote_networks);
+        let labels = [("network", &network[..])];
+        lock(&dynamic_labelled_histogram_get("freeswitch_remote_network_mos", &labels,
+                                             "FreeSWITCH Inbound Audio MOS per Far-End Network", &MOS_BUCKETS)).observe(mos);
+        lock(&dynamic_labelled_histogram_get("freeswitch_remote_network_loss_ratio", &labels,
+                                             "FreeSWITCH Inbound Audio Packet Loss per Far-End Network", &LOSS_BUCKETS)).observe(loss);
+    });
+
+    // Time callers waited in each mod_callcenter queue before an agent answered, and before
+    // giving up for the abandoned ones. The CC-*-Time headers are in seconds since the epoch
+    bind_event(binder, "callcenter_wait", fsr::event_types::CUSTOM, Some("callcenter::info"), |e| {
+        let action = match e.header("CC-Action") {
+            Some(a) => a,
+            None => {
+                let b = e.body().unwrap_or(Cow::Borrowed("<No Body>"));
+                fslog!(WARNING, "Received callcenter event with no action: {:?}\n", b);
+                lock(&METRICS.events_dropped).increment();
+                return;
+            }
+        };
+        let time = |name: &str| e.header(name).and_then(|t| t.parse::<u64>().ok()).filter(|t| *t > 0);
+        let (metric, help, end) = match &action[..] {
+            "bridge-agent-start" => ("wait", "FreeSWITCH Time Waited in Queue before an Agent Answered",
+                                     time("CC-Agent-Answered-Time")),
+            "member-queue-end" if e.header("CC-Cause").is_some_and(|c| c == "Cancel") =>
+                ("abandoned_wait", "FreeSWITCH Time Waited in Queue before Abandoning", time("CC-Member-Leaving-Time")),
+            _ => return,
+        };
+        if let (Some(queue), Some(joined), Some(end)) = (e.header("CC-Queue"), time("CC-Member-Joined-Time"), end) {
+            lock(&queue_wait(&queue, metric, help)).observe(end.saturating_sub(joined) as f64);
+        }
+    });
+
+    // Time call center agents spend available, on call, in wrap-up (the Idle state) or on
+    // break, credited to the queue they last took a call from on every change and every
+    // second by agent_time_update
+    bind_event(binder, "callcenter_agents", fsr::event_types::CUSTOM, Some("callcenter::info"), |e| {
+        let agent = match e.header("CC-Agent") {
+            Some(a) => a,
+            None => return,
+        };
+        let now = event_time(e).unwrap_or_else(now_micros);
+        let mut agents = lock(&AGENTS);
+        let time = agents.entry(agent.to_string()).or_insert_with(|| AgentTime::new(now));
+        time.credit(now);
+        if let Some(queue) = e.header("CC-Queue") {
+            time.queue = queue.to_string();
+        }
+        match e.header("CC-Action") {
+            Some(ref a) if a == "agent-status-change" => {
+                if let Some(status) = e.header("CC-Agent-Status") {
+                    time.status = status.to_string();
+                }
+            }
+            Some(ref a) if a == "agent-state-change" => {
+                if let Some(state) = e.header("CC-Agent-State") {
+                    time.state = state.to_string();
+                }
+            }
+            _ => {}
+        }
+        if time.status == "Logged Out" {
+            agents.remove(&agent[..]);
+        }
+    });
+
+    // Time callers waited before being bridged, for queues that are not mod_callcenter ones
+    // (e.g. a dialplan hold loop or IVR) and tag their channel with queue-variable. The wait
+    // starts at queue-start-variable (seconds since the epoch) when set, otherwise at answer
+    bind_event(binder, "queue_wait", fsr::event_types::CHANNEL_BRIDGE, None, |e| {
+        let config = current_config();
+        let queue = match config.queue_variable.as_ref().and_then(|v| e.header(&format!("variable_{}", v))) {
+            Some(q) => q,
+            None => return,
+        };
+        let start = config.queue_start_variable.as_ref()
+            .and_then(|v| e.header(&format!("variable_{}", v)))
+            .and_then(|t| t.parse::<f64>().ok())
+            .map(|t| (t * 1_000_000.0) as u64)
+            .or_else(|| e.header("Caller-Channel-Answered-Time").and_then(|t| t.parse::<u64>().ok()))
+            .filter(|t| *t > 0);
+        if let (Some(start), Some(now)) = (start, event_time(e)) {
+            lock(&queue_wait(&queue, "wait", "FreeSWITCH Time Waited in Queue before an Agent Answered")).observe(now.saturating_sub(start) as f64 / 1_000_000.0);
+        }
+    });
+
+    // Answering machine detection results of outbound dialer calls per campaign, counted
+    // with the prom_campaign_event ones. "unknown" campaign without campaign-variable
+    bind_event(binder, "amd_result", fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, |e| {
+        let config = current_config();
+        let result = match config.amd_variables.iter().filter_map(|v| e.header(&format!("variable_{}", v))).next() {
+            Some(r) => r.to_lowercase(),
+            None => return,
+        };
+        let event = match &result[..] {
+            "human" | "person" => "amd_human",
+            // avmd_detect is true when a beep was heard
+            "machine" | "true" => "amd_machine",
+            "notsure" | "unsure" | "unknown" => "amd_unsure",
+            _ => return,
+        };
+        let campaign = config.campaign_variable.as_ref()
+            .and_then(|v| e.header(&format!("variable_{}", v)))
+            .map_or("unknown".to_string(), |c| c.to_string());
+        api::campaign_count(&campaign, event);
+    });
+
+    // Jitter buffer statistics per direction, only for sessions whose inbound audio went
+    // through a jitter buffer (jitterbuffer_msec set)
+    bind_event(binder, "jitter_buffer", fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, |e| {
+        let (size, drops, resets) = match e.header("Call-Direction") {
+            Some(ref d) if d == "inbound" => (&METRICS.sessions_inbound_jb_size, &METRICS.sessions_inbound_jb_drops,
+                                              &METRICS.sessions_inbound_jb_resets),
+            Some(ref d) if d == "outbound" => (&METRICS.sessions_outbound_jb_size, &METRICS.sessions_outbound_jb_drops,
+                                               &METRICS.sessions_outbound_jb_resets),
+            _ => return,
+        };
+        let packets = |name: &str| e.header(name).and_then(|v| v.parse::<u64>().ok());
+        if packets("variable_rtp_audio_in_jitter_packet_count").is_none_or(|p| p == 0) {
+            return;
+        }
+        for &(histogram, variable) in [(size, "variable_rtp_audio_in_largest_jb_size"),
+                                       (drops, "variable_rtp_audio_in_skip_packet_count"),
+                                       (resets, "variable_rtp_audio_in_flush_packet_count")].iter() {
+            if let Some(p) = packets(variable) {
+                lock(histogram).observe(p as f64);
+            }
+        }
+    });
+
+    // Prompt playbacks per sound language, a missing language pack shows up as
+    // playbacks moving over to the default language series
+    bind_event(binder, "playback_start", fsr::event_types::PLAYBACK_START, None, |e| {
+        let lang = match e.header("Playback-File-Path") {
+            Some(path) => sound_language(&path),
+            None => "unknown".to_string(),
+        };
+        let lang = capped(&SOUND_LANGUAGES, lang, current_config().max_sound_languages);
+        lock(&dynamic_labelled_counter_get("freeswitch_playbacks_total", &[("language", &lang)],
+                                           "FreeSWITCH Prompt Playbacks per Sound Language")).increment();
+        lock(&METRICS.playbacks_active).increment();
+    });
+
+    // Completed playbacks by how they ended (done, break ...)
+    bind_event(binder, "playback_stop", fsr::event_types::PLAYBACK_STOP, None, |e| {
+        gauge_decrement(&METRICS.playbacks_active);
+        let status = e.header("Playback-Status").unwrap_or(Cow::Borrowed("unknown"));
+        lock(&dynamic_labelled_counter_get("freeswitch_playbacks_completed_total", &[("status", &status)],
+                                           "FreeSWITCH Completed Playbacks per Status")).increment();
+    });
+
+    bind_event(binder, "record_start", fsr::event_types::RECORD_START, None, |_| {
+        lock(&METRICS.recordings_active).increment();
+    });
+
+    // Completed recordings by completion cause (success-silence, success-maxtime ...)
+    bind_event(binder, "record_stop", fsr::event_types::RECORD_STOP, None, |e| {
+        gauge_decrement(&METRICS.recordings_active);
+        let cause = e.header("Record-Completion-Cause").unwrap_or(Cow::Borrowed("unknown"));
+        lock(&dynamic_labelled_counter_get("freeswitch_recordings_completed_total", &[("cause", &cause)],
+                                           "FreeSWITCH Completed Recordings per Completion Cause")).increment();
+    });
+
+    // Calls parked per valet parking lot and how long they stayed parked, a call parked for
+    // long was likely forgotten
+    bind_event(binder, "valet_parking", fsr::event_types::CUSTOM, Some("mod_valet_parking::info"), |e| {
+        let (uuid, action) = match (e.header("Unique-ID"), e.header("Action")) {
+            (Some(u), Some(a)) => (u, a),
+            _ => {
+                let b = e.body().unwrap_or(Cow::Borrowed("<No Body>"));
+                fslog!(WARNING, "Received valet parking event with no Unique-ID or action: {:?}\n", b);
+                lock(&METRICS.events_dropped).increment();
+                return;
+            }
+        };
+        let now = event_time(e).unwrap_or_else(now_micros);
+        let mut parked = lock(&PARKED);
+        if action == "hold" {
+            let lot = e.header("Valet-Lot-Name").map_or("unknown".to_string(), |l| metric_name_part(&l));
+            let lot = capped(&VALET_LOTS, lot, current_config().max_valet_lots);
+            if parked.insert(uuid.to_string(), (now, lot.clone())).is_none() {
+                lock(&valet_parked(&lot)).increment();
+            }
+        } else if action == "bridge" || action == "exit" {
+            // Retrieved from the lot or hung up while parked
+            if let Some((since, lot)) = parked.remove(&uuid[..]) {
+                gauge_decrement(&valet_parked(&lot));
+                lock(&METRICS.valet_park).observe(now.saturating_sub(since) as f64 / 1_000_000.0);
+            }
+        }
+    });
+
+    // Metric updates from ESL clients that can only fire events (sendevent CUSTOM with
+    // Event-Subclass: prometheus::update), applied like a prom_batch operation on the series
+    // with the labels given
+    bind_event(binder, "metric_update", fsr::event_types::CUSTOM, Some(UPDATE_SUBCLASS), |e| {
+        let (name, op) = match (e.header("Metric-Name"), e.header("Metric-Op")) {
+            (Some(n), Some(o)) => (n, o),
+            _ => {
+                let b = e.body().unwrap_or(Cow::Borrowed("<No Body>"));
+                fslog!(WARNING, "Received metric update event with no Metric-Name or Metric-Op: {:?}\n", b);
+                lock(&METRICS.events_dropped).increment();
+                return;
+            }
+        };
+        let labels = e.header("Metric-Labels").unwrap_or(Cow::Borrowed(""));
+        let value = match e.header("Metric-Value").map(|v| v.trim().parse::<f64>()) {
+            Some(Ok(v)) => Ok(v),
+            None => Ok(1.0),
+            Some(Err(_)) => Err(format!("Invalid metric value for {}", name)),
+        };
+        if let Err(err) = value.and_then(|v| api::update(&op, &name, &parse_labels(&labels), v)) {
+            fslog!(WARNING, "Ignoring metric update event: {}\n", err);
+            lock(&METRICS.events_dropped).increment();
+        }
+    });
+
+    // API commands executed (from the CLI, ESL api and bgapi, the dialplan ...), per command
+    bind_event(binder, "api_command", fsr::event_types::API, None, |e| {
+        let command = match e.header("API-Command") {
+            Some(c) => c,
+            None => {
+                let b = e.body().unwrap_or(Cow::Borrowed("<No Body>"));
+                fslog!(WARNING, "Received API event with no command: {:?}\n", b);
+                lock(&METRICS.events_dropped).increment();
+                return;
+            }
+        };
+        let mut name = command.to_string();
+        if SUBCOMMAND_APIS.contains(&&command[..]) {
+            if let Some(sub) = e.header("API-Command-Argument").as_ref().and_then(|a| a.split_whitespace().next()) {
+                name = format!("{} {}", name, sub);
+            }
+        }
+        let name = capped(&API_COMMANDS, name, current_config().max_api_commands);
+        lock(&dynamic_labelled_counter_get("freeswitch_api_commands_total", &[("command", &name)],
+                                           "FreeSWITCH API Command Executions")).increment();
+    });
+
+    // Background API jobs queued. The API event of the bgapi command doesn't carry the Job-UUID
+    // it hands out, the job is known by its command line until done
+    bind_event(binder, "background_job_queued", fsr::event_types::API, None, |e| {
+        if e.header("API-Command").is_none_or(|c| c != "bgapi") {
+            return;
+        }
+        let job = match e.header("API-Command-Argument") {
+            Some(a) => job_line(&a),
+            None => return,
+        };
+        let queued = event_time(e).unwrap_or_else(now_micros);
+        let mut jobs = lock(&BGAPI_QUEUED);
+        if jobs.len() >= MAX_BGAPI_QUEUED {
+            jobs.pop_front();
+        }
+        jobs.push_back((job, queued));
+    });
+
+    // Background API jobs (bgapi) per command, fired once the job is done
+    bind_event(binder, "background_job", fsr::event_types::BACKGROUND_JOB, None, |e| {
+        let command_line = match e.header("Job-Command") {
+            Some(c) => c,
+            None => {
+                let b = e.body().unwrap_or(Cow::Borrowed("<No Body>"));
+                fslog!(WARNING, "Received background job event with no command: {:?}\n", b);
+                lock(&METRICS.events_dropped).increment();
+                return;
+            }
+        };
+        let command = capped(&BGAPI_COMMANDS, metric_name_part(&command_line), current_config().max_api_commands);
+        lock(&dynamic_labelled_counter_get("freeswitch_bgapi_jobs_total", &[("command", &command)],
+                                           "FreeSWITCH Background API Jobs per Command")).increment();
+        let job = job_line(&format!("{} {}", command_line, e.header("Job-Command-Arg").unwrap_or(Cow::Borrowed(""))));
+        let queued = {
+            let mut jobs = lock(&BGAPI_QUEUED);
+            jobs.iter().position(|(j, _)| *j == job).and_then(|i| jobs.remove(i)).map(|(_, t)| t)
+        };
+        if let Some(queued) = queued {
+            let done = event_time(e).unwrap_or_else(now_micros);
+            lock(&METRICS.bgapi_job).observe(done.saturating_sub(queued) as f64 / 1_000_000.0);
+        }
+    });
+
+    // Core scheduler tasks (scheduled hangups, limit resets, heartbeats ...). A task is
+    // removed once it runs and is back if it's rescheduled, a flat executed counter while
+    // tasks pile up means the scheduler stopped running them
+    bind_event(binder, "add_schedule", fsr::event_types::ADD_SCHEDULE, None, |e| {
+        lock(&METRICS.scheduler_tasks_added).increment();
+        scheduler_task_update(e, true);
+    });
+
+    bind_event(binder, "exe_schedule", fsr::event_types::EXE_SCHEDULE, None, |e| {
+        lock(&METRICS.scheduler_tasks_executed).increment();
+        scheduler_task_update(e, false);
+    });
+
+    bind_event(binder, "re_schedule", fsr::event_types::RE_SCHEDULE, None, |e| {
+        lock(&METRICS.scheduler_tasks_rescheduled).increment();
+        scheduler_task_update(e, true);
+    });
+
+    bind_event(binder, "del_schedule", fsr::event_types::DEL_SCHEDULE, None, |e| {
+        lock(&METRICS.scheduler_tasks_deleted).increment();
+        scheduler_task_update(e, false);
+    });
+
+    // Event socket clients going away, ESL consumers silently dropping is a frequent
+    // cause of outages
+    bind_event(binder, "client_disconnected", fsr::event_types::CLIENT_DISCONNECTED, None, |_| {
+        lock(&METRICS.esl_disconnects).increment();
+    });
+
+    // Modules loaded and unloaded
+    bind_event(binder, "module_load", fsr::event_types::MODULE_LOAD, None, |e| {
+        module_update(e, true);
+    });
+
+    bind_event(binder, "module_unload", fsr::event_types::MODULE_UNLOAD, None, |e| {
+        module_update(e, false);
+    });
+
+    // Pick up configuration changes on reloadxml, once back on the runtime thread
+    bind_event(binder, "reloadxml", fsr::event_types::RELOADXML, None, |_| {
+        lock(&METRICS.reloadxml).increment();
+        config_reload_later();
+    });
+
+    let bindings = lock(&BINDINGS);
+    for name in current_config().disabled_bindings.iter().filter(|b| !bindings.contains(&b[..])) {
+        fslog!(WARNING, "Unknown event binding in disabled-bindings: {:?}\n", name);
+    }
+}
+
+fn scheduler_task_update(e: &dyn EventData, pending: bool) {
+    let id = match e.header("Task-ID") {
+        Some(id) => id.to_string(),
+        None => return,
+    };
+    let mut tasks = lock(&SCHEDULER_TASKS);
+    if pending {
+        tasks.insert(id);
+    } else {
+        tasks.remove(&id);
+    }
+    lock(&METRICS.scheduler_tasks).set(tasks.len() as f64);
+}
+
+// One event is fired per interface a module registers, so only the first one of a
+// load or unload is counted
+fn module_update(e: &dyn EventData, loaded: bool) {
+    let module = match e.header("key") {
+        Some(m) => m.to_string(),
+        None => return,
+    };
+    let mut modules = lock(&MODULES);
+    if modules.get(&module) == Some(&loaded) {
+        return;
+    }
+    let (name, help) = if loaded {
+        ("freeswitch_modules_loads_total", "FreeSWITCH Module Loads")
+    } else {
+        ("freeswitch_modules_unloads_total", "FreeSWITCH Module Unloads")
+    };
+    lock(&dynamic_labelled_counter_get(name, &[("module", &module)], help)).increment();
+    modules.insert(module, loaded);
+    let count = modules.values().filter(|l| **l).count();
+    lock(&METRICS.modules_loaded).set(count as f64);
+}
+
+// Observes a call duration, with the call as the exemplar of the bucket when exemplars are
+// enabled so a latency spike can be followed to the CDR of an actual call
+fn observe_call(histogram: &Arc<Mutex<Histogram>>, value: f64, e: &dyn EventData) {
+    if !current_config().exemplars {
+        lock(histogram).observe(value);
+        return;
+    }
+    let (uuid, call_id) = (e.header("Unique-ID"), e.header("variable_sip_call_id"));
+    let labels: Vec<(&str, &str)> = [("call_uuid", &uuid), ("sip_call_id", &call_id)].iter()
+        .filter_map(|&(name, value)| value.as_ref().map(|v| (name, &v[..]))).collect();
+    lock(histogram).observe_with_exemplar(value, &labels);
+}
+
+fn queue_wait(queue: &str, metric: &str, help: &str) -> Arc<Mutex<Histogram>> {
+    let queue = capped(&QUEUES, queue.to_string(), current_config().max_queues);
+    dynamic_labelled_histogram_get(&format!("freeswitch_queue_{}_seconds", metric), &[("queue", &queue)], help, &WAIT_BUCKETS)
+}
+
+fn valet_parked(lot: &str) -> Arc<Mutex<Gauge>> {
+    dynamic_labelled_gauge_get("freeswitch_valet_parked", &[("lot", lot)], "FreeSWITCH Valet Parking Calls Parked per Lot")
+}
+
+// Labels given as "queue=sales,site=paris", sorted by label so the order they are given in
+// doesn't make another series
+fn parse_labels(labels: &str) -> Vec<(&str, &str)> {
+    let mut labels: Vec<(&str, &str)> = labels.split(',').filter_map(|l| {
+        let mut kv = l.splitn(2, '=');
+        match (kv.next(), kv.next()) {
+            (Some(k), Some(v)) if !v.trim().is_empty() => Some((k.trim(), v.trim())),
+            _ => None,
+        }
+    }).collect();
+    labels.sort();
+    labels
+}
+
+// Realm and resource of a limit, capped to max-limit-resources pairs, both "other" beyond
+fn limit_resource(realm: &str, resource: &str) -> (String, String) {
+    let pair = format!("{}/{}", realm, resource);
+    if capped(&LIMIT_RESOURCES, pair, current_config().max_limit_resources) == "other" {
+        return ("other".to_string(), "other".to_string());
+    }
+    (realm.to_string(), resource.to_string())
+}
+
+// Dialplan application name, capped to max-applications applications
+fn application(name: &str) -> String {
+    capped(&APPLICATIONS, name.to_string(), current_config().max_applications)
+}
+
+// A session is transcoding while its read and write codecs differ. Each session starting
+// to transcode is counted per codec pair, e.g. freeswitch_transcoding_sessions_total{from="PCMU",to="opus"}
+fn transcoding_update(e: &dyn EventData) {
+    let (uuid, read, write) = match (e.header("Unique-ID"), e.header("Channel-Read-Codec-Name"),
+                                     e.header("Channel-Write-Codec-Name")) {
+        (Some(u), Some(r), Some(w)) => (u, r, w),
+        _ => return,
+    };
+    let mut transcoding = lock(&TRANSCODING);
+    if read != write {
+        if transcoding.insert(uuid.to_string()) {
+            lock(&METRICS.transcoding_sessions_active).increment();
+            lock(&dynamic_labelled_counter_get("freeswitch_transcoding_sessions_total", &[("from", &read), ("to", &write)],
+                                               "FreeSWITCH Transcoded Sessions per Codec Pair")).increment();
+        }
+    } else if transcoding.remove(&uuid[..]) {
+        gauge_decrement(&METRICS.transcoding_sessions_active);
+    }
+}
+
+// Average sessions created per second over the last CPS_WINDOW seconds, per direction.
+// Called every second by the module runtime thread
+pub fn sessions_rate_update() {
+    let since = now_micros().saturating_sub(CPS_WINDOW * 1_000_000);
+    let mut creates = lock(&SESSION_CREATES);
+    let (ref mut inbound, ref mut outbound) = *creates;
+    for &mut (ref mut times, gauge) in [(inbound, &METRICS.sessions_inbound_per_second),
+                                        (outbound, &METRICS.sessions_outbound_per_second)].iter_mut() {
+        // Not necessarily in order, events can be delivered late
+        times.retain(|t| *t >= since);
+        lock(gauge).set(times.len() as f64 / CPS_WINDOW as f64);
+    }
+}
+
+// Credits the time of every call center agent up to now, so long stretches in the same
+// state show up as they go. Called every second by the module runtime thread
+pub fn agent_time_update() {
+    let now = now_micros();
+    for time in lock(&AGENTS).values_mut() {
+        time.credit(now);
+    }
+}
+
+// Route group or country of a number as a metric name part, the one with the longest
+// matching prefix or "other"
+fn prefix_group(routes: &[(String, Vec<String>)], destination: &str) -> String {
+    routes.iter()
+        .flat_map(|(name, prefixes)| prefixes.iter().map(move |p| (name, p)))
+        .filter(|&(_, p)| destination.starts_with(&p[..]))
+        .max_by_key(|&(_, p)| p.len())
+        .map_or("other".to_string(), |(name, _)| metric_name_part(name))
+}
+
+// Average and highest loss fraction and jitter over the last RTCP report of every session
+// up. Called every second by the module runtime thread
+pub fn rtcp_update() {
+    let reports = lock(&RTCP_REPORTS);
+    let count = reports.len() as f64;
+    let (mut loss_sum, mut loss_max, mut jitter_sum, mut jitter_max) = (0.0, 0.0, 0.0, 0.0);
+    for &(loss, jitter) in reports.values() {
+        loss_sum += loss;
+        jitter_sum += jitter;
+        loss_max = loss.max(loss_max);
+        jitter_max = jitter.max(jitter_max);
+    }
+    let average = |sum: f64| if count > 0.0 { sum / count } else { 0.0 };
+    lock(&METRICS.rtcp_sessions).set(count);
+    lock(&METRICS.rtcp_loss_avg).set(average(loss_sum));
+    lock(&METRICS.rtcp_loss_max).set(loss_max);
+    lock(&METRICS.rtcp_jitter_avg).set(average(jitter_sum));
+    lock(&METRICS.rtcp_jitter_max).set(jitter_max);
+}
+
+// Network of an address as <network>/<bits>, keeping the first bits of the address
+fn remote_network(ip: IpAddr, mask: u8, mask_v6: u8) -> String {
+    match ip {
+        IpAddr::V4(v4) => {
+            let bits = u32::from(v4) & (!0u32).checked_shl(32 - mask as u32).unwrap_or(0);
+            format!("{}/{}", Ipv4Addr::from(bits), mask)
+        }
+        IpAddr::V6(v6) => {
+            let bits = u128::from(v6) & (!0u128).checked_shl(128 - mask_v6 as u32).unwrap_or(0);
+            format!("{}/{}", Ipv6Addr::from(bits), mask_v6)
+        }
+    }
+}
+
+// Tenant of a session, None when per tenant metrics are disabled
+fn session_tenant(e: &dyn EventData) -> Option<String> {
+    let config = current_config();
+    if config.tenant_headers.is_empty() {
+        return None;
+    }
+    let tenant = match config.tenant_headers.iter().filter_map(|h| e.header(h)).next() {
+        Some(t) => t.to_string(),
+        None => "unknown".to_string(),
+    };
+    Some(capped(&TENANTS, tenant, config.max_tenants))
+}
+
+// Billed duration with millisecond precision, as billsec rounds sub-second IVR calls down
+// to zero: variable_billmsec, else the hangup minus the answer time, else billsec
+fn billed_seconds(e: &dyn EventData) -> Option<f64> {
+    if let Some(ms) = e.header("variable_billmsec").and_then(|b| b.parse::<u64>().ok()) {
+        return Some(ms as f64 / 1000.0);
+    }
+    let time = |h: &str| e.header(h).and_then(|t| t.parse::<u64>().ok()).filter(|t| *t > 0);
+    if let (Some(answered), Some(hangup)) = (time("Caller-Channel-Answered-Time"), time("Caller-Channel-Hangup-Time")) {
+        return Some(hangup.saturating_sub(answered) as f64 / 1_000_000.0);
+    }
+    e.header("variable_billsec").and_then(|b| b.parse::<u64>().ok()).map(|b| b as f64)
+}
+
+// Built-in gauges never go below zero: a decrement for something that started before the
+// module was loaded (e.g. a session created earlier) is counted as an underflow instead
+fn gauge_decrement(gauge: &Mutex<Gauge>) {
+    let mut gauge = lock(gauge);
+    if gauge.value() >= 1.0 {
+        gauge.decrement();
+    } else {
+        gauge.set(0 as f64);
+        lock(&METRICS.gauge_underflow).increment();
+    }
+}
+
+// The value itself while it's one of the first max values seen, "other" afterwards
+pub fn capped(seen: &Mutex<HashSet<String>>, value: String, max: usize) -> String {
+    let mut seen = lock(seen);
+    if seen.contains(&value) || seen.len() < max {
+        seen.insert(value.clone());
+        value
+    } else {
+        "other".to_string()
+    }
+}
+
+// Host and port of a Contact like "1000" <sip:1000@192.0.2.10:5060;transport=udp>
+fn contact_address(contact: &str) -> Option<(&str, Option<&str>)> {
+    let start = contact.find('@').map(|p| p + 1)?;
+    let rest = &contact[start..];
+    let end = rest.find([';', '>', '?']).unwrap_or(rest.len());
+    let address = &rest[..end];
+    if address.starts_with('[') {
+        // IPv6 reference
+        let close = address.find(']')?;
+        let port = address[close + 1..].trim_start_matches(':');
+        return Some((&address[1..close], if port.is_empty() { None } else { Some(port) }));
+    }
+    let mut parts = address.splitn(2, ':');
+    Some((parts.next().unwrap_or(""), parts.next()))
+}
+
+// Transport parameter of a SIP URI, SIP URIs without one default to UDP
+fn uri_transport(uri: &str) -> String {
+    uri.trim_end_matches('>').split(';')
+        .filter_map(|p| {
+            let mut kv = p.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) if k.trim().eq_ignore_ascii_case("transport") => Some(v.to_string()),
+                _ => None,
+            }
+        })
+        .next()
+        .unwrap_or(if uri.starts_with("sips:") || uri.contains("<sips:") { "tls" } else { "udp" }.to_string())
+}
+
+// SIP transport in lower case, anything unexpected counted as "other"
+fn sip_transport(transport: &str) -> String {
+    let transport = transport.trim().to_lowercase();
+    match &transport[..] {
+        "udp" | "tcp" | "tls" | "ws" | "wss" | "sctp" => transport.clone(),
+        _ => "other".to_string(),
+    }
+}
+
+// A B-leg is originated on behalf of another session and carries its Unique-ID in
+// Other-Leg-Unique-ID. Once bridged the A-leg carries it too, but as the originatee
+fn is_b_leg(e: &dyn EventData) -> bool {
+    e.header("Other-Leg-Unique-ID").is_some() &&
+        e.header("Other-Type").is_none_or(|t| t != "originatee")
+}
+
+// Binds an event handler, doing the bookkeeping common to all bindings around it
+fn bind_event<F>(binder: &mut dyn EventBinder, binding: &'static str, event: fsr::event_types,
+                 subclass: Option<&str>, handler: F)
+    where F: Fn(&dyn EventData) + Send + Sync + 'static
+{
+    lock(&BINDINGS).insert(binding);
+    if current_config().disabled_bindings.iter().any(|b| b == binding) {
+        fslog!(INFO, "Event binding {} disabled\n", binding);
+        return;
+    }
+    let processed = dynamic_labelled_counter_get("freeswitch_prometheus_events_total", &[("binding", binding)],
+                                                 "mod_prometheus events processed per binding");
+    binder.bind(event, subclass, Box::new(move |e| {
+        if !event_wanted(event, e) {
+            return;
+        }
+        let internal;
+        let e = if internal_channel_event(event, e) {
+            internal = InternalChannelEvent(e);
+            &internal as &dyn EventData
+        } else {
+            e
+        };
+        lock(&processed).increment();
+        if current_config().journal_size == 0 {
+            handler(e);
+        } else {
+            let changes = record_changes(|| handler(e));
+            journal_record(binding, e, changes);
+        }
+    }));
+}
+
+// Whether the event is to be processed at all: events of internal channels are dropped
+// unless seen as sessions of the other direction. The same hangup can be delivered twice
+// (e.g. around reloads), it must not be counted twice, deliveries are told apart by their
+// Event-Sequence
+fn event_wanted(event: fsr::event_types, e: &dyn EventData) -> bool {
+    if internal_channel_event(event, e) && !current_config().internal_channels_as_other {
+        return false;
+    }
+    if event == fsr::event_types::CHANNEL_HANGUP || event == fsr::event_types::CHANNEL_HANGUP_COMPLETE {
+        let sequence = e.header("Event-Sequence").and_then(|s| s.parse::<u64>().ok());
+        if let (Some(uuid), Some(sequence)) = (e.header("Unique-ID"), sequence) {
+            return !lock(&HANGUPS_SEEN).duplicate(&uuid, event, sequence);
+        }
+    }
+    true
+}
+
+fn internal_channel_event(event: fsr::event_types, e: &dyn EventData) -> bool {
+    event != fsr::event_types::CUSTOM && e.header("Channel-Name").is_some_and(|n| internal_channel(&n))
+}
+
+// Whether the channel is one of internal-channel, as loopback legs doubling every call
+fn internal_channel(name: &str) -> bool {
+    current_config().internal_channels.iter().any(|p| glob_match(p.as_bytes(), name.as_bytes()))
+}
+
+// Event of an internal channel, seen as a session of the other direction
+struct InternalChannelEvent<'a>(&'a dyn EventData);
+
+impl<'a> EventData for InternalChannelEvent<'a> {
+    fn header<'b>(&'b self, name: &str) -> Option<Cow<'b, str>> {
+        if name == "Call-Direction" {
+            Some(Cow::Borrowed("internal"))
+        } else {
+            self.0.header(name)
+        }
+    }
+
+    fn body<'b>(&'b self) -> Option<Cow<'b, str>> {
+        self.0.body()
+    }
+}
+
+// Event-Date-Timestamp is set when the event is fired (microseconds since the epoch),
+// so the difference with the current time is how long the event sat in the queues
+fn event_lag_update(e: &dyn EventData) {
+    if let Some(fired) = event_time(e) {
+        let lag = now_micros().saturating_sub(fired) as f64 / 1_000_000.0;
+        lock(&METRICS.event_lag).set(lag);
+        lock(&METRICS.event_lag_seconds_total).increment_by(lag);
+        lock(&METRICS.event_lag_samples).increment();
+    }
+}
+
+// When the event was fired, in microseconds since the epoch
+fn event_time(e: &dyn EventData) -> Option<u64> {
+    e.header("Event-Date-Timestamp").and_then(|ts| ts.parse::<u64>().ok())
+}
+
+// Command line of a background job, with the whitespace as bgapi splits it
+fn job_line(line: &str) -> String {
+    line.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn now_micros() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    now.as_secs() * 1_000_000 + now.subsec_micros() as u64
+}
+
+// Keep a line with the event and the metric changes its handler made, dropping the
+// oldest line once the journal is full
+fn journal_record(binding: &str, e: &dyn EventData, changes: Vec<(String, f64)>) {
+    if changes.is_empty() {
+        return;
+    }
+    let mut line = format!("{} {}", e.header("Event-Date-Local").unwrap_or(Cow::Borrowed("-")), binding);
+    for h in ["Unique-ID", "Call-Direction", "Hangup-Cause", "Gateway", "State"].iter() {
+        if let Some(v) = e.header(h) {
+            line.push_str(&format!(" {}={}", h, v));
+        }
+    }
+    line.push_str(" |");
+    for (name, delta) in changes {
+        line.push_str(&format!(" {} {:+}", name, delta));
+    }
+    let size = current_config().journal_size;
+    let mut journal = lock(&JOURNAL);
+    while journal.len() >= size {
+        journal.pop_front();
+    }
+    journal.push_back(line);
+}
+
+// Sound files live in <sounds_dir>/<lang>/<country>/<voice>/..., so the two path
+// components after the sounds directory identify the language (e.g. "en_us")
+fn sound_language(path: &str) -> String {
+    let parts: Vec<&str> = path.split(['/', '\\']).collect();
+    if let Some(pos) = parts.iter().position(|p| *p == "sounds") {
+        if parts.len() > pos + 2 {
+            return format!("{}_{}", metric_name_part(parts[pos + 1]), metric_name_part(parts[pos + 2]));
+        }
+    }
+    "unknown".to_string()
+}
@@ -0,0 +1,849 @@
+// Built-in metrics, the ones created on demand as new dimensions are seen and
+// the user metrics created through the APIs
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use freeswitchrs::raw as fsr;
+use freeswitchrs::raw::log_level::{DEBUG, INFO, NOTICE, WARNING, ERROR};
+
+use crate::registry::{Counter, Gauge, Histogram, Summary, Collector, Sample, parse_series, series_name, valid_metric_name};
+use crate::{current_config, current_registry, lock};
+
+// Seconds, from a quick answer up to a long ring
+static SETUP_BUCKETS: [f64; 10] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0];
+
+// Seconds, from a quick API up to an originate ringing for long
+static JOB_BUCKETS: [f64; 11] = [0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+// Seconds, from a short IVR call up to a couple of hours
+pub static BILLSEC_BUCKETS: [f64; 12] = [1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0, 1800.0, 3600.0, 7200.0];
+
+// Seconds, from a quick pickup up to an abandoned call
+static PARK_BUCKETS: [f64; 9] = [5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0];
+
+// Packets, from a clean session up to a badly broken one
+static PACKET_BUCKETS: [f64; 9] = [0.0, 1.0, 5.0, 10.0, 50.0, 100.0, 500.0, 1000.0, 5000.0];
+
+// Mean opinion score, from unusable (1) up to excellent (4.5)
+pub static MOS_BUCKETS: [f64; 8] = [1.0, 2.0, 2.5, 3.0, 3.5, 3.8, 4.0, 4.3];
+
+// Fraction of the packets lost, as reported in RTCP receiver reports
+pub static LOSS_BUCKETS: [f64; 8] = [0.0, 0.01, 0.02, 0.05, 0.1, 0.2, 0.5, 1.0];
+
+// Seconds, from an agent available right away up to a caller left waiting for long
+pub static WAIT_BUCKETS: [f64; 10] = [5.0, 10.0, 20.0, 30.0, 60.0, 120.0, 180.0, 300.0, 600.0, 1200.0];
+
+// Bridge attempts of a call, from the first route up to a long failover list
+static ATTEMPT_BUCKETS: [f64; 6] = [1.0, 2.0, 3.0, 4.0, 5.0, 10.0];
+
+// Seconds, from a clean network up to a barely usable one
+static JITTER_BUCKETS: [f64; 8] = [0.005, 0.01, 0.02, 0.03, 0.05, 0.1, 0.2, 0.5];
+
+// Declares the built-in metrics as named fields of the Metrics struct, adding a
+// metric is one line here. Gauges of the same family told apart by labels list them
+// after the name, e.g. "name" => [direction = "inbound"]
+macro_rules! builtin_metrics {
+    (counters { $($cfield:ident: $cname:expr, $chelp:expr;)* }
+     gauges { $($gfield:ident: $gname:expr $(=> [$($glabel:ident = $gvalue:expr),*])*, $ghelp:expr;)* }
+     histograms { $($hfield:ident: $hname:expr, $hhelp:expr, $hbuckets:expr;)* }
+     summaries { $($sfield:ident: $sname:expr, $shelp:expr;)* }) => {
+        pub struct Metrics {
+            $(pub $cfield: Arc<Mutex<Counter>>,)*
+            $(pub $gfield: Arc<Mutex<Gauge>>,)*
+            $(pub $hfield: Arc<Mutex<Histogram>>,)*
+            $(pub $sfield: Arc<Mutex<Summary>>,)*
+        }
+
+        impl Metrics {
+            fn new() -> Metrics {
+                Metrics {
+                    $($cfield: Arc::new(Mutex::new(Counter::new($cname.to_string(), $chelp.to_string()))),)*
+                    $($gfield: Arc::new(Mutex::new(Gauge::with_labels($gname.to_string(), $ghelp.to_string(),
+                                                                      &[$($((stringify!($glabel), $gvalue)),*)*]))),)*
+                    $($hfield: Arc::new(Mutex::new(Histogram::new($hname.to_string(), $hhelp.to_string(),
+                                                                  $hbuckets.to_vec()))),)*
+                    $($sfield: Arc::new(Mutex::new(Summary::new($sname.to_string(), $shelp.to_string(),
+                                                                Vec::new(), Duration::from_secs(0)))),)*
+                }
+            }
+
+            pub fn counters(&self) -> Vec<&Arc<Mutex<Counter>>> {
+                vec![$(&self.$cfield),*]
+            }
+
+            pub fn gauges(&self) -> Vec<&Arc<Mutex<Gauge>>> {
+                vec![$(&self.$gfield),*]
+            }
+
+            pub fn histograms(&self) -> Vec<&Arc<Mutex<Histogram>>> {
+                vec![$(&self.$hfield),*]
+            }
+
+            // Only registered when duration-summaries is enabled, see configure()
+            pub fn summaries(&self) -> Vec<&Arc<Mutex<Summary>>> {
+                vec![$(&self.$sfield),*]
+            }
+        }
+    }
+}
+
+builtin_metrics! {
+    counters {
+        heartbeats: "freeswitch_heartbeats_total", "FreeSWITCH heartbeat count";
+
+        // Sessions
+        sessions_created: "freeswitch_sessions_created_total", "FreeSWITCH Session Created Count";
+        sessions_destroyed: "freeswitch_sessions_destroyed_total", "FreeSWITCH Session Destroyed Count";
+        sessions_answered: "freeswitch_sessions_answered_total", "FreeSWITCH Answered Sessions Count";
+        sessions_failed: "freeswitch_sessions_failed_total", "FreeSWITCH Failed Sessions Count";
+        sessions_inbound_created: "freeswitch_sessions_inbound_total", "FreeSWITCH Inbound Sessions Count";
+        sessions_inbound_answered: "freeswitch_sessions_inbound_answered_total", "FreeSWITCH Answered Inbound Sessions Count";
+        sessions_inbound_failed: "freeswitch_sessions_inbound_failed_total", "FreeSWITCH Failed Inbound Sessions Count";
+        sessions_outbound_created: "freeswitch_sessions_outbound_total", "FreeSWITCH Outbound Sessions Count";
+        sessions_outbound_answered: "freeswitch_sessions_outbound_answered_total", "FreeSWITCH Answered Outbound Sessions Count";
+        sessions_outbound_failed: "freeswitch_sessions_outbound_failed_total", "FreeSWITCH Failed Outbound Sessions Count";
+        // Sessions with a Call-Direction other than inbound or outbound, or none at all
+        sessions_other_created: "freeswitch_sessions_other_total", "FreeSWITCH Sessions Count, other or no direction";
+        sessions_other_answered: "freeswitch_sessions_other_answered_total", "FreeSWITCH Answered Sessions Count, other or no direction";
+        sessions_other_failed: "freeswitch_sessions_other_failed_total", "FreeSWITCH Failed Sessions Count, other or no direction";
+        sessions_inbound_answered_early_media: "freeswitch_sessions_inbound_answered_early_media_total", "FreeSWITCH Inbound Sessions Answered After Early Media";
+        sessions_inbound_answered_direct: "freeswitch_sessions_inbound_answered_direct_total", "FreeSWITCH Inbound Sessions Answered Without Early Media";
+        sessions_outbound_answered_early_media: "freeswitch_sessions_outbound_answered_early_media_total", "FreeSWITCH Outbound Sessions Answered After Early Media";
+        sessions_outbound_answered_direct: "freeswitch_sessions_outbound_answered_direct_total", "FreeSWITCH Outbound Sessions Answered Without Early Media";
+        sessions_inbound_progress: "freeswitch_sessions_inbound_180_total", "FreeSWITCH Inbound Sessions 180 Ringing";
+        sessions_inbound_progress_media: "freeswitch_sessions_inbound_183_total", "FreeSWITCH Inbound Sessions 183 Session Progress";
+        sessions_outbound_progress: "freeswitch_sessions_outbound_180_total", "FreeSWITCH Outbound Sessions 180 Ringing";
+        sessions_outbound_progress_media: "freeswitch_sessions_outbound_183_total", "FreeSWITCH Outbound Sessions 183 Session Progress";
+        calls: "freeswitch_calls_total", "FreeSWITCH Calls (A-leg Sessions) Count";
+        media_srtp_sessions: "freeswitch_media_srtp_sessions_total", "FreeSWITCH Answered Sessions with Encrypted Media (SRTP)";
+        media_rtp_sessions: "freeswitch_media_rtp_sessions_total", "FreeSWITCH Answered Sessions with Unencrypted Media (RTP)";
+        calls_inbound: "freeswitch_calls_inbound_total", "FreeSWITCH inbound Calls (A-leg Sessions) Count";
+        calls_outbound: "freeswitch_calls_outbound_total", "FreeSWITCH outbound Calls (A-leg Sessions) Count";
+        sessions_b_leg: "freeswitch_sessions_b_leg_total", "FreeSWITCH B-leg Sessions Count";
+        bridges: "freeswitch_bridges_total", "FreeSWITCH Bridges Count";
+        registrations: "freeswitch_registrations_total", "FreeSWITCH Registration Count";
+        registration_attempts: "freeswitch_registration_attempts_total", "FreeSWITCH Registration Attempts";
+        registration_failures: "freeswitch_registration_failures_total", "FreeSWITCH Registration Failures";
+        registrations_nat: "freeswitch_registrations_nat_total", "FreeSWITCH Registrations from behind NAT";
+        registrations_rewritten: "freeswitch_registrations_contact_rewrites_total", "FreeSWITCH Registrations with Contact not matching the source address";
+
+        // Outbound calls
+        sessions_outbound_call_duration_total: "freeswitch_sessions_outbound_duration_total", "FreeSWITCH outbound Calls total duration";
+        sessions_outbound_call_hangup: "freeswitch_sessions_outbound_hangup", "FreeSWITCH outbound Calls hangup";
+        sessions_outbound_call_hangup_complete: "freeswitch_sessions_outbound_hangup_complete", "FreeSWITCH outbound Calls hangup complete";
+
+        // Inbound calls
+        sessions_inbound_call_duration_total: "freeswitch_sessions_inbound_duration_total", "FreeSWITCH inbound Calls total duration";
+        sessions_inbound_call_hangup: "freeswitch_sessions_inbound_hangup", "FreeSWITCH inbound Calls hangup";
+        sessions_inbound_call_hangup_complete: "freeswitch_sessions_inbound_hangup_complete", "FreeSWITCH inbound Calls hangup complete";
+        sessions_inbound_abandoned: "freeswitch_inbound_abandoned_total", "FreeSWITCH inbound Calls abandoned while ringing";
+
+        // Core scheduler tasks
+        scheduler_tasks_added: "freeswitch_scheduler_tasks_added_total", "FreeSWITCH Scheduler Tasks Added";
+        scheduler_tasks_executed: "freeswitch_scheduler_tasks_executed_total", "FreeSWITCH Scheduler Tasks Executed";
+        scheduler_tasks_rescheduled: "freeswitch_scheduler_tasks_rescheduled_total", "FreeSWITCH Scheduler Tasks Rescheduled";
+        scheduler_tasks_deleted: "freeswitch_scheduler_tasks_deleted_total", "FreeSWITCH Scheduler Tasks Deleted";
+
+        // Bridge attempts of the calls, and the calls that needed more than one (route advance)
+        calls_bridge_attempts: "freeswitch_calls_bridge_attempts_total", "FreeSWITCH Bridge Attempts of the Calls";
+        calls_route_advanced: "freeswitch_calls_route_advanced_total", "FreeSWITCH Calls with more than one Bridge Attempt";
+
+        reloadxml: "freeswitch_reloadxml_total", "FreeSWITCH XML Configuration Reloads";
+
+        // Sessions that failed because the switch was over its session or sessions per second
+        // limits, as told by limit-hangup-causes
+        core_sessions_refused: "freeswitch_core_sessions_refused_total", "FreeSWITCH Sessions refused over the core limits";
+
+        // Event socket
+        esl_disconnects: "freeswitch_esl_disconnects_total", "FreeSWITCH Event Socket Client Disconnects";
+
+        // sum of the event delivery lag of all events received
+        event_lag_seconds_total: "freeswitch_event_lag_seconds_total", "FreeSWITCH event delivery lag total";
+
+        // events the delivery lag was measured for
+        event_lag_samples: "freeswitch_event_lag_samples_total", "FreeSWITCH event delivery lag samples";
+
+        // events ignored because they lack the headers needed to process them
+        events_dropped: "freeswitch_prometheus_events_dropped_total", "mod_prometheus events dropped";
+
+        // hangup events already processed for the same Unique-ID, ignored
+        events_duplicate: "freeswitch_prometheus_events_duplicate_total", "mod_prometheus duplicate hangup events ignored";
+
+        // errors found while processing an event
+        callback_errors: "freeswitch_prometheus_callback_errors_total", "mod_prometheus event callback errors";
+
+        // decrements of built-in gauges already at zero, kept at zero instead of going negative
+        gauge_underflow: "freeswitch_prometheus_gauge_underflow_total", "mod_prometheus built-in gauge decrements below zero";
+
+        // prom_* API calls rejected for breaking the metric semantics, e.g. a counter decrement
+        api_misuse: "freeswitch_prometheus_api_misuse_total", "mod_prometheus API calls rejected as misuse";
+
+        // user metrics not created because max-user-metrics was reached
+        user_metrics_rejected: "freeswitch_prometheus_user_metrics_rejected_total", "mod_prometheus user metrics rejected";
+
+        // user metrics removed to make room for new ones
+        user_metrics_evicted: "freeswitch_prometheus_user_metrics_evicted_total", "mod_prometheus user metrics evicted";
+
+        // user metrics removed after not being used for user-metrics-ttl
+        user_metrics_expired: "freeswitch_prometheus_user_metrics_expired_total", "mod_prometheus user metrics expired";
+    }
+    gauges {
+        sessions_active_inbound: "freeswitch_sessions_active_inbound", "FreeSWITCH Active Sessions inbound";
+        sessions_active_outbound: "freeswitch_sessions_active_outbound", "FreeSWITCH Active Sessions outbound";
+        sessions_active_other: "freeswitch_sessions_active_other", "FreeSWITCH Active Sessions, other or no direction";
+        // Sessions created per second, averaged over the last few seconds
+        sessions_inbound_per_second: "freeswitch_sessions_per_second_inbound", "FreeSWITCH inbound Sessions Created per Second";
+        sessions_outbound_per_second: "freeswitch_sessions_per_second_outbound", "FreeSWITCH outbound Sessions Created per Second";
+        // High-water marks of the active sessions since the last peak reset
+        sessions_inbound_peak: "freeswitch_sessions_peak" => [direction = "inbound"], "FreeSWITCH Peak Active Sessions";
+        sessions_outbound_peak: "freeswitch_sessions_peak" => [direction = "outbound"], "FreeSWITCH Peak Active Sessions";
+        sessions_outbound_asr: "freeswitch_outbound_asr", "FreeSWITCH outbound Answer Seizure Ratio";
+        channels_active: "freeswitch_channels_active", "FreeSWITCH Active Channels (All Legs)";
+        calls_active: "freeswitch_calls_active", "FreeSWITCH Active Calls (A-leg Sessions)";
+        bridges_active: "freeswitch_bridges_active", "FreeSWITCH Bridged Call Pairs";
+        registrations_active: "freeswitch_registrations_active", "FreeSWITCH Active Registrations";
+        sessions_outbound_acd: "freeswitch_outbound_acd", "FreeSWITCH outbound Calls Average Duration";
+        sessions_inbound_acd: "freeswitch_inbound_acd", "FreeSWITCH inbound Calls Average Duration";
+        sessions_inbound_asr: "freeswitch_inbound_asr", "FreeSWITCH inbound Answer Seizure Ratio";
+        sessions_inbound_abandonment_rate: "freeswitch_inbound_abandonment_rate", "FreeSWITCH inbound Calls Abandonment Rate";
+        event_lag: "freeswitch_event_lag_last_seconds", "FreeSWITCH last event delivery lag";
+        modules_loaded: "freeswitch_modules_loaded", "FreeSWITCH Modules Loaded Since mod_prometheus";
+        scheduler_tasks: "freeswitch_scheduler_tasks", "FreeSWITCH Scheduler Tasks Registered";
+        playbacks_active: "freeswitch_playbacks_active", "FreeSWITCH Active Playbacks";
+        recordings_active: "freeswitch_recordings_active", "FreeSWITCH Active Recordings";
+        transcoding_sessions_active: "freeswitch_transcoding_sessions_active", "FreeSWITCH Active Transcoded Sessions";
+        // Last RTCP receiver report of the calls up
+        rtcp_sessions: "freeswitch_rtcp_sessions", "FreeSWITCH Active Sessions with RTCP Receiver Reports";
+        rtcp_loss_avg: "freeswitch_rtcp_loss_fraction_avg", "FreeSWITCH Active Sessions average RTCP reported loss fraction";
+        rtcp_loss_max: "freeswitch_rtcp_loss_fraction_max", "FreeSWITCH Active Sessions highest RTCP reported loss fraction";
+        rtcp_jitter_avg: "freeswitch_rtcp_jitter_seconds_avg", "FreeSWITCH Active Sessions average RTCP reported jitter";
+        rtcp_jitter_max: "freeswitch_rtcp_jitter_seconds_max", "FreeSWITCH Active Sessions highest RTCP reported jitter";
+        media_srtp_sessions_active: "freeswitch_media_srtp_sessions_active", "FreeSWITCH Active Sessions with Encrypted Media (SRTP)";
+        media_rtp_sessions_active: "freeswitch_media_rtp_sessions_active", "FreeSWITCH Active Sessions with Unencrypted Media (RTP)";
+        // Answered sessions per media mode, only full media ones have their RTP going through the box
+        sessions_active_media_full: "freeswitch_sessions_active_media_full", "FreeSWITCH Active Sessions with Full Media";
+        sessions_active_media_proxy: "freeswitch_sessions_active_media_proxy", "FreeSWITCH Active Sessions with Proxy Media";
+        sessions_active_media_bypass: "freeswitch_sessions_active_media_bypass", "FreeSWITCH Active Sessions with Bypass Media";
+    }
+    histograms {
+        // Call setup phases, out of the channel call state changes
+        sessions_inbound_time_to_ring: "freeswitch_sessions_inbound_time_to_ring_seconds",
+            "FreeSWITCH inbound Sessions time from creation to ringing", SETUP_BUCKETS;
+        sessions_inbound_ring: "freeswitch_sessions_inbound_ring_seconds",
+            "FreeSWITCH inbound Sessions time ringing", SETUP_BUCKETS;
+        sessions_inbound_early_media: "freeswitch_sessions_inbound_early_media_seconds",
+            "FreeSWITCH inbound Sessions time in early media", SETUP_BUCKETS;
+        sessions_outbound_time_to_ring: "freeswitch_sessions_outbound_time_to_ring_seconds",
+            "FreeSWITCH outbound Sessions time from creation to ringing", SETUP_BUCKETS;
+        sessions_outbound_ring: "freeswitch_sessions_outbound_ring_seconds",
+            "FreeSWITCH outbound Sessions time ringing", SETUP_BUCKETS;
+        sessions_outbound_early_media: "freeswitch_sessions_outbound_early_media_seconds",
+            "FreeSWITCH outbound Sessions time in early media", SETUP_BUCKETS;
+        // Time to answer, from the channel creation
+        sessions_inbound_answer_delay: "freeswitch_sessions_inbound_answer_delay_seconds",
+            "FreeSWITCH inbound Sessions time from creation to answer", SETUP_BUCKETS;
+        sessions_outbound_answer_delay: "freeswitch_sessions_outbound_answer_delay_seconds",
+            "FreeSWITCH outbound Sessions time from creation to answer", SETUP_BUCKETS;
+
+        // Jitter buffer statistics of the inbound audio, out of the hangup variables
+        sessions_inbound_jb_size: "freeswitch_sessions_inbound_jitter_buffer_size_packets",
+            "FreeSWITCH inbound Sessions largest jitter buffer size", PACKET_BUCKETS;
+        sessions_inbound_jb_drops: "freeswitch_sessions_inbound_jitter_buffer_dropped_packets",
+            "FreeSWITCH inbound Sessions packets dropped by the jitter buffer", PACKET_BUCKETS;
+        sessions_inbound_jb_resets: "freeswitch_sessions_inbound_jitter_buffer_flushed_packets",
+            "FreeSWITCH inbound Sessions packets flushed by jitter buffer resets", PACKET_BUCKETS;
+        sessions_outbound_jb_size: "freeswitch_sessions_outbound_jitter_buffer_size_packets",
+            "FreeSWITCH outbound Sessions largest jitter buffer size", PACKET_BUCKETS;
+        sessions_outbound_jb_drops: "freeswitch_sessions_outbound_jitter_buffer_dropped_packets",
+            "FreeSWITCH outbound Sessions packets dropped by the jitter buffer", PACKET_BUCKETS;
+        sessions_outbound_jb_resets: "freeswitch_sessions_outbound_jitter_buffer_flushed_packets",
+            "FreeSWITCH outbound Sessions packets flushed by jitter buffer resets", PACKET_BUCKETS;
+
+        // RTCP receiver reports received during calls
+        rtcp_loss: "freeswitch_rtcp_loss_fraction", "FreeSWITCH RTCP reported fraction of packets lost", LOSS_BUCKETS;
+        rtcp_jitter: "freeswitch_rtcp_jitter_seconds", "FreeSWITCH RTCP reported interarrival jitter", JITTER_BUCKETS;
+
+        valet_park: "freeswitch_valet_park_seconds", "FreeSWITCH Valet Parking time parked", PARK_BUCKETS;
+
+        bgapi_job: "freeswitch_bgapi_job_seconds", "FreeSWITCH Background API Jobs execution time", JOB_BUCKETS;
+
+        // Bridge attempts of the calls that got bridged in the end, 1 without any route advance
+        calls_attempts_to_success: "freeswitch_calls_bridge_attempts_to_success",
+            "FreeSWITCH Bridge Attempts of the Calls Bridged", ATTEMPT_BUCKETS;
+    }
+    summaries {
+        sessions_inbound_billsec: "freeswitch_sessions_inbound_billsec_seconds", "FreeSWITCH inbound Calls billed duration";
+        sessions_outbound_billsec: "freeswitch_sessions_outbound_billsec_seconds", "FreeSWITCH outbound Calls billed duration";
+    }
+}
+
+// Metrics created through the APIs/applications, along with the last time they were
+// used so the least recently used one can be evicted once max-user-metrics is reached
+struct UserMetrics {
+    counters: HashMap<String, (Arc<Mutex<Counter>>, Instant)>,
+    gauges: HashMap<String, (Arc<Mutex<Gauge>>, Instant)>,
+    summaries: HashMap<String, (Arc<Mutex<Summary>>, Instant)>,
+    // Untyped values set by prom_value_set
+    values: HashMap<String, (Arc<Mutex<Gauge>>, Instant)>,
+    // Only warn the first time the limit is hit, the counters tell the rest
+    limit_warned: bool,
+}
+
+impl UserMetrics {
+    fn new() -> UserMetrics {
+        UserMetrics { counters: HashMap::new(), gauges: HashMap::new(), summaries: HashMap::new(), values: HashMap::new(),
+                      limit_warned: false }
+    }
+
+    // Remove the metrics not used for longer than the ttl
+    fn expire(&mut self, ttl: Duration) {
+        let reg = match current_registry() {
+            Some(r) => r,
+            None => return,
+        };
+        let now = Instant::now();
+        let mut expired = 0;
+        self.counters.retain(|name, &mut (ref counter, used)| {
+            if now.duration_since(used) < ttl {
+                return true;
+            }
+            fslog!(DEBUG, "Expiring idle user counter {}\n", name);
+            lock(&reg).unregister_counter(counter);
+            expired += 1;
+            false
+        });
+        self.gauges.retain(|name, &mut (ref gauge, used)| {
+            if now.duration_since(used) < ttl {
+                return true;
+            }
+            fslog!(DEBUG, "Expiring idle user gauge {}\n", name);
+            lock(&reg).unregister_gauge(gauge);
+            expired += 1;
+            false
+        });
+        self.summaries.retain(|name, &mut (ref summary, used)| {
+            if now.duration_since(used) < ttl {
+                return true;
+            }
+            fslog!(DEBUG, "Expiring idle user summary {}\n", name);
+            lock(&reg).unregister_summary(summary);
+            expired += 1;
+            false
+        });
+        self.values.retain(|name, &mut (ref value, used)| {
+            if now.duration_since(used) < ttl {
+                return true;
+            }
+            fslog!(DEBUG, "Expiring idle user value {}\n", name);
+            lock(&reg).unregister_untyped(value);
+            expired += 1;
+            false
+        });
+        lock(&METRICS.user_metrics_expired).increment_by(expired as f64);
+    }
+
+    fn clear(&mut self) {
+        self.counters.clear();
+        self.gauges.clear();
+        self.summaries.clear();
+        self.values.clear();
+        self.limit_warned = false;
+    }
+
+    fn len(&self) -> usize {
+        self.counters.len() + self.gauges.len() + self.summaries.len() + self.values.len()
+    }
+
+    fn contains(&self, kind: UserMetricKind, name: &str) -> bool {
+        match kind {
+            UserMetricKind::Counter => self.counters.contains_key(name),
+            UserMetricKind::Gauge => self.gauges.contains_key(name),
+            UserMetricKind::Summary => self.summaries.contains_key(name),
+            UserMetricKind::Value => self.values.contains_key(name),
+        }
+    }
+
+    // Make sure there is room for the metrics not created yet, all of them or none: false
+    // without evicting anything when they must be rejected. The metrics wanted are never
+    // evicted to make room for the others
+    fn make_room(&mut self, wanted: &[(UserMetricKind, &str)]) -> bool {
+        let mut new: Vec<&(UserMetricKind, &str)> = Vec::new();
+        for m in wanted.iter().filter(|&&(k, n)| !self.contains(k, n)) {
+            if !new.contains(&m) {
+                new.push(m);
+            }
+        }
+        let config = current_config();
+        let (limit, evict) = (config.max_user_metrics, config.evict_user_metrics);
+        if limit == 0 || self.len() + new.len() <= limit {
+            return true;
+        }
+        if !self.limit_warned {
+            fslog!(WARNING, "Reached max-user-metrics ({}), {} user metrics from now on\n",
+                   limit, if evict { "evicting least recently used" } else { "rejecting new" });
+            self.limit_warned = true;
+        }
+        let kept = wanted.iter().filter(|&&(k, n)| self.contains(k, n)).count();
+        let excess = self.len() + new.len() - limit;
+        if !evict || excess > self.len() - kept {
+            lock(&METRICS.user_metrics_rejected).increment();
+            return false;
+        }
+        let reg = match current_registry() {
+            Some(r) => r,
+            None => return false,
+        };
+        let wanted = |k: UserMetricKind, n: &str| wanted.iter().any(|&(wk, wn)| wk == k && wn == n);
+        for _ in 0..excess {
+            // Least recently used metric of each kind, the oldest of them is evicted
+            let lru_counter = self.counters.iter().filter(|&(k, _)| !wanted(UserMetricKind::Counter, k))
+                                  .min_by_key(|&(_, v)| v.1).map(|(k, v)| (v.1, 0, k.clone()));
+            let lru_gauge = self.gauges.iter().filter(|&(k, _)| !wanted(UserMetricKind::Gauge, k))
+                                .min_by_key(|&(_, v)| v.1).map(|(k, v)| (v.1, 1, k.clone()));
+            let lru_summary = self.summaries.iter().filter(|&(k, _)| !wanted(UserMetricKind::Summary, k))
+                                  .min_by_key(|&(_, v)| v.1).map(|(k, v)| (v.1, 2, k.clone()));
+            let lru_value = self.values.iter().filter(|&(k, _)| !wanted(UserMetricKind::Value, k))
+                                .min_by_key(|&(_, v)| v.1).map(|(k, v)| (v.1, 3, k.clone()));
+            match lru_counter.into_iter().chain(lru_gauge).chain(lru_summary).chain(lru_value).min() {
+                Some((_, 0, c)) => {
+                    let (counter, _) = self.counters.remove(&c).unwrap();
+                    lock(&reg).unregister_counter(&counter);
+                }
+                Some((_, 1, g)) => {
+                    let (gauge, _) = self.gauges.remove(&g).unwrap();
+                    lock(&reg).unregister_gauge(&gauge);
+                }
+                Some((_, 2, s)) => {
+                    let (summary, _) = self.summaries.remove(&s).unwrap();
+                    lock(&reg).unregister_summary(&summary);
+                }
+                Some((_, _, v)) => {
+                    let (value, _) = self.values.remove(&v).unwrap();
+                    lock(&reg).unregister_untyped(&value);
+                }
+                None => return false,
+            }
+            lock(&METRICS.user_metrics_evicted).increment();
+        }
+        true
+    }
+
+    // Looks the metric up by series name (e.g. ivr_calls_total{queue="sales"}), creating and
+    // registering it when new. There must be room for it
+    fn get(&mut self, kind: UserMetricKind, series: &str, now: Instant) -> UserMetric {
+        let reg = current_registry();
+        let (name, labels) = match parse_series(series) {
+            Some((name, labels, _)) => (name, labels),
+            None => (series.to_string(), Vec::new()),
+        };
+        let labels: Vec<(&str, &str)> = labels.iter().map(|(n, v)| (&n[..], &v[..])).collect();
+        match kind {
+            UserMetricKind::Counter => {
+                let entry = self.counters.entry(series.to_string()).or_insert_with(|| {
+                    let counter = Arc::new(Mutex::new(Counter::with_labels(name.clone(), name.clone(), &labels)));
+                    if let Some(ref reg) = reg {
+                        lock(reg).register_counter(counter.clone());
+                    }
+                    (counter, now)
+                });
+                entry.1 = now;
+                UserMetric::Counter(entry.0.clone())
+            }
+            UserMetricKind::Gauge => {
+                let entry = self.gauges.entry(series.to_string()).or_insert_with(|| {
+                    let gauge = Arc::new(Mutex::new(Gauge::with_labels(name.clone(), name.clone(), &labels)));
+                    if let Some(ref reg) = reg {
+                        lock(reg).register_gauge(gauge.clone());
+                    }
+                    (gauge, now)
+                });
+                entry.1 = now;
+                UserMetric::Gauge(entry.0.clone())
+            }
+            // Summaries have no labels, the callers only ask for them by name
+            UserMetricKind::Summary => {
+                let entry = self.summaries.entry(series.to_string()).or_insert_with(|| {
+                    let config = current_config();
+                    let summary = Arc::new(Mutex::new(Summary::new(name.clone(), name.clone(),
+                                                                   config.summary_quantiles.clone(), config.summary_window)));
+                    if let Some(ref reg) = reg {
+                        lock(reg).register_summary(summary.clone());
+                    }
+                    (summary, now)
+                });
+                entry.1 = now;
+                UserMetric::Summary(entry.0.clone())
+            }
+            // Untyped metric for values computed elsewhere (e.g. a trunk balance from a CRM), a
+            // gauge of its own type so it's not mistaken for something the module measures
+            UserMetricKind::Value => {
+                let entry = self.values.entry(series.to_string()).or_insert_with(|| {
+                    let value = Arc::new(Mutex::new(Gauge::with_labels(name.clone(), name.clone(), &labels)));
+                    if let Some(ref reg) = reg {
+                        lock(reg).register_untyped(value.clone());
+                    }
+                    (value, now)
+                });
+                entry.1 = now;
+                UserMetric::Value(entry.0.clone())
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum UserMetricKind {
+    Counter,
+    Gauge,
+    Summary,
+    Value,
+}
+
+pub enum UserMetric {
+    Counter(Arc<Mutex<Counter>>),
+    Gauge(Arc<Mutex<Gauge>>),
+    Summary(Arc<Mutex<Summary>>),
+    Value(Arc<Mutex<Gauge>>),
+}
+
+lazy_static! {
+    static ref USER_METRICS: Mutex<UserMetrics> = {
+        Mutex::new(UserMetrics::new())
+    };
+    // Built-in counters whose name or labels carry a dimension (e.g. the sound language),
+    // and are therefore only known and registered once first seen, by series name
+    static ref DYNAMIC_COUNTERS: Mutex<HashMap<String, Arc<Mutex<Counter>>>> = {
+        Mutex::new(HashMap::new())
+    };
+    static ref DYNAMIC_GAUGES: Mutex<HashMap<String, Arc<Mutex<Gauge>>>> = {
+        Mutex::new(HashMap::new())
+    };
+    static ref DYNAMIC_HISTOGRAMS: Mutex<HashMap<String, Arc<Mutex<Histogram>>>> = {
+        Mutex::new(HashMap::new())
+    };
+    pub static ref METRICS: Metrics = Metrics::new();
+    static ref PEAKS_RESET: Mutex<Instant> = {
+        Mutex::new(Instant::now())
+    };
+}
+
+
+pub fn user_metrics_expire(ttl: Duration) {
+    lock(&USER_METRICS).expire(ttl);
+}
+
+// Active sessions gauge along with the gauge of its peak
+type SessionsPeak = (&'static Arc<Mutex<Gauge>>, &'static Arc<Mutex<Gauge>>);
+
+fn sessions_peaks() -> [SessionsPeak; 2] {
+    [(&METRICS.sessions_active_inbound, &METRICS.sessions_inbound_peak),
+     (&METRICS.sessions_active_outbound, &METRICS.sessions_outbound_peak)]
+}
+
+// Raise the peak gauges to the active sessions gauges when above
+pub fn sessions_peak_update() {
+    for &(active, peak) in sessions_peaks().iter() {
+        let active = lock(active).value();
+        let mut peak = lock(peak);
+        if active > peak.value() {
+            peak.set(active);
+        }
+    }
+}
+
+// Start the peaks over from the sessions active now
+pub fn sessions_peak_reset() {
+    for &(active, peak) in sessions_peaks().iter() {
+        let active = lock(active).value();
+        lock(peak).set(active);
+    }
+    *lock(&PEAKS_RESET) = Instant::now();
+}
+
+pub fn sessions_peak_expire(interval: Duration) {
+    let elapsed = lock(&PEAKS_RESET).elapsed();
+    if elapsed >= interval {
+        sessions_peak_reset();
+    }
+}
+
+pub fn clear() {
+    lock(&USER_METRICS).clear();
+    lock(&DYNAMIC_COUNTERS).clear();
+    lock(&DYNAMIC_GAUGES).clear();
+    lock(&DYNAMIC_HISTOGRAMS).clear();
+}
+
+// Values asked to the core at scrape time, they can't drift like the
+// ones maintained from events
+pub struct CoreCollector;
+
+impl Collector for CoreCollector {
+    fn collect(&mut self) -> Vec<Sample> {
+        let sessions = unsafe { fsr::core_session_count() };
+        // Session ids are sequential, the next one to be handed out tells how many were created
+        let created = unsafe { fsr::core_session_id() }.saturating_sub(1);
+        let idle = unsafe { fsr::core_idle_cpu() };
+        let max = session_ctl(fsr::session_ctl_t::SCSC_MAX_SESSIONS);
+        let utilization = if max > 0 { sessions as f64 / max as f64 } else { 0 as f64 };
+        vec![Sample::gauge("freeswitch_sessions_current", "FreeSWITCH Sessions counted by the core", sessions as f64),
+             Sample::counter("freeswitch_core_sessions_created_total", "FreeSWITCH Sessions created by the core", created as f64),
+             Sample::gauge("freeswitch_core_sps", "FreeSWITCH Sessions created in the last second",
+                           session_ctl(fsr::session_ctl_t::SCSC_LAST_SPS) as f64),
+             Sample::gauge("freeswitch_core_sps_peak", "FreeSWITCH Peak sessions per second since startup",
+                           session_ctl(fsr::session_ctl_t::SCSC_SPS_PEAK) as f64),
+             Sample::gauge("freeswitch_core_sps_peak_5min", "FreeSWITCH Peak sessions per second over the last 5 minutes",
+                           session_ctl(fsr::session_ctl_t::SCSC_SPS_PEAK_FIVEMIN) as f64),
+             Sample::gauge("freeswitch_core_sps_max", "FreeSWITCH Maximum sessions per second allowed",
+                           session_ctl(fsr::session_ctl_t::SCSC_SPS) as f64),
+             Sample::gauge("freeswitch_core_sessions_peak", "FreeSWITCH Peak sessions since startup",
+                           session_ctl(fsr::session_ctl_t::SCSC_SESSIONS_PEAK) as f64),
+             Sample::gauge("freeswitch_core_sessions_peak_5min", "FreeSWITCH Peak sessions over the last 5 minutes",
+                           session_ctl(fsr::session_ctl_t::SCSC_SESSIONS_PEAK_FIVEMIN) as f64),
+             Sample::gauge("freeswitch_core_sessions_max", "FreeSWITCH Maximum sessions allowed",
+                           max as f64),
+             Sample::gauge("freeswitch_core_sessions_utilization_ratio", "FreeSWITCH Sessions out of the maximum allowed",
+                           utilization),
+             Sample::gauge("freeswitch_core_idle_cpu_percent", "FreeSWITCH Idle CPU as seen by the core", idle)]
+    }
+}
+
+// Integer core setting or counter, as read by the status API. A 0 argument reads
+// without changing anything
+fn session_ctl(cmd: fsr::session_ctl_t) -> i32 {
+    let mut val: i32 = 0;
+    unsafe { fsr::core_session_ctl(cmd, &mut val as *mut i32 as *mut ::std::os::raw::c_void) };
+    val
+}
+
+// Make an arbitrary value (language, profile, gateway name ...) usable as part of a metric name
+pub fn metric_name_part(value: &str) -> String {
+    value.chars()
+         .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+         .collect()
+}
+
+// A series of a family of counters told apart by labels, e.g. one per gateway, the
+// label values being capped by the caller
+pub fn dynamic_labelled_counter_get(name: &str, labels: &[(&str, &str)], help: &str) -> Arc<Mutex<Counter>> {
+    let series = series_name(name, labels);
+    let mut counters = lock(&DYNAMIC_COUNTERS);
+    counters.entry(series).or_insert_with(|| {
+        let counter = Arc::new(Mutex::new(Counter::with_labels(name.to_string(), help.to_string(), labels)));
+        if let Some(reg) = current_registry() {
+            lock(&reg).register_counter(counter.clone());
+        }
+        counter
+    }).clone()
+}
+
+pub fn dynamic_labelled_gauge_get(name: &str, labels: &[(&str, &str)], help: &str) -> Arc<Mutex<Gauge>> {
+    let series = series_name(name, labels);
+    let mut gauges = lock(&DYNAMIC_GAUGES);
+    gauges.entry(series).or_insert_with(|| {
+        let gauge = Arc::new(Mutex::new(Gauge::with_labels(name.to_string(), help.to_string(), labels)));
+        if let Some(reg) = current_registry() {
+            lock(&reg).register_gauge(gauge.clone());
+        }
+        gauge
+    }).clone()
+}
+
+pub fn dynamic_labelled_histogram_get(name: &str, labels: &[(&str, &str)], help: &str,
+                                      buckets: &[f64]) -> Arc<Mutex<Histogram>> {
+    let series = series_name(name, labels);
+    let mut histograms = lock(&DYNAMIC_HISTOGRAMS);
+    histograms.entry(series).or_insert_with(|| {
+        let histogram = Arc::new(Mutex::new(Histogram::with_labels(name.to_string(), help.to_string(),
+                                                                   buckets.to_vec(), labels)));
+        if let Some(reg) = current_registry() {
+            lock(&reg).register_histogram(histogram.clone());
+        }
+        histogram
+    }).clone()
+}
+
+// Looks up the user metrics by series name, creating the new ones, all of them or none
+// when there is no room for the new ones
+pub fn user_metrics_get(wanted: &[(UserMetricKind, &str)]) -> Option<Vec<UserMetric>> {
+    let mut user = lock(&USER_METRICS);
+    if !user.make_room(wanted) {
+        return None;
+    }
+    let now = Instant::now();
+    Some(wanted.iter().map(|&(kind, name)| user.get(kind, name, now)).collect())
+}
+
+pub fn counter_get(name: &str) -> Option<Arc<Mutex<Counter>>> {
+    match user_metrics_get(&[(UserMetricKind::Counter, name)])?.pop() {
+        Some(UserMetric::Counter(c)) => Some(c),
+        _ => None,
+    }
+}
+
+pub fn gauge_get(name: &str) -> Option<Arc<Mutex<Gauge>>> {
+    match user_metrics_get(&[(UserMetricKind::Gauge, name)])?.pop() {
+        Some(UserMetric::Gauge(g)) => Some(g),
+        _ => None,
+    }
+}
+
+pub fn value_get(name: &str) -> Option<Arc<Mutex<Gauge>>> {
+    match user_metrics_get(&[(UserMetricKind::Value, name)])?.pop() {
+        Some(UserMetric::Value(v)) => Some(v),
+        _ => None,
+    }
+}
+
+pub fn summary_get(name: &str) -> Option<Arc<Mutex<Summary>>> {
+    match user_metrics_get(&[(UserMetricKind::Summary, name)])?.pop() {
+        Some(UserMetric::Summary(s)) => Some(s),
+        _ => None,
+    }
+}
+
+// Apply the configured quantiles and window to the built-in and user summaries
+pub fn summaries_configure(quantiles: &[f64], window: Duration) {
+    for s in METRICS.summaries() {
+        lock(s).set_quantiles(quantiles.to_vec(), window);
+    }
+    for (s, _) in lock(&USER_METRICS).summaries.values() {
+        lock(s).set_quantiles(quantiles.to_vec(), window);
+    }
+}
+
+// User metrics are saved one per line as "counter|gauge|value <series> <value>", summaries
+// are not saved as their quantiles can't be restored
+pub fn user_metrics_save(path: &str) {
+    let mut out = String::new();
+    {
+        let user = lock(&USER_METRICS);
+        for (name, (counter, _)) in user.counters.iter() {
+            out.push_str(&format!("counter {} {}\n", name, lock(counter).value()));
+        }
+        for (name, (gauge, _)) in user.gauges.iter() {
+            out.push_str(&format!("gauge {} {}\n", name, lock(gauge).value()));
+        }
+        for (name, (value, _)) in user.values.iter() {
+            out.push_str(&format!("value {} {}\n", name, lock(value).value()));
+        }
+    }
+    match File::create(path).and_then(|mut f| f.write_all(out.as_bytes())) {
+        Ok(_) => fslog!(INFO, "Saved user metrics to {}\n", path),
+        Err(e) => fslog!(ERROR, "Failed to save user metrics to {}: {}\n", path, e),
+    }
+}
+
+pub fn user_metrics_restore(path: &str) {
+    let mut contents = String::new();
+    if let Err(e) = File::open(path).and_then(|mut f| f.read_to_string(&mut contents)) {
+        fslog!(NOTICE, "Not restoring user metrics from {}: {}\n", path, e);
+        return;
+    }
+    let mut restored = 0;
+    for line in contents.lines() {
+        // Labels values may contain spaces, the series is split off first
+        let mut fields = line.splitn(2, ' ');
+        let kind = fields.next().unwrap_or("");
+        let (series, value) = match fields.next().and_then(parse_series) {
+            Some((name, labels, rest)) if valid_metric_name(&name) => match rest.parse::<f64>() {
+                Ok(v) => (series_name(&name, &labels), v),
+                Err(_) => {
+                    fslog!(WARNING, "Ignoring invalid line in {}: {:?}\n", path, line);
+                    continue;
+                }
+            },
+            _ => {
+                fslog!(WARNING, "Ignoring invalid line in {}: {:?}\n", path, line);
+                continue;
+            }
+        };
+        match kind {
+            "counter" => {
+                if let Some(c) = counter_get(&series) {
+                    lock(&c).increment_by(value);
+                    restored += 1;
+                }
+            }
+            "gauge" => {
+                if let Some(g) = gauge_get(&series) {
+                    lock(&g).set(value);
+                    restored += 1;
+                }
+            }
+            "value" => {
+                if let Some(v) = value_get(&series) {
+                    lock(&v).set(value);
+                    restored += 1;
+                }
+            }
+            _ => fslog!(WARNING, "Ignoring invalid line in {}: {:?}\n", path, line),
+        }
+    }
+    fslog!(INFO, "Restored {} user metrics from {}\n", restored, path);
+}
+
+// Built-in counters are saved one per line as "<series> <value> <help>", written to a
+// temporary file first so a crash while writing doesn't lose the previous checkpoint
+pub fn counters_checkpoint(path: &str) {
+    let mut out = String::new();
+    for c in METRICS.counters() {
+        let c = lock(c);
+        out.push_str(&format!("{} {}\n", series_name(c.name(), c.labels()), c.value()));
+    }
+    for (name, c) in lock(&DYNAMIC_COUNTERS).iter() {
+        let c = lock(c);
+        out.push_str(&format!("{} {} {}\n", name, c.value(), c.help()));
+    }
+    let tmp = format!("{}.tmp", path);
+    let res = File::create(&tmp).and_then(|mut f| f.write_all(out.as_bytes()))
+                                .and_then(|_| fs::rename(&tmp, path));
+    if let Err(e) = res {
+        fslog!(ERROR, "Failed to checkpoint counters to {}: {}\n", path, e);
+    }
+}
+
+pub fn counters_restore(path: &str) {
+    let mut contents = String::new();
+    if let Err(e) = File::open(path).and_then(|mut f| f.read_to_string(&mut contents)) {
+        fslog!(NOTICE, "Not restoring counters from {}: {}\n", path, e);
+        return;
+    }
+    let mut restored = 0;
+    for line in contents.lines() {
+        // Labels values may contain spaces, the series is split off first
+        let (name, labels, rest) = match parse_series(line) {
+            Some(s) => s,
+            None => {
+                fslog!(WARNING, "Ignoring invalid line in {}: {:?}\n", path, line);
+                continue;
+            }
+        };
+        let fields: Vec<&str> = rest.splitn(2, ' ').collect();
+        let value = match fields[0].parse::<f64>() {
+            Ok(v) => v,
+            Err(_) => {
+                fslog!(WARNING, "Ignoring invalid line in {}: {:?}\n", path, line);
+                continue;
+            }
+        };
+        let builtin = METRICS.counters().into_iter().find(|c| {
+            let c = lock(c);
+            c.name() == name && c.labels() == &labels[..]
+        });
+        if let Some(c) = builtin {
+            lock(c).restore(value);
+        } else if fields.len() == 2 && valid_metric_name(&name) {
+            let labels: Vec<(&str, &str)> = labels.iter().map(|(n, v)| (&n[..], &v[..])).collect();
+            lock(&dynamic_labelled_counter_get(&name, &labels, fields[1])).restore(value);
+        } else {
+            fslog!(WARNING, "Ignoring unknown counter in {}: {:?}\n", path, line);
+            continue;
+        }
+        restored += 1;
+    }
+    fslog!(INFO, "Restored {} counters from {}\n", restored, path);
+}
@@ -0,0 +1,1042 @@
+// Prometheus registry: keeps track of the registered metrics and renders them in the
+// Prometheus text exposition format through the prometheus crate encoder, or in the
+// OpenMetrics one
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use prometheus::{proto, TextEncoder};
+
+use crate::derived::Derived;
+
+static DEFAULT_PREFIX: &str = "freeswitch_";
+
+// A mutex poisoned by a panic while holding it is used anyway, a metric possibly left
+// half updated beats taking FreeSWITCH down with a second panic
+pub fn lock<'a, T>(m: &'a Mutex<T>) -> MutexGuard<'a, T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+thread_local! {
+    // Changes made to the metrics by the current thread while recording, see record_changes
+    static CHANGES: RefCell<Option<Vec<(String, f64)>>> = const { RefCell::new(None) };
+}
+
+// Runs f and returns the changes it made to the metrics, by series name (built-in name
+// and labels) and net delta, in the order first made. Metrics changed back are left out
+pub fn record_changes<F: FnOnce()>(f: F) -> Vec<(String, f64)> {
+    CHANGES.with(|c| *c.borrow_mut() = Some(Vec::new()));
+    f();
+    let changes = CHANGES.with(|c| c.borrow_mut().take()).unwrap_or_default();
+    changes.into_iter().filter(|&(_, delta)| delta != 0 as f64).collect()
+}
+
+fn changed(name: &str, labels: &[(String, String)], delta: f64) {
+    CHANGES.with(|c| {
+        if let Some(ref mut changes) = *c.borrow_mut() {
+            let series = series_name(name, labels);
+            match changes.iter_mut().find(|&&mut (ref s, _)| *s == series) {
+                Some(&mut (_, ref mut d)) => *d += delta,
+                None => changes.push((series, delta)),
+            }
+        }
+    });
+}
+
+pub struct Counter {
+    name: String,
+    help: String,
+    // Label names and values telling this series apart from the others of the same name
+    labels: Vec<(String, String)>,
+    value: f64,
+    created: SystemTime,
+    updated: SystemTime,
+}
+
+impl Counter {
+    pub fn new(name: String, help: String) -> Counter {
+        Counter::with_labels(name, help, &[])
+    }
+
+    // One series of a family of counters sharing a name and told apart by their labels
+    pub fn with_labels(name: String, help: String, labels: &[(&str, &str)]) -> Counter {
+        let now = SystemTime::now();
+        Counter { name, help, labels: owned_labels(labels), value: 0 as f64, created: now, updated: now }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn labels(&self) -> &[(String, String)] {
+        &self.labels
+    }
+
+    pub fn help(&self) -> &str {
+        &self.help
+    }
+
+    pub fn increment(&mut self) -> f64 {
+        self.increment_by(1.0)
+    }
+
+    pub fn increment_by(&mut self, val: f64) -> f64 {
+        self.value += val;
+        self.updated = SystemTime::now();
+        changed(&self.name, &self.labels, val);
+        self.value
+    }
+
+    pub fn value(&self) -> f64 {
+        self.value
+    }
+
+    // Only meant to bring back a value saved before a restart
+    pub fn restore(&mut self, val: f64) {
+        self.value = val;
+    }
+}
+
+pub struct Gauge {
+    name: String,
+    help: String,
+    labels: Vec<(String, String)>,
+    value: f64,
+    updated: SystemTime,
+}
+
+impl Gauge {
+    pub fn new(name: String, help: String) -> Gauge {
+        Gauge::with_labels(name, help, &[])
+    }
+
+    pub fn with_labels(name: String, help: String, labels: &[(&str, &str)]) -> Gauge {
+        Gauge { name, help, labels: owned_labels(labels), value: 0 as f64, updated: SystemTime::now() }
+    }
+
+    pub fn set(&mut self, val: f64) -> f64 {
+        changed(&self.name, &self.labels, val - self.value);
+        self.value = val;
+        self.updated = SystemTime::now();
+        self.value
+    }
+
+    pub fn increment(&mut self) -> f64 {
+        self.increment_by(1.0)
+    }
+
+    pub fn increment_by(&mut self, val: f64) -> f64 {
+        self.value += val;
+        self.updated = SystemTime::now();
+        changed(&self.name, &self.labels, val);
+        self.value
+    }
+
+    pub fn decrement(&mut self) -> f64 {
+        self.decrement_by(1.0)
+    }
+
+    pub fn decrement_by(&mut self, val: f64) -> f64 {
+        self.value -= val;
+        self.updated = SystemTime::now();
+        changed(&self.name, &self.labels, -val);
+        self.value
+    }
+
+    pub fn value(&self) -> f64 {
+        self.value
+    }
+}
+
+// Observations counted in cumulative buckets by upper bound, along with their sum
+pub struct Histogram {
+    name: String,
+    help: String,
+    labels: Vec<(String, String)>,
+    // Upper bounds in increasing order, the +Inf bucket is implicit
+    buckets: Vec<f64>,
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+    updated: SystemTime,
+    // Last exemplar observed in each bucket, +Inf last, only rendered as OpenMetrics
+    exemplars: Vec<Option<Exemplar>>,
+}
+
+// An example observation (e.g. the call it was measured on) linked from a histogram bucket
+struct Exemplar {
+    labels: Vec<(String, String)>,
+    value: f64,
+    time: SystemTime,
+}
+
+// OpenMetrics limit on the combined length of the label names and values of an exemplar
+const MAX_EXEMPLAR_LABELS_LENGTH: usize = 128;
+
+impl Histogram {
+    pub fn new(name: String, help: String, buckets: Vec<f64>) -> Histogram {
+        Histogram::with_labels(name, help, buckets, &[])
+    }
+
+    pub fn with_labels(name: String, help: String, buckets: Vec<f64>, labels: &[(&str, &str)]) -> Histogram {
+        let counts = vec![0; buckets.len()];
+        let exemplars = (0..buckets.len() + 1).map(|_| None).collect();
+        Histogram { name, help, labels: owned_labels(labels), buckets, counts,
+                    sum: 0 as f64, count: 0, updated: SystemTime::now(), exemplars }
+    }
+
+    pub fn observe(&mut self, val: f64) {
+        for (bound, count) in self.buckets.iter().zip(self.counts.iter_mut()) {
+            if val <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += val;
+        self.count += 1;
+        self.updated = SystemTime::now();
+        changed(&format!("{}_sum", self.name), &self.labels, val);
+        changed(&format!("{}_count", self.name), &self.labels, 1.0);
+    }
+
+    // observe() keeping the labels (e.g. the call Unique-ID) as the exemplar of the bucket the
+    // value falls in. Labels past the OpenMetrics length limit are left out, and the exemplar
+    // too when none is left
+    pub fn observe_with_exemplar(&mut self, val: f64, labels: &[(&str, &str)]) {
+        self.observe(val);
+        let mut length = 0;
+        let labels = labels.iter().take_while(|&&(n, v)| {
+            length += n.chars().count() + v.chars().count();
+            length <= MAX_EXEMPLAR_LABELS_LENGTH
+        }).cloned().collect::<Vec<_>>();
+        if labels.is_empty() {
+            return;
+        }
+        let bucket = self.buckets.iter().position(|b| val <= *b).unwrap_or(self.buckets.len());
+        self.exemplars[bucket] = Some(Exemplar { labels: owned_labels(&labels), value: val, time: self.updated });
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+}
+
+// Quantiles of the observations over a sliding time window, computed when rendered,
+// along with the sum and count of all the observations
+pub struct Summary {
+    name: String,
+    help: String,
+    quantiles: Vec<f64>,
+    window: Duration,
+    // Observations within the window, oldest first
+    observations: VecDeque<(Instant, f64)>,
+    sum: f64,
+    count: u64,
+    updated: SystemTime,
+}
+
+impl Summary {
+    pub fn new(name: String, help: String, quantiles: Vec<f64>, window: Duration) -> Summary {
+        Summary { name, help, quantiles, window, observations: VecDeque::new(),
+                  sum: 0 as f64, count: 0, updated: SystemTime::now() }
+    }
+
+    pub fn set_quantiles(&mut self, quantiles: Vec<f64>, window: Duration) {
+        self.quantiles = quantiles;
+        self.window = window;
+        self.expire();
+    }
+
+    pub fn observe(&mut self, val: f64) {
+        self.observations.push_back((Instant::now(), val));
+        self.sum += val;
+        self.count += 1;
+        self.updated = SystemTime::now();
+        changed(&format!("{}_sum", self.name), &[], val);
+        changed(&format!("{}_count", self.name), &[], 1.0);
+        self.expire();
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    fn expire(&mut self) {
+        let window = self.window;
+        while self.observations.front().is_some_and(|&(t, _)| t.elapsed() > window) {
+            self.observations.pop_front();
+        }
+    }
+
+    // Value of each quantile over the window, NaN without observations
+    fn quantile_values(&mut self) -> Vec<(f64, f64)> {
+        self.expire();
+        let mut values: Vec<f64> = self.observations.iter().map(|&(_, v)| v).collect();
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(::std::cmp::Ordering::Equal));
+        self.quantiles.iter().map(|&q| {
+            if values.is_empty() {
+                return (q, f64::NAN);
+            }
+            let rank = (q * values.len() as f64).ceil() as usize;
+            (q, values[rank.max(1).min(values.len()) - 1])
+        }).collect()
+    }
+}
+
+pub enum MetricType {
+    Counter,
+    Gauge,
+}
+
+// A value computed by a Collector
+pub struct Sample {
+    pub name: String,
+    pub help: String,
+    pub kind: MetricType,
+    pub value: f64,
+}
+
+impl Sample {
+    pub fn gauge(name: &str, help: &str, value: f64) -> Sample {
+        Sample { name: name.to_string(), help: help.to_string(), kind: MetricType::Gauge, value }
+    }
+
+    pub fn counter(name: &str, help: &str, value: f64) -> Sample {
+        Sample { name: name.to_string(), help: help.to_string(), kind: MetricType::Counter, value }
+    }
+}
+
+// Metrics computed on demand every time the metrics are rendered, instead of
+// being kept up to date as things happen
+pub trait Collector: Send {
+    fn collect(&mut self) -> Vec<Sample>;
+}
+
+// Subset of the metrics to render, by exposed name
+#[derive(Default)]
+pub struct Filter {
+    // Only these metrics, unless empty
+    pub names: Vec<String>,
+    // Glob patterns of metrics to leave out
+    pub exclude: Vec<String>,
+    // Render in the OpenMetrics text format, with the histogram exemplars, instead of the
+    // Prometheus one
+    pub openmetrics: bool,
+}
+
+impl Filter {
+    fn selects(&self, exposed: &str) -> bool {
+        (self.names.is_empty() || self.names.iter().any(|n| n == exposed)) &&
+        !self.exclude.iter().any(|p| glob_match(p.as_bytes(), exposed.as_bytes()))
+    }
+}
+
+pub struct Registry {
+    prefix: Option<String>,
+    // Metrics along with the name they are exposed as
+    counters: Vec<(String, Arc<Mutex<Counter>>)>,
+    gauges: Vec<(String, Arc<Mutex<Gauge>>)>,
+    // Values set from outside, exposed without a type as we don't know what they measure
+    untyped: Vec<(String, Arc<Mutex<Gauge>>)>,
+    histograms: Vec<(String, Arc<Mutex<Histogram>>)>,
+    summaries: Vec<(String, Arc<Mutex<Summary>>)>,
+    derived: Vec<(String, Derived)>,
+    collectors: Vec<Box<dyn Collector>>,
+    // Glob patterns of metrics not to be exposed
+    deny: Vec<String>,
+    // Append the time of the last change to counter and gauge samples
+    timestamps: bool,
+    // Expose a <name>_created gauge with the creation time of each counter, to tell a
+    // counter that was reset (e.g. by a restart) from one that didn't move
+    created: bool,
+    // When the metrics were last rendered, over HTTP or through prom_metrics
+    last_scrape: Option<SystemTime>,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry {
+            prefix: None,
+            counters: Vec::new(),
+            gauges: Vec::new(),
+            untyped: Vec::new(),
+            histograms: Vec::new(),
+            summaries: Vec::new(),
+            derived: Vec::new(),
+            collectors: Vec::new(),
+            deny: Vec::new(),
+            timestamps: false,
+            created: false,
+            last_scrape: None,
+        }
+    }
+
+    // Namespace replacing the "freeswitch_" prefix of built-in metrics, metrics without
+    // it (user metrics) get it prepended. Only applies to metrics registered afterwards
+    pub fn set_prefix(&mut self, prefix: &str) {
+        self.prefix = Some(prefix.to_string());
+    }
+
+    // Metrics matching any of the patterns (* and ? wildcards), either by their
+    // built-in or exposed name, are not registered nor rendered
+    pub fn set_deny(&mut self, patterns: Vec<String>) {
+        self.deny = patterns;
+    }
+
+    pub fn set_timestamps(&mut self, timestamps: bool, created: bool) {
+        self.timestamps = timestamps;
+        self.created = created;
+    }
+
+    fn denied(&self, name: &str, exposed: &str) -> bool {
+        self.deny.iter().any(|p| glob_match(p.as_bytes(), name.as_bytes()) ||
+                                 glob_match(p.as_bytes(), exposed.as_bytes()))
+    }
+
+    // Sanitized, a single invalid name would make the whole exposition unscrapable
+    fn exposed_name(&self, name: &str) -> String {
+        let exposed = match self.prefix {
+            Some(ref p) if name.starts_with(DEFAULT_PREFIX) => format!("{}{}", p, &name[DEFAULT_PREFIX.len()..]),
+            Some(ref p) => format!("{}{}", p, name),
+            None => name.to_string(),
+        };
+        if valid_metric_name(&exposed) {
+            exposed
+        } else {
+            sanitize_metric_name(&exposed)
+        }
+    }
+
+    pub fn register_counter(&mut self, counter: Arc<Mutex<Counter>>) {
+        let name = self.exposed_name(&lock(&counter).name);
+        if !self.denied(&lock(&counter).name, &name) {
+            self.counters.push((name, counter));
+        }
+    }
+
+    pub fn register_gauge(&mut self, gauge: Arc<Mutex<Gauge>>) {
+        let name = self.exposed_name(&lock(&gauge).name);
+        if !self.denied(&lock(&gauge).name, &name) {
+            self.gauges.push((name, gauge));
+        }
+    }
+
+    pub fn register_untyped(&mut self, value: Arc<Mutex<Gauge>>) {
+        let name = self.exposed_name(&lock(&value).name);
+        if !self.denied(&lock(&value).name, &name) {
+            self.untyped.push((name, value));
+        }
+    }
+
+    pub fn register_histogram(&mut self, histogram: Arc<Mutex<Histogram>>) {
+        let name = self.exposed_name(&lock(&histogram).name);
+        if !self.denied(&lock(&histogram).name, &name) {
+            self.histograms.push((name, histogram));
+        }
+    }
+
+    pub fn register_summary(&mut self, summary: Arc<Mutex<Summary>>) {
+        let name = self.exposed_name(&lock(&summary).name);
+        if !self.denied(&lock(&summary).name, &name) {
+            self.summaries.push((name, summary));
+        }
+    }
+
+    pub fn unregister_counter(&mut self, counter: &Arc<Mutex<Counter>>) {
+        self.counters.retain(|(_, c)| !Arc::ptr_eq(c, counter));
+    }
+
+    pub fn unregister_gauge(&mut self, gauge: &Arc<Mutex<Gauge>>) {
+        self.gauges.retain(|(_, g)| !Arc::ptr_eq(g, gauge));
+    }
+
+    pub fn unregister_untyped(&mut self, value: &Arc<Mutex<Gauge>>) {
+        self.untyped.retain(|(_, v)| !Arc::ptr_eq(v, value));
+    }
+
+    pub fn unregister_summary(&mut self, summary: &Arc<Mutex<Summary>>) {
+        self.summaries.retain(|(_, s)| !Arc::ptr_eq(s, summary));
+    }
+
+    // Gauges computed out of the other metrics every time the metrics are rendered
+    pub fn register_derived(&mut self, derived: Derived) {
+        let name = self.exposed_name(&derived.name);
+        self.derived.push((name, derived));
+    }
+
+    pub fn register_collector(&mut self, collector: Box<dyn Collector>) {
+        self.collectors.push(collector);
+    }
+
+    pub fn clear_derived(&mut self) {
+        self.derived.clear();
+    }
+
+    // Registered metrics, not counting those produced by collectors
+    pub fn metric_count(&self) -> usize {
+        self.counters.len() + self.gauges.len() + self.untyped.len() + self.histograms.len() + self.summaries.len() + self.derived.len()
+    }
+
+    pub fn last_scrape(&self) -> Option<SystemTime> {
+        self.last_scrape
+    }
+
+    // Current value of every registered metric by its exposed name, along with its labels
+    // as in the exposition (e.g. name{label="value"}), histograms and summaries by their
+    // <name>_sum and <name>_count
+    pub fn values(&self) -> Vec<(String, f64)> {
+        let mut values = Vec::new();
+        for (name, c) in self.counters.iter() {
+            let c = lock(c);
+            values.push((series_name(name, &c.labels), c.value));
+        }
+        for (name, g) in self.gauges.iter().chain(self.untyped.iter()) {
+            let g = lock(g);
+            values.push((series_name(name, &g.labels), g.value));
+        }
+        for (name, h) in self.histograms.iter() {
+            let h = lock(h);
+            values.push((series_name(&format!("{}_sum", name), &h.labels), h.sum));
+            values.push((series_name(&format!("{}_count", name), &h.labels), h.count as f64));
+        }
+        for (name, s) in self.summaries.iter() {
+            let s = lock(s);
+            values.push((format!("{}_sum", name), s.sum));
+            values.push((format!("{}_count", name), s.count as f64));
+        }
+        values
+    }
+
+    // Current value of a single metric by its name in values(), the collected and derived
+    // ones included. Derived rates are read without being sampled
+    pub fn value(&mut self, name: &str) -> Option<f64> {
+        if let Some(v) = self.registered_value(name).or_else(|| self.collected_value(name)) {
+            return Some(v);
+        }
+        let metrics: Vec<String> = match self.derived.iter().find(|&(n, _)| n == name) {
+            Some((_, d)) => d.metrics().iter().map(|m| m.to_string()).collect(),
+            None => return None,
+        };
+        let mut values = HashMap::new();
+        for m in metrics {
+            if let Some(v) = self.registered_value(&m).or_else(|| self.collected_value(&m)) {
+                values.insert(m, v);
+            }
+        }
+        self.derived.iter().find(|&(n, _)| n == name).map(|(_, d)| d.value(&values))
+    }
+
+    fn registered_value(&self, name: &str) -> Option<f64> {
+        for (n, c) in self.counters.iter().filter(|&(n, _)| name.starts_with(&n[..])) {
+            let c = lock(c);
+            if series_name(n, &c.labels) == name {
+                return Some(c.value);
+            }
+        }
+        for (n, g) in self.gauges.iter().chain(self.untyped.iter()).filter(|&(n, _)| name.starts_with(&n[..])) {
+            let g = lock(g);
+            if series_name(n, &g.labels) == name {
+                return Some(g.value);
+            }
+        }
+        for (n, h) in self.histograms.iter().filter(|&(n, _)| name.starts_with(&n[..])) {
+            let h = lock(h);
+            if series_name(&format!("{}_sum", n), &h.labels) == name {
+                return Some(h.sum);
+            }
+            if series_name(&format!("{}_count", n), &h.labels) == name {
+                return Some(h.count as f64);
+            }
+        }
+        for (n, s) in self.summaries.iter().filter(|&(n, _)| name.starts_with(&n[..])) {
+            let s = lock(s);
+            if format!("{}_sum", n) == name {
+                return Some(s.sum);
+            }
+            if format!("{}_count", n) == name {
+                return Some(s.count as f64);
+            }
+        }
+        None
+    }
+
+    fn collected_value(&mut self, name: &str) -> Option<f64> {
+        self.collected_values().into_iter().find(|(n, _)| n == name).map(|(_, v)| v)
+    }
+
+    // Values of the collectors by exposed name
+    fn collected_values(&mut self) -> Vec<(String, f64)> {
+        let mut samples = Vec::new();
+        for c in self.collectors.iter_mut() {
+            samples.extend(c.collect());
+        }
+        samples.into_iter().filter_map(|s| {
+            let exposed = self.exposed_name(&s.name);
+            if self.denied(&s.name, &exposed) { None } else { Some((exposed, s.value)) }
+        }).collect()
+    }
+
+    // values() plus the collected and derived metrics, evaluated now
+    pub fn values_with_derived(&mut self) -> HashMap<String, f64> {
+        let mut values: HashMap<String, f64> = self.values().into_iter().collect();
+        values.extend(self.collected_values());
+        let derived: Vec<(String, f64)> = self.derived.iter_mut().map(|&mut (ref name, ref mut d)| (name.clone(), d.evaluate(&values))).collect();
+        values.extend(derived);
+        values
+    }
+
+    // " <milliseconds since the epoch>" when timestamps are enabled, to append to a sample.
+    // OpenMetrics timestamps are in seconds
+    fn timestamp(&self, time: SystemTime, openmetrics: bool) -> String {
+        if !self.timestamps {
+            String::new()
+        } else if openmetrics {
+            format!(" {}", epoch_seconds(time))
+        } else {
+            format!(" {}", (epoch_seconds(time) * 1000.0) as u64)
+        }
+    }
+
+    // A series of the prometheus crate data model, with the time of its last change when
+    // timestamps are enabled (the encoder leaves out a zero timestamp)
+    fn series<S: AsRef<str>>(&self, labels: &[(S, S)], time: Option<SystemTime>) -> proto::Metric {
+        let mut series = proto::Metric::default();
+        series.set_label(labels.iter().map(|(n, v)| {
+            let mut label = proto::LabelPair::default();
+            label.set_name(n.as_ref().to_string());
+            label.set_value(v.as_ref().to_string());
+            label
+        }).collect());
+        match time {
+            Some(time) if self.timestamps => series.set_timestamp_ms((epoch_seconds(time) * 1000.0) as i64),
+            _ => (),
+        }
+        series
+    }
+
+    // Text exposition format of all the registered metrics
+    pub fn render(&mut self) -> String {
+        self.render_filtered(&Filter::default())
+    }
+
+    // The Prometheus text format is written by the prometheus crate encoder out of the
+    // gathered families, the OpenMetrics one (not supported by the crate) by render_openmetrics
+    pub fn render_filtered(&mut self, filter: &Filter) -> String {
+        self.last_scrape = Some(SystemTime::now());
+        if filter.openmetrics {
+            return self.render_openmetrics(filter);
+        }
+        let gathered = self.gather(filter);
+        let mut out = String::new();
+        // Only fails on a family without series, which gather() doesn't produce
+        let _ = TextEncoder::new().encode_utf8(&gathered, &mut out);
+        // The encoder doesn't support untyped metrics
+        for (name, family) in families(&self.untyped) {
+            let (raw, help) = family_info(&family, |g: &Gauge| (g.name.clone(), g.help.clone()));
+            if self.denied(&raw, name) || !filter.selects(name) {
+                continue;
+            }
+            out.push_str(&format!("# HELP {} {}\n# TYPE {} untyped\n", name, escape_help(&help), name));
+            for g in family {
+                let g = lock(g);
+                out.push_str(&format!("{}{} {}{}\n", name, label_set(&g.labels, None), g.value, self.timestamp(g.updated, false)));
+            }
+        }
+        out
+    }
+
+    // The metrics selected by the filter as families of the prometheus crate data model, in
+    // the order they are rendered. Untyped metrics are left out
+    fn gather(&mut self, filter: &Filter) -> Vec<proto::MetricFamily> {
+        let mut gathered = Vec::new();
+        for (name, family) in families(&self.counters) {
+            let (raw, help) = family_info(&family, |c: &Counter| (c.name.clone(), c.help.clone()));
+            if self.denied(&raw, name) || !filter.selects(name) {
+                continue;
+            }
+            let mut counters = Vec::new();
+            let mut created = Vec::new();
+            for c in family {
+                let c = lock(c);
+                let mut series = self.series(&c.labels, Some(c.updated));
+                let mut value = proto::Counter::default();
+                value.set_value(c.value);
+                series.set_counter(value);
+                counters.push(series);
+                let mut series = self.series(&c.labels, None);
+                let mut value = proto::Gauge::default();
+                value.set_value(epoch_seconds(c.created));
+                series.set_gauge(value);
+                created.push(series);
+            }
+            gathered.push(metric_family(name, &help, proto::MetricType::COUNTER, counters));
+            if self.created {
+                let created_name = format!("{}_created", name.trim_end_matches("_total"));
+                gathered.push(metric_family(&created_name, &help, proto::MetricType::GAUGE, created));
+            }
+        }
+        for (name, family) in families(&self.gauges) {
+            let (raw, help) = family_info(&family, |g: &Gauge| (g.name.clone(), g.help.clone()));
+            if self.denied(&raw, name) || !filter.selects(name) {
+                continue;
+            }
+            let gauges = family.iter().map(|g| {
+                let g = lock(g);
+                let mut series = self.series(&g.labels, Some(g.updated));
+                let mut value = proto::Gauge::default();
+                value.set_value(g.value);
+                series.set_gauge(value);
+                series
+            }).collect();
+            gathered.push(metric_family(name, &help, proto::MetricType::GAUGE, gauges));
+        }
+        for (name, family) in families(&self.histograms) {
+            let (raw, help) = family_info(&family, |h: &Histogram| (h.name.clone(), h.help.clone()));
+            if self.denied(&raw, name) || !filter.selects(name) {
+                continue;
+            }
+            let histograms = family.iter().map(|h| {
+                let h = lock(h);
+                let mut series = self.series(&h.labels, Some(h.updated));
+                let mut value = proto::Histogram::default();
+                value.set_bucket(h.buckets.iter().zip(h.counts.iter()).map(|(&bound, &count)| {
+                    let mut bucket = proto::Bucket::default();
+                    bucket.set_upper_bound(bound);
+                    bucket.set_cumulative_count(count);
+                    bucket
+                }).collect());
+                value.set_sample_sum(h.sum);
+                value.set_sample_count(h.count);
+                series.set_histogram(value);
+                series
+            }).collect();
+            gathered.push(metric_family(name, &help, proto::MetricType::HISTOGRAM, histograms));
+        }
+        for (name, s) in self.summaries.iter() {
+            let mut s = lock(s);
+            if self.denied(&s.name, name) || !filter.selects(name) {
+                continue;
+            }
+            let mut series = self.series::<&str>(&[], Some(s.updated));
+            let mut value = proto::Summary::default();
+            value.set_quantile(s.quantile_values().into_iter().map(|(q, v)| {
+                let mut quantile = proto::Quantile::default();
+                quantile.set_quantile(q);
+                quantile.set_value(v);
+                quantile
+            }).collect());
+            value.set_sample_sum(s.sum);
+            value.set_sample_count(s.count);
+            series.set_summary(value);
+            gathered.push(metric_family(name, &s.help, proto::MetricType::SUMMARY, vec![series]));
+        }
+        let mut samples = Vec::new();
+        for c in self.collectors.iter_mut() {
+            samples.extend(c.collect());
+        }
+        for sample in samples {
+            let name = self.exposed_name(&sample.name);
+            if self.denied(&sample.name, &name) || !filter.selects(&name) {
+                continue;
+            }
+            gathered.push(match sample.kind {
+                MetricType::Counter => counter_family(&name, &sample.help, sample.value),
+                MetricType::Gauge => gauge_family(&name, &sample.help, sample.value),
+            });
+        }
+        if !self.derived.is_empty() {
+            let values: HashMap<String, f64> = self.values().into_iter().collect();
+            let deny = &self.deny;
+            for &mut (ref name, ref mut d) in self.derived.iter_mut() {
+                if deny.iter().any(|p| glob_match(p.as_bytes(), name.as_bytes())) || !filter.selects(name) {
+                    continue;
+                }
+                let value = d.evaluate(&values);
+                gathered.push(gauge_family(name, &d.help, value));
+            }
+        }
+        gathered
+    }
+
+    // OpenMetrics text format, with the histogram exemplars
+    fn render_openmetrics(&mut self, filter: &Filter) -> String {
+        let mut out = String::new();
+        for (name, family) in families(&self.counters) {
+            let (raw, help) = family_info(&family, |c: &Counter| (c.name.clone(), c.help.clone()));
+            if self.denied(&raw, name) || !filter.selects(name) {
+                continue;
+            }
+            // OpenMetrics names the family without the _total suffix of its samples
+            let base = name.trim_end_matches("_total");
+            out.push_str(&format!("# HELP {} {}\n# TYPE {} counter\n", base, escape_label_value(&help), base));
+            for c in family {
+                let c = lock(c);
+                let labels = label_set(&c.labels, None);
+                out.push_str(&format!("{}_total{} {}{}\n", base, labels, c.value, self.timestamp(c.updated, true)));
+                if self.created {
+                    out.push_str(&format!("{}_created{} {}\n", base, labels, epoch_seconds(c.created)));
+                }
+            }
+        }
+        for (kind, metrics) in [("gauge", &self.gauges), ("unknown", &self.untyped)].iter() {
+            for (name, family) in families(metrics) {
+                let (raw, help) = family_info(&family, |g: &Gauge| (g.name.clone(), g.help.clone()));
+                if self.denied(&raw, name) || !filter.selects(name) {
+                    continue;
+                }
+                out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, escape_label_value(&help), name, kind));
+                for g in family {
+                    let g = lock(g);
+                    out.push_str(&format!("{}{} {}{}\n", name, label_set(&g.labels, None), g.value, self.timestamp(g.updated, true)));
+                }
+            }
+        }
+        for (name, family) in families(&self.histograms) {
+            let (raw, help) = family_info(&family, |h: &Histogram| (h.name.clone(), h.help.clone()));
+            if self.denied(&raw, name) || !filter.selects(name) {
+                continue;
+            }
+            out.push_str(&format!("# HELP {} {}\n# TYPE {} histogram\n", name, escape_label_value(&help), name));
+            for h in family {
+                let h = lock(h);
+                let ts = self.timestamp(h.updated, true);
+                let exemplar = |i: usize| match h.exemplars[i] {
+                    Some(ref e) => format!(" # {} {} {}", label_set(&e.labels, None), e.value, epoch_seconds(e.time)),
+                    None => String::new(),
+                };
+                for (i, (bound, count)) in h.buckets.iter().zip(h.counts.iter()).enumerate() {
+                    out.push_str(&format!("{}_bucket{} {}{}{}\n",
+                                          name, label_set(&h.labels, Some(("le", &bound.to_string()))), count, ts, exemplar(i)));
+                }
+                let labels = label_set(&h.labels, None);
+                out.push_str(&format!("{}_bucket{} {}{}{}\n{}_sum{} {}{}\n{}_count{} {}{}\n",
+                                      name, label_set(&h.labels, Some(("le", "+Inf"))), h.count, ts, exemplar(h.buckets.len()),
+                                      name, labels, h.sum, ts, name, labels, h.count, ts));
+            }
+        }
+        for (name, s) in self.summaries.iter() {
+            let mut s = lock(s);
+            if self.denied(&s.name, name) || !filter.selects(name) {
+                continue;
+            }
+            let ts = self.timestamp(s.updated, true);
+            out.push_str(&format!("# HELP {} {}\n# TYPE {} summary\n", name, escape_label_value(&s.help), name));
+            for (q, value) in s.quantile_values() {
+                out.push_str(&format!("{}{{quantile=\"{}\"}} {}{}\n",
+                                      name, escape_label_value(&q.to_string()), value, ts));
+            }
+            out.push_str(&format!("{}_sum {}{}\n{}_count {}{}\n", name, s.sum, ts, name, s.count, ts));
+        }
+        let mut samples = Vec::new();
+        for c in self.collectors.iter_mut() {
+            samples.extend(c.collect());
+        }
+        for sample in samples {
+            let name = self.exposed_name(&sample.name);
+            if self.denied(&sample.name, &name) || !filter.selects(&name) {
+                continue;
+            }
+            let (kind, family_name, name) = match sample.kind {
+                MetricType::Counter => {
+                    let base = name.trim_end_matches("_total").to_string();
+                    ("counter", base.clone(), format!("{}_total", base))
+                }
+                MetricType::Gauge => ("gauge", name.clone(), name),
+            };
+            out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n{} {}\n",
+                                  family_name, escape_label_value(&sample.help), family_name, kind, name, sample.value));
+        }
+        if !self.derived.is_empty() {
+            let values: HashMap<String, f64> = self.values().into_iter().collect();
+            let deny = &self.deny;
+            for &mut (ref name, ref mut d) in self.derived.iter_mut() {
+                if deny.iter().any(|p| glob_match(p.as_bytes(), name.as_bytes())) || !filter.selects(name) {
+                    continue;
+                }
+                let value = d.evaluate(&values);
+                out.push_str(&format!("# HELP {} {}\n# TYPE {} gauge\n{} {}\n",
+                                      name, escape_label_value(&d.help), name, name, value));
+            }
+        }
+        out.push_str("# EOF\n");
+        out
+    }
+}
+
+// Metrics grouped by exposed name, in the order each name was first registered. The
+// series of a family must be rendered together under a single HELP and TYPE
+fn families<T>(metrics: &[(String, Arc<Mutex<T>>)]) -> Vec<(&str, Vec<&Arc<Mutex<T>>>)> {
+    let mut families: Vec<(&str, Vec<&Arc<Mutex<T>>>)> = Vec::new();
+    let mut index: HashMap<&str, usize> = HashMap::new();
+    for (name, metric) in metrics.iter() {
+        match index.get(&name[..]) {
+            Some(&i) => families[i].1.push(metric),
+            None => {
+                index.insert(&name[..], families.len());
+                families.push((&name[..], vec![metric]));
+            }
+        }
+    }
+    families
+}
+
+// Registered name and help of a family, as given by its first series
+fn family_info<T, F: Fn(&T) -> (String, String)>(family: &[&Arc<Mutex<T>>], info: F) -> (String, String) {
+    info(&lock(family[0]))
+}
+
+// A family of the prometheus crate data model
+fn metric_family(name: &str, help: &str, kind: proto::MetricType, series: Vec<proto::Metric>) -> proto::MetricFamily {
+    let mut family = proto::MetricFamily::default();
+    family.set_name(name.to_string());
+    family.set_help(help.to_string());
+    family.set_field_type(kind);
+    family.set_metric(series);
+    family
+}
+
+fn counter_family(name: &str, help: &str, value: f64) -> proto::MetricFamily {
+    let mut series = proto::Metric::default();
+    let mut counter = proto::Counter::default();
+    counter.set_value(value);
+    series.set_counter(counter);
+    metric_family(name, help, proto::MetricType::COUNTER, vec![series])
+}
+
+fn gauge_family(name: &str, help: &str, value: f64) -> proto::MetricFamily {
+    let mut series = proto::Metric::default();
+    let mut gauge = proto::Gauge::default();
+    gauge.set_value(value);
+    series.set_gauge(gauge);
+    metric_family(name, help, proto::MetricType::GAUGE, vec![series])
+}
+
+fn owned_labels(labels: &[(&str, &str)]) -> Vec<(String, String)> {
+    labels.iter().map(|&(n, v)| (n.to_string(), v.to_string())).collect()
+}
+
+// {name="value",...} with an extra label (e.g. a histogram bucket bound) appended,
+// nothing without any label
+fn label_set<S: AsRef<str>>(labels: &[(S, S)], extra: Option<(&str, &str)>) -> String {
+    let pairs: Vec<String> = labels.iter().map(|(n, v)| (n.as_ref(), v.as_ref())).chain(extra)
+                                   .map(|(n, v)| format!("{}=\"{}\"", n, escape_label_value(v)))
+                                   .collect();
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+// A series as it appears in the exposition, e.g. freeswitch_gateway_registered{gateway="carrier"}
+pub fn series_name<S: AsRef<str>>(name: &str, labels: &[(S, S)]) -> String {
+    format!("{}{}", name, label_set(labels, None))
+}
+
+// Metric name, labels and rest of a line starting with a series
+type ParsedSeries<'a> = (String, Vec<(String, String)>, &'a str);
+
+// Splits a line starting with a series written by series_name into the metric name, its
+// labels and the rest of the line
+pub fn parse_series(line: &str) -> Option<ParsedSeries<'_>> {
+    let end = line.find(['{', ' ']).unwrap_or(line.len());
+    let name = line[..end].to_string();
+    let mut labels = Vec::new();
+    let mut rest = &line[end..];
+    if rest.starts_with('{') {
+        rest = &rest[1..];
+        while !rest.starts_with('}') {
+            let eq = rest.find("=\"")?;
+            let label = rest[..eq].to_string();
+            let mut value = String::new();
+            let mut chars = rest[eq + 2..].char_indices();
+            let close = loop {
+                match chars.next()? {
+                    (i, '"') => break eq + 2 + i,
+                    (_, '\\') => match chars.next()?.1 {
+                        'n' => value.push('\n'),
+                        c => value.push(c),
+                    },
+                    (_, c) => value.push(c),
+                }
+            };
+            labels.push((label, value));
+            rest = &rest[close + 1..];
+            if rest.starts_with(',') {
+                rest = &rest[1..];
+            } else if !rest.starts_with('}') {
+                return None;
+            }
+        }
+        rest = &rest[1..];
+    }
+    Some((name, labels, rest.trim_start_matches(' ')))
+}
+
+fn epoch_seconds(time: SystemTime) -> f64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as f64 + d.subsec_nanos() as f64 / 1_000_000_000.0,
+        Err(_) => 0 as f64,
+    }
+}
+
+// Prometheus metric names must match [a-zA-Z_:][a-zA-Z0-9_:]*, anything else makes the
+// whole exposition unscrapable
+pub fn valid_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' || c == ':' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+        }
+        _ => false,
+    }
+}
+
+// Label names must match [a-zA-Z_][a-zA-Z0-9_]*, those starting with __ are reserved
+pub fn valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            !name.starts_with("__") && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+pub fn sanitize_metric_name(name: &str) -> String {
+    let mut sanitized: String = name.chars()
+                                    .map(|c| if c.is_ascii_alphanumeric() || c == ':' { c } else { '_' })
+                                    .collect();
+    if sanitized.chars().next().is_none_or(|c| c.is_ascii_digit()) {
+        sanitized.insert(0, '_');
+    }
+    sanitized
+}
+
+// HELP text escapes backslashes and line feeds, label values also double quotes
+fn escape_help(help: &str) -> String {
+    help.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn escape_label_value(value: &str) -> String {
+    escape_help(value).replace('"', "\\\"")
+}
+
+
+// Shell-like pattern match supporting * (any sequence) and ? (any character)
+pub fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some(&b'*') => (0..name.len() + 1).any(|i| glob_match(&pattern[1..], &name[i..])),
+        Some(&b'?') => !name.is_empty() && glob_match(&pattern[1..], &name[1..]),
+        Some(c) => name.first() == Some(c) && glob_match(&pattern[1..], &name[1..]),
+    }
+}
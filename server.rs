@@ -0,0 +1,619 @@
+// HTTP listener serving the registry metrics to Prometheus
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+use std::net::{self, IpAddr, SocketAddr};
+use std::ptr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime};
+
+use freeswitchrs::raw as fsr;
+use freeswitchrs::raw::log_level::{DEBUG, NOTICE, WARNING};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::{Builder, Runtime};
+use tokio::sync::{oneshot, watch};
+use tokio::task::spawn_blocking;
+use tokio::time::{sleep, sleep_until, timeout};
+
+use crate::registry::{Counter, Filter, Histogram, Registry, lock};
+
+static MAX_REQUEST_SIZE: usize = 8192;
+
+// Connections each listener keeps open unless configured otherwise
+static MAX_CONNECTIONS: usize = 64;
+
+// Clients whose last request time is shown by prom_status, the one that scraped least
+// recently is forgotten to make room for a new one
+static MAX_HTTP_CLIENTS: usize = 32;
+
+static REQUEST_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+// Requests of all the listeners, registered along with the built-in metrics on load
+pub struct HttpMetrics {
+    ok: Arc<Mutex<Counter>>,
+    forbidden: Arc<Mutex<Counter>>,
+    unavailable: Arc<Mutex<Counter>>,
+    // Connections closed without a response: timed out, too large or not a valid request
+    aborted: Arc<Mutex<Counter>>,
+    duration: Arc<Mutex<Histogram>>,
+    // When each client last got the metrics, to tell which Prometheus stopped scraping. Kept
+    // out of the metrics, a series per client address would grow without bounds
+    clients: Mutex<HashMap<IpAddr, SystemTime>>,
+}
+
+impl HttpMetrics {
+    fn new() -> HttpMetrics {
+        let counter = |code: &str| {
+            Arc::new(Mutex::new(Counter::with_labels("freeswitch_prometheus_http_requests_total".to_string(),
+                                                     "FreeSWITCH Prometheus HTTP requests answered".to_string(),
+                                                     &[("code", code)])))
+        };
+        HttpMetrics {
+            ok: counter("200"),
+            forbidden: counter("403"),
+            unavailable: counter("503"),
+            aborted: Arc::new(Mutex::new(Counter::new("freeswitch_prometheus_http_requests_aborted_total".to_string(),
+                                                      "FreeSWITCH Prometheus HTTP connections closed without a response".to_string()))),
+            duration: Arc::new(Mutex::new(Histogram::new("freeswitch_prometheus_http_request_duration_seconds".to_string(),
+                                                         "FreeSWITCH Prometheus HTTP time to serve the metrics".to_string(),
+                                                         REQUEST_BUCKETS.to_vec()))),
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn register(metrics: &Arc<HttpMetrics>, reg: &mut Registry) {
+        reg.register_counter(metrics.ok.clone());
+        reg.register_counter(metrics.forbidden.clone());
+        reg.register_counter(metrics.unavailable.clone());
+        reg.register_counter(metrics.aborted.clone());
+        reg.register_histogram(metrics.duration.clone());
+    }
+
+    // Clients served lately along with when they last got the metrics, most recent first
+    pub fn clients(&self) -> Vec<(IpAddr, SystemTime)> {
+        let mut clients: Vec<(IpAddr, SystemTime)> = lock(&self.clients).iter().map(|(c, t)| (*c, *t)).collect();
+        clients.sort_by_key(|&(_, t)| Reverse(t));
+        clients
+    }
+
+    fn served(&self, client: IpAddr, duration: Duration) {
+        lock(&self.ok).increment();
+        lock(&self.duration).observe(duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1_000_000_000.0);
+        let mut clients = lock(&self.clients);
+        if clients.len() >= MAX_HTTP_CLIENTS && !clients.contains_key(&client) {
+            let oldest = clients.iter().min_by_key(|&(_, t)| *t).map(|(c, _)| *c);
+            if let Some(c) = oldest {
+                clients.remove(&c);
+            }
+        }
+        clients.insert(client, SystemTime::now());
+    }
+}
+
+// Async runtime serving the connections of all the listeners from a small pool of threads,
+// so a new listener, a slow client or a connection held open doesn't need a thread of its
+// own. Started on load and stopped on unload, once the listeners are stopped
+pub struct Workers {
+    runtime: Option<Runtime>,
+    // Set when stopping to the time past which connections still waiting for their request
+    // are closed unanswered
+    deadline: watch::Sender<Option<Instant>>,
+    connections: Arc<OpenConnections>,
+    metrics: Arc<HttpMetrics>,
+}
+
+// Connections of all the listeners not closed yet, waited for when stopping
+#[derive(Default)]
+struct OpenConnections {
+    count: Mutex<usize>,
+    closed: Condvar,
+}
+
+impl Workers {
+    pub fn start(count: usize) -> io::Result<Workers> {
+        let runtime = Builder::new_multi_thread().worker_threads(count.max(1)).thread_name("mod_prometheus-http")
+                                                 .enable_io().enable_time().build()?;
+        Ok(Workers {
+            runtime: Some(runtime),
+            deadline: watch::channel(None).0,
+            connections: Arc::new(OpenConnections::default()),
+            metrics: Arc::new(HttpMetrics::new()),
+        })
+    }
+
+    pub fn metrics(&self) -> &Arc<HttpMetrics> {
+        &self.metrics
+    }
+
+    // Finishes the scrapes in progress, giving up on the connections still waiting for their
+    // request once the deadline passes. A request already read is answered within the write
+    // timeout, it's never cut short leaving a truncated response
+    pub fn stop(&mut self, deadline: Duration) {
+        self.deadline.send_replace(Some(Instant::now() + deadline));
+        let mut count = lock(&self.connections.count);
+        while *count > 0 {
+            count = self.connections.closed.wait(count).unwrap();
+        }
+        drop(count);
+        self.runtime = None;
+    }
+}
+
+// Settings of how the real client address is found when scraped through a proxy
+struct ProxySettings {
+    // Connections start with a PROXY protocol (v1) header carrying the real client address
+    proxy_protocol: bool,
+    // Peers whose X-Forwarded-For header is trusted to carry the real client address
+    trusted_proxies: Vec<IpAddr>,
+    // FreeSWITCH ACL (acl.conf.xml list) the real client address must be allowed by
+    acl: Option<String>,
+}
+
+// Limits protecting the switch from a misbehaving scraper or a port scanner holding
+// connections open
+#[derive(Clone, Copy)]
+struct Limits {
+    // Connections accepted and not closed yet, any more get a 503 response
+    max_connections: usize,
+    read_timeout: Duration,
+    write_timeout: Duration,
+}
+
+// What the connections accepted by a listener are served with
+struct Listener {
+    registry: Arc<Mutex<Registry>>,
+    proxy: Arc<Mutex<ProxySettings>>,
+    limits: Arc<Mutex<Limits>>,
+    access_log: Arc<AtomicBool>,
+    openmetrics: Arc<AtomicBool>,
+    metrics: Arc<HttpMetrics>,
+    // Connections accepted by this listener and not closed yet
+    connections: Arc<AtomicUsize>,
+    open: Arc<OpenConnections>,
+    deadline: watch::Receiver<Option<Instant>>,
+}
+
+// Counts a connection as closed, for its listener and the workers, once served
+struct ConnectionGuard {
+    listener: Arc<AtomicUsize>,
+    workers: Arc<OpenConnections>,
+}
+
+impl ConnectionGuard {
+    fn new(listener: &Listener) -> ConnectionGuard {
+        *lock(&listener.open.count) += 1;
+        ConnectionGuard { listener: listener.connections.clone(), workers: listener.open.clone() }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.listener.fetch_sub(1, Ordering::SeqCst);
+        let mut count = lock(&self.workers.count);
+        *count -= 1;
+        if *count == 0 {
+            self.workers.closed.notify_all();
+        }
+    }
+}
+
+pub struct Server {
+    host: String,
+    // Tried in order until one is not in use
+    ports: Vec<u16>,
+    // Shared with the listener task so they can be changed on reload
+    proxy: Arc<Mutex<ProxySettings>>,
+    limits: Arc<Mutex<Limits>>,
+    // Log every request served at DEBUG
+    access_log: Arc<AtomicBool>,
+    // Serve the OpenMetrics format to the clients asking for it
+    openmetrics: Arc<AtomicBool>,
+    // Tells the listener task to stop, and disconnected once it stopped listening
+    listener: Option<(oneshot::Sender<()>, mpsc::Receiver<()>)>,
+    // Address listened on once started
+    address: Option<SocketAddr>,
+}
+
+impl Server {
+    pub fn new(host: String, ports: Vec<u16>) -> Server {
+        Server {
+            host,
+            ports,
+            proxy: Arc::new(Mutex::new(ProxySettings { proxy_protocol: false, trusted_proxies: Vec::new(), acl: None })),
+            limits: Arc::new(Mutex::new(Limits {
+                max_connections: MAX_CONNECTIONS,
+                read_timeout: Duration::from_secs(5),
+                write_timeout: Duration::from_secs(5),
+            })),
+            access_log: Arc::new(AtomicBool::new(false)),
+            openmetrics: Arc::new(AtomicBool::new(false)),
+            listener: None,
+            address: None,
+        }
+    }
+
+    pub fn set_proxy(&self, proxy_protocol: bool, trusted_proxies: Vec<IpAddr>) {
+        let mut proxy = lock(&self.proxy);
+        proxy.proxy_protocol = proxy_protocol;
+        proxy.trusted_proxies = trusted_proxies;
+    }
+
+    // Clients not allowed by the ACL get a 403 Forbidden, None allows any client
+    pub fn set_acl(&self, acl: Option<String>) {
+        lock(&self.proxy).acl = acl;
+    }
+
+    pub fn set_limits(&self, max_connections: usize, read_timeout: Duration, write_timeout: Duration) {
+        *lock(&self.limits) = Limits {
+            max_connections,
+            read_timeout,
+            write_timeout,
+        };
+    }
+
+    pub fn set_access_log(&self, access_log: bool) {
+        self.access_log.store(access_log, Ordering::SeqCst);
+    }
+
+    // Off by default: the OpenMetrics format names counter samples <name>_total, renaming the
+    // counters without the suffix for the Prometheus scrapers asking for it
+    pub fn set_openmetrics(&self, openmetrics: bool) {
+        self.openmetrics.store(openmetrics, Ordering::SeqCst);
+    }
+
+    // Listen on the first port not in use, accepting and serving the registry metrics from
+    // a task on the workers runtime. Returns the address listened on, or the error of the
+    // last port tried
+    pub fn start(&mut self, reg: &Arc<Mutex<Registry>>, workers: &Workers) -> io::Result<SocketAddr> {
+        let runtime = match workers.runtime {
+            Some(ref r) => r,
+            None => return Err(io::Error::other("workers stopped")),
+        };
+        let mut result = Err(io::Error::new(io::ErrorKind::InvalidInput, "no port to listen on"));
+        for port in self.ports.iter() {
+            result = net::TcpListener::bind((&self.host[..], *port));
+            match result {
+                Ok(_) => break,
+                Err(ref e) => fslog!(WARNING, "Failed to listen on {}: {}\n", display_address(&self.host, *port), e),
+            }
+        }
+        let listener = result?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let listener = {
+            let _runtime = runtime.enter();
+            TcpListener::from_std(listener)?
+        };
+        let served = Arc::new(Listener {
+            registry: reg.clone(),
+            proxy: self.proxy.clone(),
+            limits: self.limits.clone(),
+            access_log: self.access_log.clone(),
+            openmetrics: self.openmetrics.clone(),
+            metrics: workers.metrics.clone(),
+            connections: Arc::new(AtomicUsize::new(0)),
+            open: workers.connections.clone(),
+            deadline: workers.deadline.subscribe(),
+        });
+        let (stop, stopping) = oneshot::channel();
+        let (listening, stopped) = mpsc::channel();
+        fslog!(NOTICE, "Serving metrics on {}\n", addr);
+        runtime.spawn(accept(listener, addr, served, stopping, listening));
+        self.listener = Some((stop, stopped));
+        self.address = Some(addr);
+        Ok(addr)
+    }
+
+    pub fn address(&self) -> Option<SocketAddr> {
+        self.address
+    }
+
+    pub fn proxy_protocol(&self) -> bool {
+        lock(&self.proxy).proxy_protocol
+    }
+
+    // Stops accepting connections, those accepted are still served until the workers stop
+    pub fn stop(&mut self) {
+        if let Some((stop, stopped)) = self.listener.take() {
+            let _ = stop.send(());
+            // Returns once the listener task is gone, along with its socket
+            let _ = stopped.recv();
+        }
+        self.address = None;
+    }
+}
+
+// host:port, with the host in brackets when it's an IPv6 literal
+fn display_address(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+// Accepts connections until told to stop, serving each from a task of its own.
+// The listening sender is dropped once the socket is closed
+async fn accept(listener: TcpListener, addr: SocketAddr, served: Arc<Listener>, mut stop: oneshot::Receiver<()>,
+                listening: mpsc::Sender<()>) {
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(a) => a,
+                // Out of file descriptors and the like, give the switch some time
+                Err(_) => {
+                    sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+            _ = &mut stop => break,
+        };
+        let limits = *lock(&served.limits);
+        if served.connections.fetch_add(1, Ordering::SeqCst) >= limits.max_connections {
+            served.connections.fetch_sub(1, Ordering::SeqCst);
+            fslog!(WARNING, "Too many connections, refusing connection from {}\n", peer);
+            lock(&served.metrics.unavailable).increment();
+            tokio::spawn(unavailable(stream));
+            continue;
+        }
+        let guard = ConnectionGuard::new(&served);
+        tokio::spawn(serve(served.clone(), stream, peer, guard));
+    }
+    drop(listener);
+    drop(listening);
+    fslog!(DEBUG, "Stopped serving metrics on {}\n", addr);
+}
+
+// Answered without reading the request, the write timeout is short so a client not
+// reading can't hold the connection
+async fn unavailable(mut stream: TcpStream) {
+    let _ = timeout(Duration::from_millis(100), stream.write_all(b"HTTP/1.1 503 Service Unavailable\r\n\
+                                                                  Content-Length: 0\r\n\
+                                                                  Retry-After: 1\r\n\
+                                                                  Connection: close\r\n\r\n")).await;
+}
+
+// Any request gets the metrics, we don't care about the method or path. The connection is
+// closed once the request is accounted for
+async fn serve(served: Arc<Listener>, mut stream: TcpStream, peer: SocketAddr, _guard: ConnectionGuard) {
+    let start = Instant::now();
+    let access_log = served.access_log.load(Ordering::SeqCst);
+    let metrics = &served.metrics;
+    match respond(&served, &mut stream, peer).await {
+        Some((client, code, bytes)) => {
+            let duration = start.elapsed();
+            if code == 200 {
+                metrics.served(client, duration);
+            } else {
+                lock(&metrics.forbidden).increment();
+            }
+            if access_log {
+                fslog!(DEBUG, "{} {} {} bytes in {} ms\n", client, code, bytes,
+                       duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1_000_000);
+            }
+        }
+        None => {
+            lock(&metrics.aborted).increment();
+            if access_log {
+                fslog!(DEBUG, "{} aborted\n", peer.ip());
+            }
+        }
+    }
+}
+
+// The client served, the response code and the bytes sent, None when the connection is
+// closed without a response
+async fn respond(served: &Listener, stream: &mut TcpStream, peer: SocketAddr) -> Option<(IpAddr, u16, usize)> {
+    let limits = *lock(&served.limits);
+    let mut deadline = served.deadline.clone();
+    let request = tokio::select! {
+        request = timeout(limits.read_timeout, read_request(stream)) => match request {
+            Ok(Some(r)) => r,
+            _ => return None,
+        },
+        _ = stopped(&mut deadline) => return None,
+    };
+    let (proxy_protocol, trusted_proxies, acl) = {
+        let p = lock(&served.proxy);
+        (p.proxy_protocol, p.trusted_proxies.clone(), p.acl.clone())
+    };
+    let request = String::from_utf8_lossy(&request).into_owned();
+    let client = match client_address(&request, peer.ip(), proxy_protocol, &trusted_proxies) {
+        Some(c) => c,
+        None => {
+            fslog!(WARNING, "Dropping request from {} without a valid PROXY protocol header\n", peer);
+            return None;
+        }
+    };
+    if let Some(acl) = acl {
+        if !acl_allows(&acl, client) {
+            fslog!(WARNING, "Refusing metrics to {} not allowed by ACL {}\n", client, acl);
+            let response = b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+            return write_response(stream, response, limits.write_timeout).await.map(|_| (client, 403, response.len()));
+        }
+    }
+    fslog!(DEBUG, "Serving metrics to {}\n", client);
+    let mut filter = request_filter(&request);
+    filter.openmetrics = served.openmetrics.load(Ordering::SeqCst) && accepts_openmetrics(&request);
+    let openmetrics = filter.openmetrics;
+    // Rendering holds the registry lock while walking every metric, done on the blocking
+    // pool so a large registry or a busy lock doesn't stall the runtime workers
+    let registry = served.registry.clone();
+    let body = match spawn_blocking(move || lock(&registry).render_filtered(&filter)).await {
+        Ok(b) => b,
+        Err(e) => {
+            fslog!(WARNING, "Failed to render the metrics for {}: {}
+", client, e);
+            return None;
+        }
+    };
+    let content_type = if openmetrics {
+        "application/openmetrics-text; version=1.0.0; charset=utf-8"
+    } else {
+        "text/plain; version=0.0.4"
+    };
+    let response = format!("HTTP/1.1 200 OK\r\n\
+                            Content-Type: {}\r\n\
+                            Content-Length: {}\r\n\
+                            Connection: close\r\n\r\n{}", content_type, body.len(), body);
+    write_response(stream, response.as_bytes(), limits.write_timeout).await.map(|_| (client, 200, response.len()))
+}
+
+// Up to the end of the request headers, None when the client closes the connection
+// first or the request is too large
+async fn read_request(stream: &mut TcpStream) -> Option<Vec<u8>> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => request.extend_from_slice(&buf[..n]),
+        }
+        if request.len() > MAX_REQUEST_SIZE {
+            return None;
+        }
+    }
+    Some(request)
+}
+
+async fn write_response(stream: &mut TcpStream, response: &[u8], write_timeout: Duration) -> Option<()> {
+    timeout(write_timeout, stream.write_all(response)).await.ok()?.ok()
+}
+
+// Resolves once the workers are stopping and their deadline passed, or are gone
+async fn stopped(deadline: &mut watch::Receiver<Option<Instant>>) {
+    let deadline = match deadline.wait_for(|d| d.is_some()).await {
+        Ok(d) => *d,
+        Err(_) => None,
+    };
+    if let Some(d) = deadline {
+        sleep_until(d.into()).await;
+    }
+}
+
+// Metrics asked for in the query string: name[]=<metric> (repeated) selects metrics by name
+// and exclude=<glob> (repeated) leaves metrics out, e.g. /metrics?name[]=a_total&name[]=b_total
+fn request_filter(request: &str) -> Filter {
+    let mut filter = Filter::default();
+    let line = match request.lines().find(|l| !l.starts_with("PROXY ")) {
+        Some(l) => l,
+        None => return filter,
+    };
+    let target = line.split(' ').nth(1).unwrap_or("");
+    let query = match target.find('?') {
+        Some(pos) => &target[pos + 1..],
+        None => return filter,
+    };
+    for param in query.split('&') {
+        let mut kv = param.splitn(2, '=');
+        let key = percent_decode(kv.next().unwrap_or(""));
+        let value = percent_decode(kv.next().unwrap_or(""));
+        if value.is_empty() {
+            continue;
+        }
+        match &key[..] {
+            "name[]" | "name" => filter.names.push(value),
+            "exclude" => filter.exclude.push(value),
+            _ => {}
+        }
+    }
+    filter
+}
+
+// Whether the client accepts the OpenMetrics format, as Prometheus does unless told
+// otherwise, e.g. "Accept: application/openmetrics-text;version=1.0.0,text/plain;q=0.5"
+fn accepts_openmetrics(request: &str) -> bool {
+    request.lines()
+           .filter_map(|l| l.split_once(':'))
+           .filter(|&(name, _)| name.trim().eq_ignore_ascii_case("accept"))
+           .flat_map(|(_, value)| value.split(','))
+           .any(|media| {
+               let mut params = media.split(';').map(|p| p.trim());
+               params.next().is_some_and(|t| t.eq_ignore_ascii_case("application/openmetrics-text")) &&
+               !params.any(|p| p.strip_prefix("q=").and_then(|q| q.parse::<f64>().ok()) == Some(0 as f64))
+           })
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = if bytes[i] == b'%' && i + 2 < bytes.len() {
+            std::str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|h| u8::from_str_radix(h, 16).ok())
+        } else {
+            None
+        };
+        match hex {
+            Some(b) => {
+                out.push(b);
+                i += 3;
+            }
+            None => {
+                out.push(if bytes[i] == b'+' { b' ' } else { bytes[i] });
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// Address of the real client when the request went through a proxy. With the PROXY
+// protocol enabled the request must start with "PROXY TCP4|TCP6 <src> <dst> <sport> <dport>",
+// otherwise X-Forwarded-For is honored only when sent by a trusted proxy
+fn client_address(request: &str, peer: IpAddr, proxy_protocol: bool, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    if proxy_protocol {
+        let line = request.lines().next().unwrap_or("");
+        let fields: Vec<&str> = line.split(' ').collect();
+        if fields.len() < 2 || fields[0] != "PROXY" {
+            return None;
+        }
+        if fields[1] == "UNKNOWN" {
+            return Some(peer);
+        }
+        return fields.get(2).and_then(|a| a.parse::<IpAddr>().ok());
+    }
+    if !trusted_proxies.contains(&peer) {
+        return Some(peer);
+    }
+    // Each proxy appends the address it got the request from, so only the addresses from
+    // the right up to the first one not of a trusted proxy can be relied on, anything left
+    // of it may have been sent by the client itself
+    let forwarded: Vec<&str> = request.lines()
+                                      .filter_map(|l| {
+                                          let mut header = l.splitn(2, ':');
+                                          match (header.next(), header.next()) {
+                                              (Some(n), Some(v)) if n.eq_ignore_ascii_case("X-Forwarded-For") => Some(v),
+                                              _ => None,
+                                          }
+                                      })
+                                      .flat_map(|v| v.split(','))
+                                      .collect();
+    let mut client = peer;
+    for addr in forwarded.iter().rev() {
+        match addr.trim().parse::<IpAddr>() {
+            Ok(a) => {
+                client = a;
+                if !trusted_proxies.contains(&a) {
+                    break;
+                }
+            }
+            Err(_) => break,
+        }
+    }
+    Some(client)
+}
+
+// Whether the FreeSWITCH ACL (a list of acl.conf.xml, or a built-in one such as
+// loopback.auto) allows the address
+fn acl_allows(acl: &str, client: IpAddr) -> bool {
+    let (ip, list) = match (CString::new(client.to_string()), CString::new(acl)) {
+        (Ok(i), Ok(l)) => (i, l),
+        _ => return false,
+    };
+    unsafe { fsr::check_network_list_ip_token(ip.as_ptr(), list.as_ptr(), ptr::null_mut()) == fsr::bool_t::TRUE }
+}
@@ -0,0 +1,1394 @@
+// Drives the event handlers with synthetic events and checks the exposition output,
+// no running FreeSWITCH needed
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use freeswitchrs::raw as fsr;
+
+use crate::alerts::{self, Alert};
+use crate::api;
+use crate::config::{self, Config};
+use crate::discovery;
+use crate::events::{self, EventBinder, EventData, Handler};
+use crate::metrics::{self, METRICS};
+use crate::registry::{Filter, Registry};
+use crate::server::Workers;
+use crate::{ModuleState, STATE};
+
+struct TestEvent {
+    headers: HashMap<String, String>,
+}
+
+impl EventData for TestEvent {
+    fn header<'a>(&'a self, name: &str) -> Option<Cow<'a, str>> {
+        self.headers.get(name).map(|v| Cow::Borrowed(&v[..]))
+    }
+
+    fn body<'a>(&'a self) -> Option<Cow<'a, str>> {
+        None
+    }
+}
+
+struct TestBinder {
+    handlers: Vec<(fsr::event_types, Option<String>, Handler)>,
+}
+
+impl EventBinder for TestBinder {
+    fn bind(&mut self, event: fsr::event_types, subclass: Option<&str>, handler: Handler) {
+        self.handlers.push((event, subclass.map(|s| s.to_string()), handler));
+    }
+}
+
+impl TestBinder {
+    // Every event fired gets its own Event-Sequence, as FreeSWITCH does, unless given one
+    fn fire(&self, event: fsr::event_types, subclass: Option<&str>, headers: &[(&str, &str)]) {
+        let mut e = TestEvent {
+            headers: headers.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect(),
+        };
+        let sequence = EVENT_SEQUENCE.fetch_add(1, Ordering::SeqCst) + 1;
+        e.headers.entry("Event-Sequence".to_string()).or_insert(sequence.to_string());
+        for (ev, sub, handler) in self.handlers.iter() {
+            if *ev == event && sub.as_ref().map(|s| &s[..]) == subclass {
+                handler(&e);
+            }
+        }
+    }
+}
+
+lazy_static! {
+    // The metrics are global, tests firing events must not run concurrently
+    static ref TEST_LOCK: Mutex<()> = Mutex::new(());
+}
+
+static EVENT_SEQUENCE: AtomicUsize = AtomicUsize::new(0);
+
+// Module state with the built-in metrics registered (the listener is not started)
+// and the handlers bound to a TestBinder
+fn setup() -> (MutexGuard<'static, ()>, TestBinder) {
+    let guard = TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    {
+        let mut state = STATE.lock().unwrap();
+        if state.is_none() {
+            let reg = Arc::new(Mutex::new(Registry::new()));
+            {
+                let mut r = reg.lock().unwrap();
+                for c in METRICS.counters() {
+                    r.register_counter(c.clone());
+                }
+                for g in METRICS.gauges() {
+                    r.register_gauge(g.clone());
+                }
+                for h in METRICS.histograms() {
+                    r.register_histogram(h.clone());
+                }
+            }
+            *state = Some(ModuleState {
+                registry: reg,
+                servers: Vec::new(),
+                workers: Workers::start(1).unwrap(),
+                event_node_ids: Vec::new(),
+            });
+        }
+    }
+    let mut binder = TestBinder { handlers: Vec::new() };
+    events::bind_events(&mut binder);
+    (guard, binder)
+}
+
+// Apply a configuration the way a reload does
+fn set_config(config: Config) {
+    let state = STATE.lock().unwrap();
+    let state = state.as_ref().unwrap();
+    crate::configure(&state.registry, &state.servers, &config);
+    crate::set_config(config);
+}
+
+// Value of a metric in the exposition output
+fn scrape(name: &str) -> f64 {
+    let out = STATE.lock().unwrap().as_ref().unwrap().registry.lock().unwrap().render();
+    for line in out.lines() {
+        if line.starts_with('#') {
+            continue;
+        }
+        // Label values may hold spaces, the value is after the last one
+        if let Some((series, value)) = line.rsplit_once(' ') {
+            if series == name {
+                return value.parse().unwrap();
+            }
+        }
+    }
+    panic!("{} not found in:\n{}", name, out);
+}
+
+#[test]
+fn inbound_call_answered() {
+    let (_guard, binder) = setup();
+    let created = scrape("freeswitch_sessions_inbound_total");
+    let answered = scrape("freeswitch_sessions_inbound_answered_total");
+    let active = scrape("freeswitch_sessions_active_inbound");
+    let duration = scrape("freeswitch_sessions_inbound_duration_total");
+
+    binder.fire(fsr::event_types::CHANNEL_CREATE, None, &[("Call-Direction", "inbound")]);
+    assert_eq!(scrape("freeswitch_sessions_inbound_total"), created + 1.0);
+    assert_eq!(scrape("freeswitch_sessions_active_inbound"), active + 1.0);
+
+    binder.fire(fsr::event_types::CHANNEL_ANSWER, None, &[("Call-Direction", "inbound")]);
+    assert_eq!(scrape("freeswitch_sessions_inbound_answered_total"), answered + 1.0);
+
+    let hangup = [("Call-Direction", "inbound"),
+                  ("Caller-Channel-Answered-Time", "1500000000000000"),
+                  ("Hangup-Cause", "NORMAL_CLEARING"),
+                  ("variable_billsec", "42")];
+    binder.fire(fsr::event_types::CHANNEL_HANGUP, None, &hangup);
+    binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &hangup);
+    assert_eq!(scrape("freeswitch_sessions_inbound_duration_total"), duration + 42.0);
+
+    binder.fire(fsr::event_types::CHANNEL_DESTROY, None, &[("Call-Direction", "inbound")]);
+    assert_eq!(scrape("freeswitch_sessions_active_inbound"), active);
+}
+
+#[test]
+fn outbound_acd_milliseconds() {
+    let (_guard, binder) = setup();
+    let duration = scrape("freeswitch_sessions_outbound_duration_total");
+    let hangups = scrape("freeswitch_sessions_outbound_hangup_complete");
+
+    binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &[("Call-Direction", "outbound"),
+                                                                   ("Hangup-Cause", "NORMAL_CLEARING"),
+                                                                   ("variable_billsec", "1"),
+                                                                   ("variable_billmsec", "1500")]);
+    assert_eq!(scrape("freeswitch_sessions_outbound_duration_total"), duration + 1.5);
+    assert_eq!(scrape("freeswitch_outbound_acd"), (duration + 1.5) / (hangups + 1.0));
+}
+
+#[test]
+fn outbound_call_failed() {
+    let (_guard, binder) = setup();
+    let failed = scrape("freeswitch_sessions_outbound_failed_total");
+    let total_failed = scrape("freeswitch_sessions_failed_total");
+    let active = scrape("freeswitch_sessions_active_outbound");
+
+    binder.fire(fsr::event_types::CHANNEL_CREATE, None, &[("Call-Direction", "outbound")]);
+    binder.fire(fsr::event_types::CHANNEL_HANGUP, None, &[("Call-Direction", "outbound"),
+                                                          ("Caller-Channel-Answered-Time", "0")]);
+    binder.fire(fsr::event_types::CHANNEL_DESTROY, None, &[("Call-Direction", "outbound")]);
+    assert_eq!(scrape("freeswitch_sessions_outbound_failed_total"), failed + 1.0);
+    assert_eq!(scrape("freeswitch_sessions_failed_total"), total_failed + 1.0);
+    assert_eq!(scrape("freeswitch_sessions_active_outbound"), active);
+}
+
+#[test]
+fn session_without_direction_is_other() {
+    let (_guard, binder) = setup();
+    let dropped = scrape("freeswitch_prometheus_events_dropped_total");
+    let created = scrape("freeswitch_sessions_created_total");
+    let other = scrape("freeswitch_sessions_other_total");
+
+    binder.fire(fsr::event_types::CHANNEL_CREATE, None, &[]);
+    binder.fire(fsr::event_types::CHANNEL_CREATE, None, &[("Call-Direction", "loopback")]);
+    assert_eq!(scrape("freeswitch_sessions_created_total"), created + 2.0);
+    assert_eq!(scrape("freeswitch_sessions_other_total"), other + 2.0);
+    assert_eq!(scrape("freeswitch_prometheus_events_dropped_total"), dropped);
+    binder.fire(fsr::event_types::CHANNEL_DESTROY, None, &[]);
+    binder.fire(fsr::event_types::CHANNEL_DESTROY, None, &[("Call-Direction", "loopback")]);
+}
+
+#[test]
+fn gateway_registration_state() {
+    let (_guard, binder) = setup();
+    binder.fire(fsr::event_types::CUSTOM, Some("sofia::gateway_state"), &[("Gateway", "carrier-1"), ("State", "REGED")]);
+    binder.fire(fsr::event_types::CUSTOM, Some("sofia::gateway_state"), &[("Gateway", "carrier_1"), ("State", "REGED")]);
+    assert_eq!(scrape("freeswitch_gateway_registered{gateway=\"carrier-1\"}"), 1.0);
+    binder.fire(fsr::event_types::CUSTOM, Some("sofia::gateway_state"), &[("Gateway", "carrier-1"), ("State", "FAIL_WAIT")]);
+    assert_eq!(scrape("freeswitch_gateway_registered{gateway=\"carrier-1\"}"), 0.0);
+    assert_eq!(scrape("freeswitch_gateway_registration_retries_total{gateway=\"carrier-1\"}"), 1.0);
+    // Names differing only by punctuation are different gateways
+    assert_eq!(scrape("freeswitch_gateway_registered{gateway=\"carrier_1\"}"), 1.0);
+}
+
+#[test]
+fn gateway_ping() {
+    let (_guard, binder) = setup();
+    binder.fire(fsr::event_types::CUSTOM, Some("sofia::gateway_state"), &[("Gateway", "carrier-2"), ("State", "NOREG"),
+                                                                         ("Ping-Status", "UP"), ("Ping-Time", "42.5")]);
+    assert_eq!(scrape("freeswitch_gateway_ping_up{gateway=\"carrier-2\"}"), 1.0);
+    assert_eq!(scrape("freeswitch_gateway_ping_rtt_seconds{gateway=\"carrier-2\"}"), 0.0425);
+    binder.fire(fsr::event_types::CUSTOM, Some("sofia::gateway_state"), &[("Gateway", "carrier-2"), ("State", "NOREG"),
+                                                                         ("Ping-Status", "DOWN")]);
+    assert_eq!(scrape("freeswitch_gateway_ping_up{gateway=\"carrier-2\"}"), 0.0);
+    assert_eq!(scrape("freeswitch_gateway_ping_failures_total{gateway=\"carrier-2\"}"), 1.0);
+}
+
+#[test]
+fn gateway_billing() {
+    let (_guard, binder) = setup();
+    let hangup = |billsec: &'static str| [("variable_sip_gateway_name", "carrier-4"),
+                                         ("Caller-Channel-Answered-Time", "1500000000000000"),
+                                         ("variable_billsec", billsec)];
+    binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &hangup("3"));
+    binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &hangup("300"));
+    assert_eq!(scrape("freeswitch_gateway_answered_calls_total{gateway=\"carrier-4\"}"), 2.0);
+    assert_eq!(scrape("freeswitch_gateway_billable_calls_total{gateway=\"carrier-4\"}"), 1.0);
+}
+
+#[test]
+fn labelled_counters_checkpointed() {
+    let (_guard, _binder) = setup();
+    let path = ::std::env::temp_dir().join("mod_prometheus_test_counters").to_string_lossy().to_string();
+    let labels = [("gateway", "carrier \"3\"")];
+    let counter = metrics::dynamic_labelled_counter_get("freeswitch_gateway_ping_failures_total", &labels, "Ping Failures");
+    counter.lock().unwrap().restore(5.0);
+    metrics::counters_checkpoint(&path);
+    counter.lock().unwrap().restore(0 as f64);
+    metrics::counters_restore(&path);
+    assert_eq!(counter.lock().unwrap().value(), 5.0);
+}
+
+#[test]
+fn sip_transport() {
+    let (_guard, binder) = setup();
+    binder.fire(fsr::event_types::CUSTOM, Some("sofia::register"), &[("status", "Registered(TLS-NAT)")]);
+    binder.fire(fsr::event_types::CUSTOM, Some("sofia::register"), &[("contact", "<sip:1000@10.0.0.1:5060;transport=TCP>")]);
+    binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &[("variable_sip_via_protocol", "wss")]);
+    binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &[("variable_sip_req_uri", "1000@10.0.0.1:5060")]);
+    for _ in 0..2 {
+        binder.fire(fsr::event_types::CUSTOM, Some("sofia::unregister"), &[]);
+    }
+
+    assert_eq!(scrape("freeswitch_registrations_transport_total{transport=\"tls\"}"), 1.0);
+    assert_eq!(scrape("freeswitch_registrations_transport_total{transport=\"tcp\"}"), 1.0);
+    assert_eq!(scrape("freeswitch_sessions_transport_total{transport=\"wss\"}"), 1.0);
+    assert_eq!(scrape("freeswitch_sessions_transport_total{transport=\"udp\"}"), 1.0);
+}
+
+#[test]
+fn internal_channels() {
+    let (_guard, binder) = setup();
+    let inbound = scrape("freeswitch_sessions_inbound_total");
+    let other = scrape("freeswitch_sessions_other_total");
+    let create = |name: &str| {
+        binder.fire(fsr::event_types::CHANNEL_CREATE, None, &[("Channel-Name", name), ("Call-Direction", "inbound")]);
+    };
+
+    set_config(Config { internal_channels: vec!["loopback/*".to_string()], ..Config::default() });
+    create("loopback/1000-a");
+    create("sofia/internal/1000@192.0.2.10");
+    assert_eq!(scrape("freeswitch_sessions_inbound_total"), inbound + 1.0);
+    assert_eq!(scrape("freeswitch_sessions_other_total"), other);
+
+    set_config(Config { internal_channels: vec!["loopback/*".to_string()], internal_channels_as_other: true,
+                        ..Config::default() });
+    create("loopback/1000-b");
+    set_config(Config::default());
+    assert_eq!(scrape("freeswitch_sessions_inbound_total"), inbound + 1.0);
+    assert_eq!(scrape("freeswitch_sessions_other_total"), other + 1.0);
+}
+
+#[test]
+fn application_executions() {
+    let (_guard, binder) = setup();
+
+    binder.fire(fsr::event_types::CHANNEL_EXECUTE, None, &[("Application", "playback")]);
+    binder.fire(fsr::event_types::CHANNEL_EXECUTE_COMPLETE, None, &[("Application", "playback"),
+                                                                    ("Application-Response", "FILE PLAYED")]);
+    binder.fire(fsr::event_types::CHANNEL_EXECUTE, None, &[("Application", "playback")]);
+    binder.fire(fsr::event_types::CHANNEL_EXECUTE_COMPLETE, None, &[("Application", "playback"),
+                                                                    ("Application-Response", "-ERR file not found")]);
+
+    assert_eq!(scrape("freeswitch_applications_executed_total{application=\"playback\"}"), 2.0);
+    assert_eq!(scrape("freeswitch_applications_failed_total{application=\"playback\"}"), 1.0);
+}
+
+#[test]
+fn api_commands() {
+    let (_guard, binder) = setup();
+
+    binder.fire(fsr::event_types::API, None, &[("API-Command", "show"), ("API-Command-Argument", "channels as json")]);
+    binder.fire(fsr::event_types::API, None, &[("API-Command", "show"), ("API-Command-Argument", "channels")]);
+    binder.fire(fsr::event_types::API, None, &[("API-Command", "uuid_kill"), ("API-Command-Argument", "abc")]);
+
+    assert_eq!(scrape("freeswitch_api_commands_total{command=\"show channels\"}"), 2.0);
+    assert_eq!(scrape("freeswitch_api_commands_total{command=\"uuid_kill\"}"), 1.0);
+}
+
+#[test]
+fn bridge_failures() {
+    let (_guard, binder) = setup();
+
+    binder.fire(fsr::event_types::CHANNEL_EXECUTE_COMPLETE, None, &[("Application", "bridge"),
+                                                                    ("variable_originate_disposition", "USER_BUSY")]);
+    binder.fire(fsr::event_types::CHANNEL_EXECUTE_COMPLETE, None, &[("Application", "bridge"),
+                                                                    ("variable_originate_disposition", "NO_ROUTE_DESTINATION")]);
+    binder.fire(fsr::event_types::CHANNEL_EXECUTE_COMPLETE, None, &[("Application", "bridge"),
+                                                                    ("variable_originate_disposition", "SUCCESS")]);
+
+    assert_eq!(scrape("freeswitch_bridges_failed_total{cause=\"USER_BUSY\"}"), 1.0);
+    assert_eq!(scrape("freeswitch_bridges_failed_total{cause=\"NO_ROUTE_DESTINATION\"}"), 1.0);
+}
+
+#[test]
+fn route_advance() {
+    let (_guard, binder) = setup();
+    let bridge = |uuid: &str, disposition: &str| {
+        binder.fire(fsr::event_types::CHANNEL_EXECUTE_COMPLETE, None, &[("Unique-ID", uuid), ("Application", "bridge"),
+                                                                        ("variable_originate_disposition", disposition)]);
+    };
+
+    bridge("advanced", "NORMAL_TEMPORARY_FAILURE");
+    bridge("advanced", "SUCCESS");
+    bridge("direct", "SUCCESS");
+    bridge("failed", "NO_ROUTE_DESTINATION");
+    for uuid in ["advanced", "direct", "failed"].iter() {
+        binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &[("Unique-ID", uuid)]);
+    }
+    set_config(Config { route_attempts_variable: Some("lcr_attempts".to_string()), ..Config::default() });
+    binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &[("Unique-ID", "lcr"), ("variable_lcr_attempts", "3"),
+                                                                   ("variable_last_bridge_to", "b-leg")]);
+    set_config(Config::default());
+
+    assert_eq!(scrape("freeswitch_calls_bridge_attempts_total"), 7.0);
+    assert_eq!(scrape("freeswitch_calls_route_advanced_total"), 2.0);
+    assert_eq!(scrape("freeswitch_calls_bridge_attempts_to_success_count"), 3.0);
+    assert_eq!(scrape("freeswitch_calls_bridge_attempts_to_success_bucket{le=\"1\"}"), 1.0);
+    assert_eq!(scrape("freeswitch_calls_bridge_attempts_to_success_sum"), 6.0);
+}
+
+#[test]
+fn media_modes() {
+    let (_guard, binder) = setup();
+
+    binder.fire(fsr::event_types::CHANNEL_ANSWER, None, &[("Unique-ID", "full")]);
+    binder.fire(fsr::event_types::CHANNEL_ANSWER, None, &[("Unique-ID", "proxy"), ("variable_proxy_media", "true")]);
+    binder.fire(fsr::event_types::CHANNEL_ANSWER, None, &[("Unique-ID", "bypass"), ("variable_bypass_media", "true")]);
+    binder.fire(fsr::event_types::CHANNEL_ANSWER, None, &[("Unique-ID", "bypass"), ("variable_bypass_media", "true")]);
+    assert_eq!(scrape("freeswitch_sessions_active_media_full"), 1.0);
+    assert_eq!(scrape("freeswitch_sessions_active_media_proxy"), 1.0);
+    assert_eq!(scrape("freeswitch_sessions_active_media_bypass"), 1.0);
+
+    binder.fire(fsr::event_types::CHANNEL_DESTROY, None, &[("Unique-ID", "bypass")]);
+    assert_eq!(scrape("freeswitch_sessions_active_media_bypass"), 0.0);
+    assert_eq!(scrape("freeswitch_sessions_active_media_full"), 1.0);
+}
+
+#[test]
+fn media_encryption() {
+    let (_guard, binder) = setup();
+    let srtp = scrape("freeswitch_media_srtp_sessions_active");
+    let rtp = scrape("freeswitch_media_rtp_sessions_active");
+
+    binder.fire(fsr::event_types::CHANNEL_ANSWER, None, &[("Unique-ID", "srtp"), ("variable_rtp_use_codec_name", "OPUS"),
+                                                         ("variable_rtp_has_crypto", "AES_CM_128_HMAC_SHA1_80")]);
+    binder.fire(fsr::event_types::CHANNEL_ANSWER, None, &[("Unique-ID", "rtp"), ("variable_rtp_use_codec_name", "PCMU")]);
+    binder.fire(fsr::event_types::CHANNEL_ANSWER, None, &[("Unique-ID", "loopback")]);
+    assert_eq!(scrape("freeswitch_media_srtp_sessions_active"), srtp + 1.0);
+    assert_eq!(scrape("freeswitch_media_rtp_sessions_active"), rtp + 1.0);
+
+    binder.fire(fsr::event_types::CHANNEL_DESTROY, None, &[("Unique-ID", "srtp")]);
+    binder.fire(fsr::event_types::CHANNEL_DESTROY, None, &[("Unique-ID", "rtp")]);
+    binder.fire(fsr::event_types::CHANNEL_DESTROY, None, &[("Unique-ID", "loopback")]);
+    assert_eq!(scrape("freeswitch_media_srtp_sessions_active"), srtp);
+    assert_eq!(scrape("freeswitch_media_rtp_sessions_active"), rtp);
+}
+
+#[test]
+fn bridged_call_counted_once() {
+    let (_guard, binder) = setup();
+    let calls = scrape("freeswitch_calls_total");
+    let b_legs = scrape("freeswitch_sessions_b_leg_total");
+    let bridges = scrape("freeswitch_bridges_total");
+    let active = scrape("freeswitch_calls_active");
+    let channels = scrape("freeswitch_channels_active");
+    let bridged = scrape("freeswitch_bridges_active");
+
+    binder.fire(fsr::event_types::CHANNEL_CREATE, None, &[("Call-Direction", "inbound"), ("Unique-ID", "a")]);
+    binder.fire(fsr::event_types::CHANNEL_CREATE, None, &[("Call-Direction", "outbound"), ("Unique-ID", "b"),
+                                                         ("Other-Leg-Unique-ID", "a"), ("Other-Type", "originator")]);
+    binder.fire(fsr::event_types::CHANNEL_BRIDGE, None, &[("Unique-ID", "a"), ("Other-Leg-Unique-ID", "b")]);
+    assert_eq!(scrape("freeswitch_calls_total"), calls + 1.0);
+    assert_eq!(scrape("freeswitch_sessions_b_leg_total"), b_legs + 1.0);
+    assert_eq!(scrape("freeswitch_bridges_total"), bridges + 1.0);
+    assert_eq!(scrape("freeswitch_calls_active"), active + 1.0);
+    assert_eq!(scrape("freeswitch_channels_active"), channels + 2.0);
+    assert_eq!(scrape("freeswitch_bridges_active"), bridged + 1.0);
+
+    binder.fire(fsr::event_types::CHANNEL_UNBRIDGE, None, &[("Unique-ID", "a"), ("Other-Leg-Unique-ID", "b")]);
+    binder.fire(fsr::event_types::CHANNEL_DESTROY, None, &[("Call-Direction", "outbound"), ("Unique-ID", "b"),
+                                                          ("Other-Leg-Unique-ID", "a"), ("Other-Type", "originator")]);
+    binder.fire(fsr::event_types::CHANNEL_DESTROY, None, &[("Call-Direction", "inbound"), ("Unique-ID", "a"),
+                                                          ("Other-Leg-Unique-ID", "b"), ("Other-Type", "originatee")]);
+    assert_eq!(scrape("freeswitch_calls_active"), active);
+    assert_eq!(scrape("freeswitch_channels_active"), channels);
+    assert_eq!(scrape("freeswitch_bridges_active"), bridged);
+}
+
+#[test]
+fn call_setup_histograms() {
+    let (_guard, binder) = setup();
+    let to_ring = scrape("freeswitch_sessions_outbound_time_to_ring_seconds_count");
+    let ring = scrape("freeswitch_sessions_outbound_ring_seconds_sum");
+    let early = scrape("freeswitch_sessions_outbound_early_media_seconds_count");
+
+    let callstate = |state: &str, original: &str, time: &str| {
+        binder.fire(fsr::event_types::CHANNEL_CALLSTATE, None, &[("Unique-ID", "setup"), ("Call-Direction", "outbound"),
+                                                                ("Channel-Call-State", state),
+                                                                ("Original-Channel-Call-State", original),
+                                                                ("Caller-Channel-Created-Time", "1500000000000000"),
+                                                                ("Event-Date-Timestamp", time)]);
+    };
+    callstate("RINGING", "DOWN", "1500000000500000");
+    callstate("EARLY", "RINGING", "1500000002500000");
+    callstate("ACTIVE", "EARLY", "1500000003000000");
+    callstate("HANGUP", "ACTIVE", "1500000063000000");
+    assert_eq!(scrape("freeswitch_sessions_outbound_time_to_ring_seconds_count"), to_ring + 1.0);
+    assert_eq!(scrape("freeswitch_sessions_outbound_ring_seconds_sum"), ring + 2.0);
+    assert_eq!(scrape("freeswitch_sessions_outbound_early_media_seconds_count"), early + 1.0);
+}
+
+#[test]
+fn early_media_answer() {
+    let (_guard, binder) = setup();
+    let progress = scrape("freeswitch_sessions_outbound_183_total");
+    let early = scrape("freeswitch_sessions_outbound_answered_early_media_total");
+    let direct = scrape("freeswitch_sessions_outbound_answered_direct_total");
+
+    binder.fire(fsr::event_types::CHANNEL_PROGRESS_MEDIA, None, &[("Call-Direction", "outbound")]);
+    binder.fire(fsr::event_types::CHANNEL_ANSWER, None, &[("Call-Direction", "outbound"),
+                                                         ("Caller-Channel-Progress-Media-Time", "1500000000000000")]);
+    binder.fire(fsr::event_types::CHANNEL_ANSWER, None, &[("Call-Direction", "outbound"),
+                                                         ("Caller-Channel-Progress-Media-Time", "0")]);
+    assert_eq!(scrape("freeswitch_sessions_outbound_183_total"), progress + 1.0);
+    assert_eq!(scrape("freeswitch_sessions_outbound_answered_early_media_total"), early + 1.0);
+    assert_eq!(scrape("freeswitch_sessions_outbound_answered_direct_total"), direct + 1.0);
+}
+
+#[test]
+fn outbound_sip_responses() {
+    let (_guard, binder) = setup();
+    binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &[("Call-Direction", "outbound"),
+                                                                   ("variable_sip_gateway_name", "carrier-2"),
+                                                                   ("variable_sip_invite_failure_status", "503")]);
+    binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &[("Call-Direction", "inbound"),
+                                                                   ("variable_sip_term_status", "503")]);
+    binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &[("Call-Direction", "outbound"),
+                                                                   ("variable_sip_term_status", "403")]);
+    assert_eq!(scrape("freeswitch_outbound_sip_responses_total{code=\"503\",gateway=\"carrier-2\"}"), 1.0);
+    assert_eq!(scrape("freeswitch_outbound_sip_responses_total{code=\"403\",gateway=\"\"}"), 1.0);
+}
+
+#[test]
+fn transcoding_session() {
+    let (_guard, binder) = setup();
+    let active = scrape("freeswitch_transcoding_sessions_active");
+    let codecs = [("Unique-ID", "transcoding"), ("Channel-Read-Codec-Name", "PCMU"), ("Channel-Write-Codec-Name", "opus")];
+
+    binder.fire(fsr::event_types::CODEC, None, &codecs);
+    binder.fire(fsr::event_types::CODEC, None, &codecs);
+    assert_eq!(scrape("freeswitch_transcoding_sessions_active"), active + 1.0);
+    assert_eq!(scrape("freeswitch_transcoding_sessions_total{from=\"PCMU\",to=\"opus\"}"), 1.0);
+
+    binder.fire(fsr::event_types::CHANNEL_DESTROY, None, &[("Unique-ID", "transcoding")]);
+    assert_eq!(scrape("freeswitch_transcoding_sessions_active"), active);
+}
+
+#[test]
+fn recording_completed() {
+    let (_guard, binder) = setup();
+    let active = scrape("freeswitch_recordings_active");
+
+    binder.fire(fsr::event_types::RECORD_START, None, &[("Record-File-Path", "/tmp/test.wav")]);
+    assert_eq!(scrape("freeswitch_recordings_active"), active + 1.0);
+    binder.fire(fsr::event_types::RECORD_STOP, None, &[("Record-File-Path", "/tmp/test.wav"),
+                                                       ("Record-Completion-Cause", "success-maxtime")]);
+    assert_eq!(scrape("freeswitch_recordings_active"), active);
+    assert_eq!(scrape("freeswitch_recordings_completed_total{cause=\"success-maxtime\"}"), 1.0);
+}
+
+#[test]
+fn attended_transfer_failed() {
+    let (_guard, binder) = setup();
+    binder.fire(fsr::event_types::CHANNEL_EXECUTE_COMPLETE, None, &[("Application", "att_xfer"),
+                                                                    ("variable_originate_disposition", "USER_BUSY")]);
+    binder.fire(fsr::event_types::CHANNEL_EXECUTE_COMPLETE, None, &[("Application", "answer")]);
+    assert_eq!(scrape("freeswitch_transfers_total{kind=\"attended\",result=\"failure\"}"), 1.0);
+}
+
+#[test]
+fn valet_parked_call() {
+    let (_guard, binder) = setup();
+    let parked = scrape("freeswitch_valet_park_seconds_sum");
+
+    binder.fire(fsr::event_types::CUSTOM, Some("mod_valet_parking::info"), &[("Unique-ID", "parked"), ("Action", "hold"),
+                                                                           ("Valet-Lot-Name", "lobby"),
+                                                                           ("Event-Date-Timestamp", "1500000000000000")]);
+    assert_eq!(scrape("freeswitch_valet_parked{lot=\"lobby\"}"), 1.0);
+    binder.fire(fsr::event_types::CUSTOM, Some("mod_valet_parking::info"), &[("Unique-ID", "parked"), ("Action", "bridge"),
+                                                                           ("Valet-Lot-Name", "lobby"),
+                                                                           ("Event-Date-Timestamp", "1500000090000000")]);
+    assert_eq!(scrape("freeswitch_valet_parked{lot=\"lobby\"}"), 0.0);
+    assert_eq!(scrape("freeswitch_valet_park_seconds_sum"), parked + 90.0);
+}
+
+#[test]
+fn background_job_timed() {
+    let (_guard, binder) = setup();
+    let jobs = scrape("freeswitch_bgapi_job_seconds_count");
+    let time = scrape("freeswitch_bgapi_job_seconds_sum");
+
+    // Timed from the bgapi command queuing it
+    binder.fire(fsr::event_types::API, None, &[("API-Command", "bgapi"),
+                                               ("API-Command-Argument", "originate user/1000 &park"),
+                                               ("Event-Date-Timestamp", "1500000000000000")]);
+    binder.fire(fsr::event_types::BACKGROUND_JOB, None, &[("Job-UUID", "5ba9a3b4-1c2f-4f1d-9e0a-6f1d2c3b4a5e"),
+                                                         ("Job-Command", "originate"),
+                                                         ("Job-Command-Arg", "user/1000 &park"),
+                                                         ("Event-Date-Timestamp", "1500000002500000")]);
+    // Queued otherwise (e.g. ESL bgapi), counted but not timed
+    binder.fire(fsr::event_types::BACKGROUND_JOB, None, &[("Job-UUID", "bb22c000-683d-11e7-8000-000000000000"),
+                                                         ("Job-Command", "originate"),
+                                                         ("Job-Command-Arg", "user/1000 &park")]);
+    assert_eq!(scrape("freeswitch_bgapi_jobs_total{command=\"originate\"}"), 2.0);
+    assert_eq!(scrape("freeswitch_bgapi_job_seconds_count"), jobs + 1.0);
+    assert_eq!(scrape("freeswitch_bgapi_job_seconds_sum"), time + 2.5);
+}
+
+#[test]
+fn scheduler_tasks() {
+    let (_guard, binder) = setup();
+    let tasks = scrape("freeswitch_scheduler_tasks");
+
+    binder.fire(fsr::event_types::ADD_SCHEDULE, None, &[("Task-ID", "1"), ("Task-Desc", "heartbeat")]);
+    binder.fire(fsr::event_types::ADD_SCHEDULE, None, &[("Task-ID", "2"), ("Task-Desc", "sched_hangup")]);
+    assert_eq!(scrape("freeswitch_scheduler_tasks"), tasks + 2.0);
+    binder.fire(fsr::event_types::EXE_SCHEDULE, None, &[("Task-ID", "1")]);
+    binder.fire(fsr::event_types::RE_SCHEDULE, None, &[("Task-ID", "1")]);
+    binder.fire(fsr::event_types::EXE_SCHEDULE, None, &[("Task-ID", "2")]);
+    assert_eq!(scrape("freeswitch_scheduler_tasks"), tasks + 1.0);
+    binder.fire(fsr::event_types::DEL_SCHEDULE, None, &[("Task-ID", "1")]);
+    assert_eq!(scrape("freeswitch_scheduler_tasks"), tasks);
+}
+
+#[test]
+fn module_reloaded() {
+    let (_guard, binder) = setup();
+    let loaded = scrape("freeswitch_modules_loaded");
+
+    binder.fire(fsr::event_types::MODULE_UNLOAD, None, &[("type", "endpoint"), ("key", "mod_sofia")]);
+    binder.fire(fsr::event_types::MODULE_UNLOAD, None, &[("type", "api"), ("key", "mod_sofia")]);
+    assert_eq!(scrape("freeswitch_modules_unloads_total{module=\"mod_sofia\"}"), 1.0);
+    binder.fire(fsr::event_types::MODULE_LOAD, None, &[("type", "endpoint"), ("key", "mod_sofia")]);
+    binder.fire(fsr::event_types::MODULE_LOAD, None, &[("type", "api"), ("key", "mod_sofia")]);
+    assert_eq!(scrape("freeswitch_modules_loads_total{module=\"mod_sofia\"}"), 1.0);
+    assert_eq!(scrape("freeswitch_modules_loaded"), loaded + 1.0);
+}
+
+#[test]
+fn media_timeout() {
+    let (_guard, binder) = setup();
+    binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &[("variable_sofia_profile_name", "external"),
+                                                                   ("Hangup-Cause", "MEDIA_TIMEOUT"),
+                                                                   ("Caller-Channel-Answered-Time", "1500000000000000"),
+                                                                   ("variable_rtp_audio_in_media_packet_count", "0")]);
+    assert_eq!(scrape("freeswitch_sofia_media_timeouts_total{profile=\"external\"}"), 1.0);
+    assert_eq!(scrape("freeswitch_sofia_rtp_no_media_sessions_total{profile=\"external\"}"), 1.0);
+}
+
+#[test]
+fn nat_registration() {
+    let (_guard, binder) = setup();
+    let nat = scrape("freeswitch_registrations_nat_total");
+    let rewrites = scrape("freeswitch_registrations_contact_rewrites_total");
+
+    binder.fire(fsr::event_types::CUSTOM, Some("sofia::register"), &[("status", "Registered(UDP-NAT)"),
+                                                                     ("contact", "\"1000\" <sip:1000@192.168.1.10:5060;fs_nat=yes>"),
+                                                                     ("network-ip", "203.0.113.7"),
+                                                                     ("network-port", "40312")]);
+    binder.fire(fsr::event_types::CUSTOM, Some("sofia::register"), &[("status", "Registered(UDP)"),
+                                                                     ("contact", "\"1001\" <sip:1001@203.0.113.8:5060>"),
+                                                                     ("network-ip", "203.0.113.8"),
+                                                                     ("network-port", "5060")]);
+    assert_eq!(scrape("freeswitch_registrations_nat_total"), nat + 1.0);
+    assert_eq!(scrape("freeswitch_registrations_contact_rewrites_total"), rewrites + 1.0);
+}
+
+#[test]
+fn tenant_sessions() {
+    let (_guard, binder) = setup();
+    set_config(Config { tenant_headers: vec!["variable_domain_name".to_string()], max_tenants: 1, ..Config::default() });
+
+    binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &[("variable_domain_name", "acme.example.com"),
+                                                                   ("Caller-Channel-Answered-Time", "1500000000000000"),
+                                                                   ("variable_billsec", "60")]);
+    binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &[("variable_domain_name", "globex.example.com"),
+                                                                   ("Caller-Channel-Answered-Time", "0")]);
+    set_config(Config::default());
+
+    assert_eq!(scrape("freeswitch_tenant_sessions_answered_total{tenant=\"acme.example.com\"}"), 1.0);
+    assert_eq!(scrape("freeswitch_tenant_sessions_duration_seconds_total{tenant=\"acme.example.com\"}"), 60.0);
+    assert_eq!(scrape("freeswitch_tenant_sessions_failed_total{tenant=\"other\"}"), 1.0);
+}
+
+#[test]
+fn accountcode_billsec() {
+    let (_guard, binder) = setup();
+    set_config(Config { billsec_histograms: true, ..Config::default() });
+
+    binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &[("variable_accountcode", "1234"),
+                                                                   ("Caller-Channel-Answered-Time", "1500000000000000"),
+                                                                   ("variable_billsec", "75")]);
+    set_config(Config::default());
+
+    assert_eq!(scrape("freeswitch_accountcode_billsec_seconds_sum{accountcode=\"1234\"}"), 75.0);
+    assert_eq!(scrape("freeswitch_accountcode_billsec_seconds_bucket{accountcode=\"1234\",le=\"60\"}"), 0.0);
+    assert_eq!(scrape("freeswitch_accountcode_billsec_seconds_bucket{accountcode=\"1234\",le=\"120\"}"), 1.0);
+}
+
+#[test]
+fn answer_delay_histogram() {
+    let (_guard, binder) = setup();
+    let count = scrape("freeswitch_sessions_inbound_answer_delay_seconds_count");
+    let sum = scrape("freeswitch_sessions_inbound_answer_delay_seconds_sum");
+
+    binder.fire(fsr::event_types::CHANNEL_ANSWER, None, &[("Call-Direction", "inbound"),
+                                                         ("Caller-Channel-Created-Time", "1500000000000000"),
+                                                         ("Caller-Channel-Answered-Time", "1500000004500000")]);
+    assert_eq!(scrape("freeswitch_sessions_inbound_answer_delay_seconds_count"), count + 1.0);
+    assert_eq!(scrape("freeswitch_sessions_inbound_answer_delay_seconds_sum"), sum + 4.5);
+}
+
+#[test]
+fn openmetrics_families_unique() {
+    let (_guard, _binder) = setup();
+    let filter = Filter { openmetrics: true, ..Filter::default() };
+    let out = STATE.lock().unwrap().as_ref().unwrap().registry.lock().unwrap().render_filtered(&filter);
+    let mut families: Vec<&str> = out.lines().filter_map(|l| l.strip_prefix("# TYPE "))
+                                     .filter_map(|l| l.split(' ').next()).collect();
+    let count = families.len();
+    families.sort_unstable();
+    families.dedup();
+    assert_eq!(families.len(), count, "duplicate families in:\n{}", out);
+}
+
+#[test]
+fn answer_delay_exemplar() {
+    let (_guard, binder) = setup();
+    let answer = |uuid: &str| {
+        binder.fire(fsr::event_types::CHANNEL_ANSWER, None, &[("Call-Direction", "outbound"), ("Unique-ID", uuid),
+                                                             ("variable_sip_call_id", "abc@192.0.2.10"),
+                                                             ("Caller-Channel-Created-Time", "1500000000000000"),
+                                                             ("Caller-Channel-Answered-Time", "1500000000700000")]);
+    };
+    answer("no-exemplar");
+    set_config(Config { exemplars: true, ..Config::default() });
+    answer("exemplar");
+    set_config(Config::default());
+    for uuid in ["no-exemplar", "exemplar"].iter() {
+        binder.fire(fsr::event_types::CHANNEL_DESTROY, None, &[("Unique-ID", uuid)]);
+    }
+
+    let filter = Filter { openmetrics: true, ..Filter::default() };
+    let out = STATE.lock().unwrap().as_ref().unwrap().registry.lock().unwrap().render_filtered(&filter);
+    let bucket = out.lines().find(|l| l.starts_with("freeswitch_sessions_outbound_answer_delay_seconds_bucket{le=\"1\"}"))
+                    .unwrap_or_else(|| panic!("bucket missing in:\n{}", out));
+    assert!(bucket.contains(" # {call_uuid=\"exemplar\",sip_call_id=\"abc@192.0.2.10\"} 0.7 "), "{}", bucket);
+    // Only the OpenMetrics format has exemplars
+    let out = STATE.lock().unwrap().as_ref().unwrap().registry.lock().unwrap().render();
+    assert!(!out.contains("call_uuid"));
+}
+
+#[test]
+fn sessions_per_second() {
+    let (_guard, binder) = setup();
+    events::sessions_rate_update();
+    let rate = scrape("freeswitch_sessions_per_second_outbound");
+    let calls = scrape("freeswitch_calls_outbound_total");
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let timestamp = (now.as_secs() * 1_000_000).to_string();
+    for _ in 0..5 {
+        binder.fire(fsr::event_types::CHANNEL_CREATE, None, &[("Call-Direction", "outbound"),
+                                                             ("Event-Date-Timestamp", &timestamp)]);
+    }
+    // An old session is out of the window
+    binder.fire(fsr::event_types::CHANNEL_CREATE, None, &[("Call-Direction", "outbound"),
+                                                         ("Event-Date-Timestamp", "1500000000000000")]);
+    events::sessions_rate_update();
+    assert_eq!(scrape("freeswitch_sessions_per_second_outbound"), rate + 0.5);
+    assert_eq!(scrape("freeswitch_calls_outbound_total"), calls + 6.0);
+}
+
+#[test]
+fn sessions_peak() {
+    let (_guard, binder) = setup();
+    metrics::sessions_peak_reset();
+    let peak = scrape("freeswitch_sessions_peak{direction=\"inbound\"}");
+
+    for _ in 0..2 {
+        binder.fire(fsr::event_types::CHANNEL_CREATE, None, &[("Call-Direction", "inbound")]);
+    }
+    for _ in 0..2 {
+        binder.fire(fsr::event_types::CHANNEL_DESTROY, None, &[("Call-Direction", "inbound")]);
+    }
+    assert_eq!(scrape("freeswitch_sessions_peak{direction=\"inbound\"}"), peak + 2.0);
+    metrics::sessions_peak_reset();
+    assert_eq!(scrape("freeswitch_sessions_peak{direction=\"inbound\"}"), scrape("freeswitch_sessions_active_inbound"));
+}
+
+#[test]
+fn abandoned_calls() {
+    let (_guard, binder) = setup();
+    let abandoned = scrape("freeswitch_inbound_abandoned_total");
+
+    let hangup = |uuid: &str, cause: &str, progress: &str| {
+        binder.fire(fsr::event_types::CHANNEL_CREATE, None, &[("Unique-ID", uuid), ("Call-Direction", "inbound")]);
+        binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &[("Unique-ID", uuid), ("Call-Direction", "inbound"),
+                                                                       ("Hangup-Cause", cause),
+                                                                       ("Caller-Channel-Answered-Time", "0"),
+                                                                       ("Caller-Channel-Progress-Time", progress)]);
+    };
+    hangup("abandoned", "ORIGINATOR_CANCEL", "1500000000000000");
+    hangup("not-ringing", "ORIGINATOR_CANCEL", "0");
+    hangup("rejected", "CALL_REJECTED", "1500000000000000");
+    assert_eq!(scrape("freeswitch_inbound_abandoned_total"), abandoned + 1.0);
+    let rate = scrape("freeswitch_inbound_abandonment_rate");
+    assert!(rate > 0.0 && rate <= 1.0);
+}
+
+#[test]
+fn short_and_long_calls() {
+    let (_guard, binder) = setup();
+    let hangup = |billsec: &str| {
+        binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &[("Call-Direction", "outbound"),
+                                                                       ("variable_sip_gateway_name", "carrier"),
+                                                                       ("Caller-Channel-Answered-Time", "1500000000000000"),
+                                                                       ("variable_billsec", billsec)]);
+    };
+    hangup("2");
+    hangup("60");
+    hangup("20000");
+
+    assert_eq!(scrape("freeswitch_sessions_unusual_length_calls_total{direction=\"outbound\",length=\"short\"}"), 1.0);
+    assert_eq!(scrape("freeswitch_sessions_unusual_length_calls_total{direction=\"outbound\",length=\"long\"}"), 1.0);
+    assert_eq!(scrape("freeswitch_gateway_unusual_length_calls_total{gateway=\"carrier\",length=\"short\"}"), 1.0);
+    assert_eq!(scrape("freeswitch_gateway_unusual_length_calls_total{gateway=\"carrier\",length=\"long\"}"), 1.0);
+}
+
+#[test]
+fn fraud_signals() {
+    let (_guard, binder) = setup();
+    set_config(Config { fraud_prefixes: vec!["00882".to_string()], fraud_max_user_calls: 1,
+                        business_hours: Some((8, 20)), ..Config::default() });
+
+    let outbound = |destination: &str, time: &str| {
+        binder.fire(fsr::event_types::CHANNEL_CREATE, None, &[("Call-Direction", "outbound"),
+                                                             ("Caller-Destination-Number", destination),
+                                                             ("Event-Date-Local", time)]);
+    };
+    outbound("0088212345", "2020-01-01 12:00:00");
+    outbound("0044123456", "2020-01-01 23:30:00");
+    outbound("0044123456", "2020-01-01 10:00:00");
+    outbound("0123456789", "2020-01-01 23:30:00");
+    for uuid in ["fraud-1", "fraud-2"].iter() {
+        binder.fire(fsr::event_types::CHANNEL_CREATE, None, &[("Call-Direction", "inbound"), ("Unique-ID", uuid),
+                                                             ("Caller-Username", "1000")]);
+    }
+    binder.fire(fsr::event_types::CHANNEL_DESTROY, None, &[("Call-Direction", "inbound"), ("Unique-ID", "fraud-2"),
+                                                          ("Caller-Username", "1000")]);
+    binder.fire(fsr::event_types::CHANNEL_CREATE, None, &[("Call-Direction", "inbound"), ("Unique-ID", "fraud-3"),
+                                                         ("Caller-Username", "1000")]);
+    set_config(Config::default());
+
+    assert_eq!(scrape("freeswitch_fraud_signals_total{signal=\"high_rate_destination\"}"), 1.0);
+    assert_eq!(scrape("freeswitch_fraud_signals_total{signal=\"after_hours_international\"}"), 1.0);
+    assert_eq!(scrape("freeswitch_fraud_signals_total{signal=\"user_concurrent_calls\"}"), 2.0);
+}
+
+#[test]
+fn route_groups() {
+    let (_guard, binder) = setup();
+    set_config(Config { routes: vec![("mobile".to_string(), vec!["336".to_string()]),
+                                     ("intl".to_string(), vec!["00".to_string(), "33".to_string()])],
+                        ..Config::default() });
+
+    let call = |uuid: &str, destination: &str, answer: bool| {
+        binder.fire(fsr::event_types::CHANNEL_CREATE, None, &[("Unique-ID", uuid), ("Caller-Destination-Number", destination)]);
+        let answered = if answer {
+            binder.fire(fsr::event_types::CHANNEL_ANSWER, None, &[("Unique-ID", uuid)]);
+            "1500000000000000"
+        } else {
+            "0"
+        };
+        binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &[("Unique-ID", uuid),
+                                                                       ("Caller-Channel-Answered-Time", answered),
+                                                                       ("variable_billsec", "30")]);
+    };
+    call("route-1", "33612345678", true);
+    call("route-2", "33145678901", false);
+    call("route-3", "911", false);
+    set_config(Config::default());
+
+    assert_eq!(scrape("freeswitch_route_created_total{route=\"mobile\"}"), 1.0);
+    assert_eq!(scrape("freeswitch_route_answered_total{route=\"mobile\"}"), 1.0);
+    assert_eq!(scrape("freeswitch_route_duration_seconds_total{route=\"mobile\"}"), 30.0);
+    assert_eq!(scrape("freeswitch_route_created_total{route=\"intl\"}"), 1.0);
+    assert_eq!(scrape("freeswitch_route_created_total{route=\"other\"}"), 1.0);
+}
+
+#[test]
+fn country_buckets() {
+    let (_guard, binder) = setup();
+    set_config(Config { countries: vec![("fr".to_string(), vec!["33".to_string(), "+33".to_string()]),
+                                        ("us".to_string(), vec!["1".to_string()])],
+                        ..Config::default() });
+
+    binder.fire(fsr::event_types::CHANNEL_CREATE, None, &[("Caller-Caller-ID-Number", "+33612345678"),
+                                                         ("Caller-Destination-Number", "12125551234")]);
+    binder.fire(fsr::event_types::CHANNEL_CREATE, None, &[("Caller-Caller-ID-Number", "anonymous"),
+                                                         ("Caller-Destination-Number", "33145678901")]);
+    set_config(Config::default());
+
+    assert_eq!(scrape("freeswitch_country_calls_total{country=\"fr\",result=\"calling\"}"), 1.0);
+    assert_eq!(scrape("freeswitch_country_calls_total{country=\"other\",result=\"calling\"}"), 1.0);
+    assert_eq!(scrape("freeswitch_country_calls_total{country=\"us\",result=\"called\"}"), 1.0);
+    assert_eq!(scrape("freeswitch_country_calls_total{country=\"fr\",result=\"called\"}"), 1.0);
+}
+
+#[test]
+fn jitter_buffer_histograms() {
+    let (_guard, binder) = setup();
+    let size = scrape("freeswitch_sessions_inbound_jitter_buffer_size_packets_count");
+    let drops = scrape("freeswitch_sessions_inbound_jitter_buffer_dropped_packets_sum");
+
+    binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &[("Call-Direction", "inbound"),
+                                                                   ("variable_rtp_audio_in_jitter_packet_count", "1500"),
+                                                                   ("variable_rtp_audio_in_largest_jb_size", "8"),
+                                                                   ("variable_rtp_audio_in_skip_packet_count", "12")]);
+    binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &[("Call-Direction", "inbound"),
+                                                                   ("variable_rtp_audio_in_jitter_packet_count", "0"),
+                                                                   ("variable_rtp_audio_in_largest_jb_size", "0")]);
+    assert_eq!(scrape("freeswitch_sessions_inbound_jitter_buffer_size_packets_count"), size + 1.0);
+    assert_eq!(scrape("freeswitch_sessions_inbound_jitter_buffer_dropped_packets_sum"), drops + 12.0);
+}
+
+#[test]
+fn rtcp_reports() {
+    let (_guard, binder) = setup();
+    let report = |uuid: &str, fraction: &str, jitter: &str| {
+        binder.fire(fsr::event_types::RECV_RTCP_MESSAGE, None, &[("Unique-ID", uuid), ("Source0-Fraction", fraction),
+                                                                ("Source0-Jitter", jitter), ("RTP-Rate", "8000")]);
+    };
+    report("rtcp-1", "0", "80");
+    report("rtcp-2", "64", "240");
+    events::rtcp_update();
+    assert_eq!(scrape("freeswitch_rtcp_sessions"), 2.0);
+    assert_eq!(scrape("freeswitch_rtcp_loss_fraction_max"), 0.25);
+    assert_eq!(scrape("freeswitch_rtcp_jitter_seconds_avg"), 0.02);
+
+    binder.fire(fsr::event_types::CHANNEL_DESTROY, None, &[("Unique-ID", "rtcp-2")]);
+    binder.fire(fsr::event_types::CHANNEL_DESTROY, None, &[("Unique-ID", "rtcp-1")]);
+    events::rtcp_update();
+    assert_eq!(scrape("freeswitch_rtcp_sessions"), 0.0);
+    assert_eq!(scrape("freeswitch_rtcp_loss_fraction_max"), 0.0);
+}
+
+#[test]
+fn limit_refusals() {
+    let (_guard, binder) = setup();
+    let refused = scrape("freeswitch_core_sessions_refused_total");
+
+    binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &[("Unique-ID", "refused-1"),
+                                                                   ("Hangup-Cause", "SWITCH_CONGESTION")]);
+    binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &[("Unique-ID", "refused-2"),
+                                                                   ("Hangup-Cause", "NORMAL_TEMPORARY_FAILURE"),
+                                                                   ("variable_originate_disposition", "SWITCH_CONGESTION")]);
+    binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &[("Unique-ID", "refused-3"),
+                                                                   ("Hangup-Cause", "USER_BUSY")]);
+
+    assert_eq!(scrape("freeswitch_core_sessions_refused_total"), refused + 2.0);
+}
+
+#[test]
+fn remote_network_quality() {
+    let (_guard, binder) = setup();
+    set_config(Config { remote_network_histograms: true, ..Config::default() });
+
+    for &(ip, mos) in [("198.51.100.7", "4.1"), ("198.51.100.200", "2.4"), ("2001:db8:1:2::1", "4.4")].iter() {
+        binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &[("variable_remote_media_ip", ip),
+                                                                       ("variable_rtp_audio_in_mos", mos),
+                                                                       ("variable_rtp_audio_in_jitter_loss_rate", "2")]);
+    }
+    set_config(Config::default());
+
+    assert_eq!(scrape("freeswitch_remote_network_mos_count{network=\"198.51.100.0/24\"}"), 2.0);
+    assert_eq!(scrape("freeswitch_remote_network_mos_bucket{network=\"198.51.100.0/24\",le=\"2.5\"}"), 1.0);
+    assert_eq!(scrape("freeswitch_remote_network_loss_ratio_sum{network=\"198.51.100.0/24\"}"), 0.04);
+    assert_eq!(scrape("freeswitch_remote_network_mos_count{network=\"2001:db8:1::/48\"}"), 1.0);
+}
+
+#[test]
+fn queue_wait_histograms() {
+    let (_guard, binder) = setup();
+    set_config(Config { queue_variable: Some("queue_name".to_string()),
+                        queue_start_variable: Some("queue_start".to_string()), ..Config::default() });
+
+    binder.fire(fsr::event_types::CUSTOM, Some("callcenter::info"), &[("CC-Queue", "support"),
+                                                                     ("CC-Action", "bridge-agent-start"),
+                                                                     ("CC-Member-Joined-Time", "1500000000"),
+                                                                     ("CC-Agent-Answered-Time", "1500000025")]);
+    binder.fire(fsr::event_types::CUSTOM, Some("callcenter::info"), &[("CC-Queue", "support"),
+                                                                     ("CC-Action", "member-queue-end"),
+                                                                     ("CC-Cause", "Cancel"),
+                                                                     ("CC-Member-Joined-Time", "1500000000"),
+                                                                     ("CC-Member-Leaving-Time", "1500000200")]);
+    binder.fire(fsr::event_types::CHANNEL_BRIDGE, None, &[("variable_queue_name", "sales"),
+                                                         ("variable_queue_start", "1500000000"),
+                                                         ("Event-Date-Timestamp", "1500000045000000")]);
+    binder.fire(fsr::event_types::CHANNEL_BRIDGE, None, &[("Caller-Channel-Answered-Time", "1500000000000000"),
+                                                         ("Event-Date-Timestamp", "1500000045000000")]);
+    set_config(Config::default());
+
+    assert_eq!(scrape("freeswitch_queue_wait_seconds_bucket{queue=\"support\",le=\"30\"}"), 1.0);
+    assert_eq!(scrape("freeswitch_queue_wait_seconds_sum{queue=\"support\"}"), 25.0);
+    assert_eq!(scrape("freeswitch_queue_abandoned_wait_seconds_sum{queue=\"support\"}"), 200.0);
+    assert_eq!(scrape("freeswitch_queue_wait_seconds_count{queue=\"sales\"}"), 1.0);
+    assert_eq!(scrape("freeswitch_queue_wait_seconds_sum{queue=\"sales\"}"), 45.0);
+}
+
+#[test]
+fn agent_state_time() {
+    let (_guard, binder) = setup();
+    let fire = |action: &str, header: (&str, &str), seconds: u64| {
+        let timestamp = ((1500000000 + seconds) * 1_000_000).to_string();
+        binder.fire(fsr::event_types::CUSTOM, Some("callcenter::info"), &[("CC-Action", action), ("CC-Agent", "1000"),
+                                                                         header, ("Event-Date-Timestamp", &timestamp)]);
+    };
+
+    fire("agent-status-change", ("CC-Agent-Status", "Available"), 0);
+    fire("agent-offering", ("CC-Queue", "support"), 10);
+    fire("agent-state-change", ("CC-Agent-State", "In a queue call"), 10);
+    fire("agent-state-change", ("CC-Agent-State", "Idle"), 70);
+    fire("agent-state-change", ("CC-Agent-State", "Waiting"), 100);
+    fire("agent-status-change", ("CC-Agent-Status", "On Break"), 120);
+    fire("agent-status-change", ("CC-Agent-Status", "Logged Out"), 420);
+    fire("agent-status-change", ("CC-Agent-Status", "Available"), 500);
+
+    assert_eq!(scrape("freeswitch_queue_agent_seconds_total{queue=\"unknown\",activity=\"available\"}"), 10.0);
+    assert_eq!(scrape("freeswitch_queue_agent_seconds_total{queue=\"support\",activity=\"on_call\"}"), 60.0);
+    assert_eq!(scrape("freeswitch_queue_agent_seconds_total{queue=\"support\",activity=\"wrap_up\"}"), 30.0);
+    assert_eq!(scrape("freeswitch_queue_agent_seconds_total{queue=\"support\",activity=\"available\"}"), 20.0);
+    assert_eq!(scrape("freeswitch_queue_agent_seconds_total{queue=\"support\",activity=\"break\"}"), 300.0);
+}
+
+#[test]
+fn billsec_milliseconds() {
+    let (_guard, binder) = setup();
+    set_config(Config { billsec_histograms: true, ..Config::default() });
+
+    binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &[("variable_accountcode", "ivr"),
+                                                                   ("Caller-Channel-Answered-Time", "1500000000000000"),
+                                                                   ("variable_billsec", "0"),
+                                                                   ("variable_billmsec", "450")]);
+    binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &[("variable_accountcode", "ivr"),
+                                                                   ("Caller-Channel-Answered-Time", "1500000000000000"),
+                                                                   ("Caller-Channel-Hangup-Time", "1500000002250000"),
+                                                                   ("variable_billsec", "2")]);
+    set_config(Config::default());
+
+    assert_eq!(scrape("freeswitch_accountcode_billsec_seconds_sum{accountcode=\"ivr\"}"), 2.7);
+    assert_eq!(scrape("freeswitch_accountcode_billsec_seconds_bucket{accountcode=\"ivr\",le=\"1\"}"), 1.0);
+}
+
+#[test]
+fn billsec_summary() {
+    let (_guard, binder) = setup();
+    set_config(Config { duration_summaries: true, summary_quantiles: vec![0.5, 1.0], ..Config::default() });
+
+    for billsec in ["10", "20", "30"].iter() {
+        binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &[("Call-Direction", "inbound"),
+                                                                       ("Caller-Channel-Answered-Time", "1500000000000000"),
+                                                                       ("variable_billsec", billsec)]);
+    }
+    let median = scrape("freeswitch_sessions_inbound_billsec_seconds{quantile=\"0.5\"}");
+    let max = scrape("freeswitch_sessions_inbound_billsec_seconds{quantile=\"1\"}");
+    set_config(Config::default());
+    assert_eq!(median, 20.0);
+    assert_eq!(max, 30.0);
+}
+
+#[test]
+fn alert_fires_after_hold() {
+    let mut alert = Alert::parse("low_asr", "freeswitch_asr < 0.3", Duration::from_secs(300)).unwrap();
+    let start = Instant::now();
+    let low: HashMap<String, f64> = vec![("freeswitch_asr".to_string(), 0.2)].into_iter().collect();
+    let high: HashMap<String, f64> = vec![("freeswitch_asr".to_string(), 0.5)].into_iter().collect();
+
+    assert_eq!(alert.check(&low, start), None);
+    assert_eq!(alert.check(&low, start + Duration::from_secs(299)), None);
+    assert_eq!(alert.check(&low, start + Duration::from_secs(300)), Some(true));
+    assert_eq!(alert.check(&low, start + Duration::from_secs(301)), None);
+    assert_eq!(alert.check(&high, start + Duration::from_secs(302)), Some(false));
+    assert_eq!(alert.check(&HashMap::new(), start + Duration::from_secs(303)), None);
+    assert!(Alert::parse("bad", "freeswitch_asr ~ 0.3", Duration::from_secs(0)).is_err());
+}
+
+#[test]
+fn metric_value_read_back() {
+    let (_guard, binder) = setup();
+    binder.fire(fsr::event_types::CHANNEL_CREATE, None, &[("Call-Direction", "outbound"), ("Unique-ID", "get-var-1")]);
+    let active = METRICS.sessions_active_outbound.lock().unwrap().value();
+
+    assert_eq!(api::metric_value("freeswitch_sessions_active_outbound"), Some(active));
+    assert_eq!(api::metric_value("freeswitch_no_such_metric"), None);
+    binder.fire(fsr::event_types::CHANNEL_DESTROY, None, &[("Call-Direction", "outbound"), ("Unique-ID", "get-var-1")]);
+}
+
+#[test]
+fn limit_usage_and_rejection() {
+    let (_guard, binder) = setup();
+    binder.fire(fsr::event_types::CUSTOM, Some("limit::usage"), &[("backend", "hash"), ("realm", "inbound"),
+                                                                  ("key", "gw1"), ("usage", "3"), ("max", "10")]);
+    binder.fire(fsr::event_types::CHANNEL_EXECUTE_COMPLETE, None, &[("Application", "limit"),
+                                                                    ("Application-Data", "hash inbound gw1 10"),
+                                                                    ("Caller-Destination-Number", "limit_exceeded")]);
+    binder.fire(fsr::event_types::CHANNEL_EXECUTE_COMPLETE, None, &[("Application", "limit"),
+                                                                    ("Application-Data", "hash inbound gw1 10"),
+                                                                    ("Caller-Destination-Number", "1000")]);
+
+    assert_eq!(scrape("freeswitch_limit_usage{realm=\"inbound\",resource=\"gw1\"}"), 3.0);
+    assert_eq!(scrape("freeswitch_limit_max{realm=\"inbound\",resource=\"gw1\"}"), 10.0);
+    assert_eq!(scrape("freeswitch_limit_rejected_total{realm=\"inbound\",resource=\"gw1\"}"), 1.0);
+}
+
+#[test]
+fn file_sd_registration() {
+    let path = ::std::env::temp_dir().join("mod_prometheus_test_sd.json").to_string_lossy().to_string();
+    let config = Config { sd_file: Some(path.clone()), sd_address: Some("media1:9282".to_string()), ..Config::default() };
+
+    discovery::register(&config, None);
+    let written = ::std::fs::read_to_string(&path).unwrap();
+    discovery::deregister();
+
+    assert_eq!(written, "[{\"targets\": [\"media1:9282\"], \"labels\": {\"service\": \"freeswitch\"}}]\n");
+    assert!(!::std::path::Path::new(&path).exists());
+}
+
+#[test]
+fn remote_config_fetched() {
+    use std::io::{Read, Write};
+    let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/fs/prometheus.conf.xml", listener.local_addr().unwrap());
+    let server = ::std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = [0; 1024];
+        let n = stream.read(&mut request).unwrap();
+        stream.write_all(b"HTTP/1.0 200 OK\r\n\r\n<configuration name=\"prometheus.conf\"/>").unwrap();
+        String::from_utf8_lossy(&request[..n]).into_owned()
+    });
+
+    assert_eq!(discovery::http_get(&url), Ok("<configuration name=\"prometheus.conf\"/>".to_string()));
+    assert!(server.join().unwrap().starts_with("GET /fs/prometheus.conf.xml HTTP/1.0\r\n"));
+    assert!(discovery::http_get("https://config.example.com/prometheus.conf.xml").is_err());
+}
+
+#[test]
+fn remote_config_bounded() {
+    use std::io::{Read, Write};
+    let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/prometheus.conf.xml", listener.local_addr().unwrap());
+    let server = ::std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = [0; 1024];
+        let _ = stream.read(&mut request).unwrap();
+        let _ = stream.write_all(b"HTTP/1.0 200 OK\r\n\r\n");
+        let _ = stream.write_all(&vec![b' '; 2 * 1024 * 1024]);
+    });
+
+    let fetched = discovery::http_get(&url);
+    assert!(fetched.as_ref().err().is_some_and(|e| e.starts_with("response larger than")), "{:?}", fetched.map(|b| b.len()));
+    server.join().unwrap();
+}
+
+#[test]
+fn config_from_env() {
+    let mut config = Config { tenant_headers: vec!["variable_domain_name".to_string()],
+                              disabled_bindings: vec!["heartbeat".to_string()], ..Config::default() };
+    let vars = vec![("MOD_PROMETHEUS_BINDADDR", "127.0.0.1:9300, [::1]"),
+                    ("MOD_PROMETHEUS_MAX_TENANTS", "10"),
+                    ("MOD_PROMETHEUS_TENANT_HEADER", "variable_accountcode"),
+                    ("MOD_PROMETHEUS_DISABLED_BINDINGS", "codec, sip_responses"),
+                    ("MOD_PROMETHEUS_EXEMPLARS", "Yes"),
+                    ("MOD_PROMETHEUS_ENABLE_HTTP_LISTENER", "0"),
+                    ("MOD_PROMETHEUS_PORT", "9999"),
+                    ("HOME", "/root")];
+    config.set_from_env(vars.into_iter().map(|(k, v)| (k.to_string(), v.to_string())));
+
+    assert_eq!(config.listen_addresses, vec![("127.0.0.1".to_string(), Some(9300)), ("::1".to_string(), None)]);
+    assert_eq!(config.max_tenants, 10);
+    assert_eq!(config.tenant_headers, vec!["variable_accountcode".to_string()]);
+    assert_eq!(config.disabled_bindings, vec!["codec".to_string(), "sip_responses".to_string()]);
+    assert!(config.exemplars);
+    assert!(!config.http_listener);
+}
+
+#[test]
+fn disabled_binding() {
+    let (_guard, _) = setup();
+    set_config(Config { disabled_bindings: vec!["heartbeat".to_string()], ..Config::default() });
+    let mut binder = TestBinder { handlers: Vec::new() };
+    events::bind_events(&mut binder);
+    set_config(Config::default());
+
+    let before = scrape("freeswitch_heartbeats_total");
+    binder.fire(fsr::event_types::HEARTBEAT, None, &[]);
+    assert_eq!(scrape("freeswitch_heartbeats_total"), before);
+    assert!(!binder.handlers.is_empty());
+}
+
+#[test]
+fn binding_events_counted() {
+    let (_guard, binder) = setup();
+    let processed = scrape("freeswitch_prometheus_events_total{binding=\"heartbeat\"}");
+
+    binder.fire(fsr::event_types::HEARTBEAT, None, &[]);
+    assert_eq!(scrape("freeswitch_prometheus_events_total{binding=\"heartbeat\"}"), processed + 1.0);
+}
+
+#[test]
+fn call_log_level() {
+    let (_guard, _) = setup();
+    assert_eq!(events::call_log_level(), fsr::log_level::DEBUG);
+    set_config(Config { call_log_level: config::parse_log_level("notice").unwrap(), ..Config::default() });
+    let level = events::call_log_level();
+    set_config(Config::default());
+
+    assert_eq!(level, fsr::log_level::NOTICE);
+    assert_eq!(config::parse_log_level("verbose"), None);
+}
+
+#[test]
+fn poisoned_lock_recovered() {
+    let m = Arc::new(Mutex::new(1));
+    let poisoner = m.clone();
+    let _ = ::std::thread::spawn(move || {
+        let _guard = poisoner.lock().unwrap();
+        panic!("poisoning the mutex");
+    }).join();
+
+    assert!(m.is_poisoned());
+    *crate::lock(&m) += 1;
+    assert_eq!(*crate::lock(&m), 2);
+}
+
+#[test]
+fn counter_decrement_rejected() {
+    let (_guard, _binder) = setup();
+    let misuse = scrape("freeswitch_prometheus_api_misuse_total");
+
+    assert_eq!(api::counter_increment("test_monotonic_total", 2.0), Ok(2.0));
+    assert!(api::counter_increment("test_monotonic_total", -1.0).is_err());
+    assert_eq!(scrape("test_monotonic_total"), 2.0);
+    assert_eq!(scrape("freeswitch_prometheus_api_misuse_total"), misuse + 1.0);
+}
+
+#[test]
+fn untyped_value() {
+    let (_guard, _binder) = setup();
+    metrics::value_get("test_trunk_balance").unwrap().lock().unwrap().set(1520.75);
+
+    assert_eq!(scrape("test_trunk_balance"), 1520.75);
+    let out = STATE.lock().unwrap().as_ref().unwrap().registry.lock().unwrap().render();
+    assert!(out.contains("# TYPE test_trunk_balance untyped\n"));
+}
+
+#[test]
+fn campaign_events() {
+    let (_guard, _binder) = setup();
+
+    assert_eq!(api::campaign_event("spring-sale attempt"), Ok(1.0));
+    assert_eq!(api::campaign_event("spring-sale attempt"), Ok(2.0));
+    assert_eq!(api::campaign_event("spring-sale amd_human"), Ok(1.0));
+    assert!(api::campaign_event("spring-sale voicemail").is_err());
+    assert!(api::campaign_event("attempt").is_err());
+
+    assert_eq!(scrape("freeswitch_campaign_events_total{campaign=\"spring-sale\",event=\"attempt\"}"), 2.0);
+    assert_eq!(scrape("freeswitch_campaign_events_total{campaign=\"spring-sale\",event=\"amd_human\"}"), 1.0);
+}
+
+#[test]
+fn amd_results() {
+    let (_guard, binder) = setup();
+    set_config(Config { campaign_variable: Some("campaign".to_string()), ..Config::default() });
+
+    binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &[("Unique-ID", "amd-1"), ("variable_campaign", "renewals"),
+                                                                   ("variable_amd_result", "MACHINE")]);
+    binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &[("Unique-ID", "amd-2"), ("variable_campaign", "renewals"),
+                                                                   ("variable_amd_result", "NOTSURE")]);
+    binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &[("Unique-ID", "amd-3"), ("variable_avmd_detect", "TRUE")]);
+    set_config(Config::default());
+
+    assert_eq!(scrape("freeswitch_campaign_events_total{campaign=\"renewals\",event=\"amd_machine\"}"), 1.0);
+    assert_eq!(scrape("freeswitch_campaign_events_total{campaign=\"renewals\",event=\"amd_unsure\"}"), 1.0);
+    assert_eq!(scrape("freeswitch_campaign_events_total{campaign=\"unknown\",event=\"amd_machine\"}"), 1.0);
+}
+
+#[test]
+fn batch_updates() {
+    let (_guard, _binder) = setup();
+
+    assert_eq!(api::batch("counter_increment test_batch_total 2; gauge_set test_batch_level 7;gauge_decrement test_batch_level"),
+               Ok(3));
+    assert_eq!(scrape("test_batch_total"), 2.0);
+    assert_eq!(scrape("test_batch_level"), 6.0);
+
+    // Nothing applied when any operation is invalid
+    assert!(api::batch("counter_increment test_batch_total; gauge_bump test_batch_level").is_err());
+    assert!(api::batch("gauge_set test_batch_level 1; counter_increment test-batch 1").is_err());
+    assert_eq!(scrape("test_batch_total"), 2.0);
+    assert_eq!(scrape("test_batch_level"), 6.0);
+
+    // Nor when the new metrics don't fit, without evicting the ones of the batch
+    set_config(Config { max_user_metrics: 2, evict_user_metrics: false, ..Config::default() });
+    assert!(api::batch("counter_increment test_batch_total; gauge_set test_batch_new 1").is_err());
+    set_config(Config { max_user_metrics: 2, evict_user_metrics: true, ..Config::default() });
+    assert!(api::batch("counter_increment test_batch_total; gauge_set test_batch_level 1; value_set test_batch_new 1").is_err());
+    set_config(Config::default());
+    assert_eq!(scrape("test_batch_total"), 2.0);
+    assert_eq!(scrape("test_batch_level"), 6.0);
+    assert_eq!(api::metric_value("test_batch_new"), None);
+}
+
+#[test]
+fn metric_update_event() {
+    let (_guard, binder) = setup();
+    let dropped = scrape("freeswitch_prometheus_events_dropped_total");
+
+    binder.fire(fsr::event_types::CUSTOM, Some("prometheus::update"),
+                &[("Metric-Name", "test_ivr_calls_total"), ("Metric-Op", "counter_increment"),
+                  ("Metric-Labels", "site=Paris,queue=sales")]);
+    binder.fire(fsr::event_types::CUSTOM, Some("prometheus::update"),
+                &[("Metric-Name", "test_ivr_waiting"), ("Metric-Op", "gauge_set"), ("Metric-Value", "4")]);
+    binder.fire(fsr::event_types::CUSTOM, Some("prometheus::update"),
+                &[("Metric-Name", "test_ivr_waiting"), ("Metric-Op", "gauge_bump")]);
+    // Headers can't smuggle in other operations
+    binder.fire(fsr::event_types::CUSTOM, Some("prometheus::update"),
+                &[("Metric-Name", "test_ivr_waiting"), ("Metric-Op", "gauge_set"),
+                  ("Metric-Value", "1; counter_increment test_ivr_injected_total")]);
+    assert_eq!(scrape("test_ivr_calls_total{queue=\"sales\",site=\"Paris\"}"), 1.0);
+    assert_eq!(scrape("test_ivr_waiting"), 4.0);
+    assert_eq!(api::metric_value("test_ivr_injected_total"), None);
+    assert_eq!(scrape("freeswitch_prometheus_events_dropped_total"), dropped + 2.0);
+}
+
+#[test]
+fn snapshot_event_headers() {
+    let (_guard, _binder) = setup();
+    let values = STATE.lock().unwrap().as_ref().unwrap().registry.lock().unwrap().values_with_derived();
+
+    let headers = alerts::snapshot_headers(&values, &Config::default().snapshot_metrics);
+    let names: Vec<&str> = headers.iter().map(|(n, _)| &n[..]).collect();
+    assert!(names.contains(&"freeswitch_sessions_active_inbound"));
+    assert!(names.contains(&"freeswitch_calls_active"));
+    assert!(!names.contains(&"freeswitch_playbacks_active"));
+}
+
+#[test]
+fn gauge_underflow() {
+    let (_guard, binder) = setup();
+    let active = scrape("freeswitch_playbacks_active");
+    let underflows = scrape("freeswitch_prometheus_gauge_underflow_total");
+
+    // One more playback stopped than started since load
+    for _ in 0..active as usize + 1 {
+        binder.fire(fsr::event_types::PLAYBACK_STOP, None, &[("Playback-Status", "done")]);
+    }
+    assert_eq!(scrape("freeswitch_playbacks_active"), 0.0);
+    assert_eq!(scrape("freeswitch_prometheus_gauge_underflow_total"), underflows + 1.0);
+}
+
+#[test]
+fn playback_languages() {
+    let (_guard, binder) = setup();
+    set_config(Config { max_sound_languages: 1, ..Config::default() });
+
+    for path in &["/usr/share/freeswitch/sounds/en/us/callie/ivr/8000/ivr-welcome.wav",
+                  "/usr/share/freeswitch/sounds/fr/ca/june/ivr/8000/ivr-welcome.wav"] {
+        binder.fire(fsr::event_types::PLAYBACK_START, None, &[("Playback-File-Path", path)]);
+        binder.fire(fsr::event_types::PLAYBACK_STOP, None, &[("Playback-Status", "break")]);
+    }
+    set_config(Config::default());
+
+    assert_eq!(scrape("freeswitch_playbacks_total{language=\"en_us\"}"), 1.0);
+    assert_eq!(scrape("freeswitch_playbacks_total{language=\"other\"}"), 1.0);
+    assert_eq!(scrape("freeswitch_playbacks_completed_total{status=\"break\"}"), 2.0);
+}
+
+#[test]
+fn duplicate_hangup_ignored() {
+    let (_guard, binder) = setup();
+    let failed = scrape("freeswitch_sessions_inbound_failed_total");
+    let duplicates = scrape("freeswitch_prometheus_events_duplicate_total");
+    let hangup = [("Call-Direction", "inbound"), ("Unique-ID", "dup-hangup-1"), ("Caller-Channel-Answered-Time", "0")];
+
+    binder.fire(fsr::event_types::CHANNEL_HANGUP, None, &hangup);
+    binder.fire(fsr::event_types::CHANNEL_HANGUP, None, &hangup);
+    assert_eq!(scrape("freeswitch_sessions_inbound_failed_total"), failed + 1.0);
+    assert_eq!(scrape("freeswitch_prometheus_events_duplicate_total"), duplicates + 1.0);
+}
+
+#[test]
+fn journal_changes() {
+    let (_guard, _) = setup();
+    set_config(Config { journal_size: 10, ..Config::default() });
+    let binder = {
+        let mut binder = TestBinder { handlers: Vec::new() };
+        events::bind_events(&mut binder);
+        binder
+    };
+
+    binder.fire(fsr::event_types::HEARTBEAT, None, &[]);
+    let line = events::JOURNAL.lock().unwrap().back().cloned().unwrap();
+    set_config(Config::default());
+    assert!(line.ends_with(" heartbeat | freeswitch_heartbeats_total +1"), "{}", line);
+}
+
+#[test]
+fn event_lag_once_per_event() {
+    let (_guard, binder) = setup();
+    let samples = scrape("freeswitch_event_lag_samples_total");
+    let processed = events::events_processed();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let timestamp = (now.as_secs() * 1_000_000).to_string();
+
+    // CHANNEL_CREATE has several bindings, the event is accounted for once
+    binder.fire(fsr::event_types::CHANNEL_CREATE, None, &[
+        ("Call-Direction", "inbound"), ("Unique-ID", "lag-1"), ("Event-Date-Timestamp", &timestamp),
+    ]);
+    assert_eq!(scrape("freeswitch_event_lag_samples_total"), samples + 1.0);
+    assert_eq!(events::events_processed(), processed + 1);
+}
+
+#[test]
+fn hangup_complete_after_hangup() {
+    let (_guard, binder) = setup();
+    let hangups = scrape("freeswitch_sessions_inbound_hangup");
+    let completed = scrape("freeswitch_sessions_inbound_hangup_complete");
+    let duplicates = scrape("freeswitch_prometheus_events_duplicate_total");
+    let hangup = [
+        ("Call-Direction", "inbound"), ("Unique-ID", "dup-hangup-2"), ("Caller-Channel-Answered-Time", "1000"),
+        ("Hangup-Cause", "NORMAL_CLEARING"), ("variable_billsec", "10"),
+    ];
+
+    // Same call, different events: neither is a duplicate
+    binder.fire(fsr::event_types::CHANNEL_HANGUP, None, &hangup);
+    binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &hangup);
+    assert_eq!(scrape("freeswitch_sessions_inbound_hangup"), hangups + 1.0);
+    assert_eq!(scrape("freeswitch_sessions_inbound_hangup_complete"), completed + 1.0);
+    assert_eq!(scrape("freeswitch_prometheus_events_duplicate_total"), duplicates);
+
+    // A second delivery is counted as one duplicate whatever the number of bindings
+    binder.fire(fsr::event_types::CHANNEL_HANGUP_COMPLETE, None, &hangup);
+    assert_eq!(scrape("freeswitch_sessions_inbound_hangup_complete"), completed + 1.0);
+    assert_eq!(scrape("freeswitch_prometheus_events_duplicate_total"), duplicates + 1.0);
+}
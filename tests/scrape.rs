@@ -0,0 +1,377 @@
+// Scrapes the metrics over HTTP from a listener on an ephemeral port and checks the
+// response parses as the Prometheus text format
+
+// Not everything the modules define is used here
+#![allow(dead_code)]
+
+#[macro_use]
+extern crate freeswitchrs;
+extern crate prometheus_parse;
+
+#[path = "../derived.rs"]
+mod derived;
+#[path = "../registry.rs"]
+mod registry;
+#[path = "../server.rs"]
+mod server;
+
+use std::io::{BufRead, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use prometheus_parse::{Scrape, Value};
+
+use derived::Derived;
+use registry::{Registry, Counter, Gauge, Histogram, Collector, Sample};
+use server::{HttpMetrics, Server, Workers};
+
+struct TestCollector;
+
+impl Collector for TestCollector {
+    fn collect(&mut self) -> Vec<Sample> {
+        vec![Sample::gauge("freeswitch_test_collected", "Collected at scrape time", 7.0)]
+    }
+}
+
+fn get(addr: SocketAddr, request: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response);
+    response
+}
+
+fn sample_registry() -> Arc<Mutex<Registry>> {
+    let counter = Arc::new(Mutex::new(Counter::new("freeswitch_test_calls_total".to_string(),
+                                                   "Test calls".to_string())));
+    let gauge = Arc::new(Mutex::new(Gauge::new("freeswitch_test_active".to_string(),
+                                               "Test active calls".to_string())));
+    counter.lock().unwrap().increment_by(3.0);
+    gauge.lock().unwrap().set(2.0);
+    let reg = Arc::new(Mutex::new(Registry::new()));
+    {
+        let mut r = reg.lock().unwrap();
+        r.register_counter(counter);
+        r.register_gauge(gauge);
+        r.register_collector(Box::new(TestCollector));
+        r.register_derived(Derived::parse("freeswitch_test_ratio", "Active per call",
+                                          "freeswitch_test_active / freeswitch_test_calls_total").unwrap());
+    }
+    reg
+}
+
+#[test]
+fn scrape_parses() {
+    let reg = sample_registry();
+    let workers = Workers::start(1).unwrap();
+    let mut server = Server::new("127.0.0.1".to_string(), vec![0]);
+    let addr = server.start(&reg, &workers).unwrap();
+
+    assert!(reg.lock().unwrap().last_scrape().is_none());
+    let response = get(addr, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(server.address(), Some(addr));
+    server.stop();
+    assert!(reg.lock().unwrap().last_scrape().is_some());
+    assert_eq!(reg.lock().unwrap().metric_count(), 3);
+
+    let mut parts = response.splitn(2, "\r\n\r\n");
+    let head = parts.next().unwrap();
+    let body = parts.next().expect("response without body");
+    assert!(head.starts_with("HTTP/1.1 200 OK"), "unexpected response: {}", head);
+    assert!(head.contains("Content-Type: text/plain; version=0.0.4"));
+    assert!(head.contains(&format!("Content-Length: {}", body.len())));
+
+    let scrape = Scrape::parse(body.as_bytes().lines()).unwrap();
+    let value = |name: &str| {
+        scrape.samples.iter().find(|s| s.metric == name).map(|s| s.value.clone())
+                       .unwrap_or_else(|| panic!("{} missing in:\n{}", name, body))
+    };
+    assert_eq!(value("freeswitch_test_calls_total"), Value::Counter(3.0));
+    assert_eq!(value("freeswitch_test_active"), Value::Gauge(2.0));
+    assert_eq!(value("freeswitch_test_collected"), Value::Gauge(7.0));
+    assert_eq!(value("freeswitch_test_ratio"), Value::Gauge(2.0 / 3.0));
+    assert_eq!(scrape.docs.get("freeswitch_test_calls_total").map(|d| &d[..]), Some("Test calls"));
+}
+
+#[test]
+fn single_value() {
+    let reg = sample_registry();
+    let mut r = reg.lock().unwrap();
+    r.register_derived(Derived::parse("freeswitch_test_calls_rate", "Calls per second",
+                                      "rate(freeswitch_test_calls_total, 60)").unwrap());
+
+    assert_eq!(r.value("freeswitch_test_calls_total"), Some(3.0));
+    assert_eq!(r.value("freeswitch_test_collected"), Some(7.0));
+    assert_eq!(r.value("freeswitch_test_ratio"), Some(2.0 / 3.0));
+    assert_eq!(r.value("freeswitch_test_missing"), None);
+    // Reading takes no sample, the rate needs one taken by a scrape
+    assert!(r.value("freeswitch_test_calls_rate").unwrap().is_nan());
+    assert!(r.value("freeswitch_test_calls_rate").unwrap().is_nan());
+    r.render();
+    thread::sleep(Duration::from_millis(10));
+    assert_eq!(r.value("freeswitch_test_calls_rate"), Some(0 as f64));
+    // Alerts are checked against the collected metrics too
+    assert_eq!(r.values_with_derived().get("freeswitch_test_collected"), Some(&7.0));
+}
+
+#[test]
+fn listeners_share_workers() {
+    let reg = sample_registry();
+    let mut workers = Workers::start(1).unwrap();
+    let mut first = Server::new("127.0.0.1".to_string(), vec![0]);
+    let mut second = Server::new("127.0.0.1".to_string(), vec![0]);
+    let first_addr = first.start(&reg, &workers).unwrap();
+    let second_addr = second.start(&reg, &workers).unwrap();
+
+    assert!(get(first_addr, "GET /metrics HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 200 OK"));
+    assert!(get(second_addr, "GET /metrics HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 200 OK"));
+    first.stop();
+    second.stop();
+    workers.stop(Duration::from_secs(1));
+    assert!(Server::new("127.0.0.1".to_string(), vec![0]).start(&reg, &workers).is_err());
+}
+
+#[test]
+fn proxy_protocol_required() {
+    let reg = sample_registry();
+    let workers = Workers::start(1).unwrap();
+    let mut server = Server::new("127.0.0.1".to_string(), vec![0]);
+    server.set_proxy(true, Vec::new());
+    let addr = server.start(&reg, &workers).unwrap();
+
+    let dropped = get(addr, "GET /metrics HTTP/1.1\r\n\r\n");
+    let served = get(addr, "PROXY TCP4 10.0.0.1 10.0.0.2 40000 9282\r\nGET /metrics HTTP/1.1\r\n\r\n");
+    server.stop();
+
+    assert_eq!(dropped, "");
+    assert!(served.starts_with("HTTP/1.1 200 OK"));
+}
+
+#[test]
+fn port_in_use() {
+    let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = taken.local_addr().unwrap().port();
+    let reg = sample_registry();
+    let workers = Workers::start(1).unwrap();
+
+    let mut server = Server::new("127.0.0.1".to_string(), vec![port]);
+    assert!(server.start(&reg, &workers).is_err());
+
+    let mut server = Server::new("127.0.0.1".to_string(), vec![port, 0]);
+    let addr = server.start(&reg, &workers).unwrap();
+    assert!(addr.port() != port);
+    assert!(get(addr, "GET /metrics HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 200 OK"));
+    server.stop();
+}
+
+#[test]
+fn query_filter() {
+    let reg = sample_registry();
+    let workers = Workers::start(1).unwrap();
+    let mut server = Server::new("127.0.0.1".to_string(), vec![0]);
+    let addr = server.start(&reg, &workers).unwrap();
+
+    let selected = get(addr, "GET /metrics?name%5B%5D=freeswitch_test_active&name[]=freeswitch_test_ratio HTTP/1.1\r\n\r\n");
+    let excluded = get(addr, "GET /metrics?exclude=freeswitch_test_%2A_total HTTP/1.1\r\n\r\n");
+    server.stop();
+
+    let metrics = |response: &str| -> Vec<String> {
+        let body = response.split_once("\r\n\r\n").unwrap().1;
+        Scrape::parse(body.as_bytes().lines()).unwrap().samples.into_iter().map(|s| s.metric).collect()
+    };
+    assert_eq!(metrics(&selected), vec!["freeswitch_test_active", "freeswitch_test_ratio"]);
+    assert_eq!(metrics(&excluded), vec!["freeswitch_test_active", "freeswitch_test_collected", "freeswitch_test_ratio"]);
+}
+
+#[test]
+fn openmetrics_negotiated() {
+    let reg = sample_registry();
+    let histogram = Arc::new(Mutex::new(Histogram::new("freeswitch_test_delay_seconds".to_string(),
+                                                       "Test delay".to_string(), vec![1.0, 5.0])));
+    histogram.lock().unwrap().observe_with_exemplar(0.5, &[("call_uuid", "c0ffee")]);
+    histogram.lock().unwrap().observe_with_exemplar(7.0, &[("call_uuid", &"x".repeat(200))]);
+    reg.lock().unwrap().register_histogram(histogram);
+    let workers = Workers::start(1).unwrap();
+    let mut server = Server::new("127.0.0.1".to_string(), vec![0]);
+    let addr = server.start(&reg, &workers).unwrap();
+
+    // Only negotiated with exemplars enabled
+    let disabled = get(addr, "GET /metrics HTTP/1.1\r\nAccept: application/openmetrics-text\r\n\r\n");
+    server.set_openmetrics(true);
+    let prometheus = get(addr, "GET /metrics HTTP/1.1\r\nAccept: text/plain;version=0.0.4\r\n\r\n");
+    let refused = get(addr, "GET /metrics HTTP/1.1\r\nAccept: application/openmetrics-text;q=0\r\n\r\n");
+    let openmetrics = get(addr, "GET /metrics HTTP/1.1\r\naccept: application/openmetrics-text;version=1.0.0,\
+                                 text/plain;version=0.0.4;q=0.5\r\n\r\n");
+    server.stop();
+
+    for response in [&disabled, &prometheus, &refused].iter() {
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"), "{}", response);
+        assert!(!response.contains("c0ffee") && !response.contains("# EOF"), "{}", response);
+    }
+    let (head, body) = openmetrics.split_once("\r\n\r\n").unwrap();
+    assert!(head.contains("Content-Type: application/openmetrics-text; version=1.0.0; charset=utf-8\r\n"), "{}", head);
+    assert!(body.contains("# TYPE freeswitch_test_calls counter\nfreeswitch_test_calls_total 3\n"), "{}", body);
+    assert!(body.contains("freeswitch_test_delay_seconds_bucket{le=\"1\"} 1 # {call_uuid=\"c0ffee\"} 0.5 "), "{}", body);
+    // No exemplar without labels within the OpenMetrics length limit
+    assert!(body.contains("freeswitch_test_delay_seconds_bucket{le=\"+Inf\"} 2\n"), "{}", body);
+    assert!(body.ends_with("\n# EOF\n"), "{}", body);
+}
+
+#[test]
+fn connection_limit() {
+    let reg = sample_registry();
+    let workers = Workers::start(1).unwrap();
+    let mut server = Server::new("127.0.0.1".to_string(), vec![0]);
+    server.set_limits(1, Duration::from_secs(1), Duration::from_secs(1));
+    let addr = server.start(&reg, &workers).unwrap();
+
+    // Holds the only connection allowed without sending a request
+    let idle = TcpStream::connect(addr).unwrap();
+    let response = get(addr, "GET /metrics HTTP/1.1\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{:?}", response);
+
+    // Disconnected once the read timeout expires, freeing the connection
+    let mut buf = [0; 16];
+    idle.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert_eq!((&idle).read(&mut buf).unwrap(), 0);
+    let response = get(addr, "GET /metrics HTTP/1.1\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}", response);
+    server.stop();
+}
+
+#[test]
+fn request_metrics() {
+    let reg = sample_registry();
+    let workers = Workers::start(1).unwrap();
+    HttpMetrics::register(workers.metrics(), &mut reg.lock().unwrap());
+    let mut server = Server::new("127.0.0.1".to_string(), vec![0]);
+    server.set_access_log(true);
+    let addr = server.start(&reg, &workers).unwrap();
+
+    get(addr, "GET /metrics HTTP/1.1\r\n\r\n");
+    drop(TcpStream::connect(addr).unwrap());
+    // Connections are served concurrently, the closed one is counted once noticed
+    thread::sleep(Duration::from_millis(200));
+    let response = get(addr, "GET /metrics HTTP/1.1\r\n\r\n");
+    server.stop();
+
+    let body = response.split_once("\r\n\r\n").unwrap().1;
+    let scrape = Scrape::parse(body.lines().map(|l| Ok(l.to_string()))).unwrap();
+    let value = |name: &str| scrape.samples.iter().find(|s| s.metric == name).map(|s| s.value.clone());
+    // The second scrape is counted once its response is sent
+    let ok = scrape.samples.iter().find(|s| s.metric == "freeswitch_prometheus_http_requests_total" &&
+                                             s.labels.get("code") == Some("200"));
+    assert_eq!(ok.map(|s| s.value.clone()), Some(Value::Counter(1.0)));
+    assert_eq!(value("freeswitch_prometheus_http_requests_aborted_total"), Some(Value::Counter(1.0)));
+    let clients = workers.metrics().clients();
+    assert_eq!(clients.iter().map(|c| c.0.to_string()).collect::<Vec<String>>(), vec!["127.0.0.1"]);
+    assert!(body.contains("freeswitch_prometheus_http_request_duration_seconds_count 1\n"));
+}
+
+#[test]
+fn stop_drains_scrapes() {
+    let reg = sample_registry();
+    let mut workers = Workers::start(1).unwrap();
+    let mut server = Server::new("127.0.0.1".to_string(), vec![0]);
+    server.set_limits(4, Duration::from_secs(5), Duration::from_secs(1));
+    let addr = server.start(&reg, &workers).unwrap();
+
+    // Both connected before stopping, one sends its request while stopping and the other never does
+    let mut late = TcpStream::connect(addr).unwrap();
+    let mut idle = TcpStream::connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(300));
+    server.stop();
+    assert!(TcpStream::connect(addr).is_err());
+
+    let mut sender = late.try_clone().unwrap();
+    let request = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(300));
+        sender.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+    });
+    let stopping = Instant::now();
+    workers.stop(Duration::from_secs(1));
+    request.join().unwrap();
+    let mut response = String::new();
+    late.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}", response);
+    assert!(response.contains("freeswitch_test_active 2\n"));
+
+    // Past the deadline, well before the read timeout, the connection still waiting for its
+    // request is closed without a response
+    let mut response = String::new();
+    let _ = idle.read_to_string(&mut response);
+    assert_eq!(response, "");
+    assert!(stopping.elapsed() < Duration::from_secs(3));
+}
+
+#[test]
+fn text_format_escaping() {
+    let counter = Arc::new(Mutex::new(Counter::new("freeswitch_test-calls.total".to_string(),
+                                                   "Calls to \"sales\"\nC:\\ drive".to_string())));
+    let mut reg = Registry::new();
+    reg.register_counter(counter);
+    let body = reg.render();
+    assert!(body.contains("# HELP freeswitch_test_calls_total Calls to \"sales\"\\nC:\\\\ drive\n"), "{:?}", body);
+    assert!(body.contains("\nfreeswitch_test_calls_total 0\n"));
+    Scrape::parse(body.lines().map(|l| Ok(l.to_string()))).unwrap();
+}
+
+#[test]
+fn labelled_families() {
+    let gauge = |gateway: &str| {
+        Arc::new(Mutex::new(Gauge::with_labels("freeswitch_gateway_registered".to_string(), "Registered".to_string(),
+                                               &[("gateway", gateway)])))
+    };
+    let (first, second) = (gauge("carrier-1"), gauge("carrier_1"));
+    first.lock().unwrap().set(1.0);
+    let mut reg = Registry::new();
+    reg.register_gauge(first);
+    reg.register_gauge(Arc::new(Mutex::new(Gauge::new("freeswitch_test_active".to_string(), "Active".to_string()))));
+    reg.register_gauge(second);
+    let body = reg.render();
+    // Both series under a single HELP and TYPE, even when registered apart
+    assert_eq!(body.matches("# TYPE freeswitch_gateway_registered gauge\n").count(), 1, "{}", body);
+    assert!(body.contains("freeswitch_gateway_registered{gateway=\"carrier-1\"} 1\n\
+                           freeswitch_gateway_registered{gateway=\"carrier_1\"} 0\n"), "{}", body);
+
+    let scrape = Scrape::parse(body.lines().map(|l| Ok(l.to_string()))).unwrap();
+    let gateways: Vec<String> = scrape.samples.iter().filter(|s| s.metric == "freeswitch_gateway_registered")
+                                      .map(|s| s.labels.get("gateway").unwrap().to_string()).collect();
+    assert_eq!(gateways, vec!["carrier-1", "carrier_1"]);
+}
+
+#[test]
+fn forwarded_client() {
+    let reg = sample_registry();
+    let workers = Workers::start(1).unwrap();
+    let mut server = Server::new("127.0.0.1".to_string(), vec![0]);
+    server.set_proxy(false, vec!["127.0.0.1".parse().unwrap(), "10.0.0.7".parse().unwrap()]);
+    let addr = server.start(&reg, &workers).unwrap();
+    // Served clients are recorded once the connection is closed, the last one is waited for
+    let client_seen = |forwarded: &str, client: &str| {
+        get(addr, &format!("GET /metrics HTTP/1.1\r\nX-Forwarded-For: {}\r\n\r\n", forwarded));
+        (0..50).any(|_| {
+            let seen = workers.metrics().clients().first().map(|c| c.0.to_string());
+            seen.as_ref().map(|c| &c[..]) == Some(client) || {
+                thread::sleep(Duration::from_millis(20));
+                false
+            }
+        })
+    };
+
+    // The first address from the right that is not a trusted proxy, whatever the client put left of it
+    assert!(client_seen("6.6.6.6, 1.2.3.4, 10.0.0.7", "1.2.3.4"));
+    assert!(client_seen("10.0.0.7", "10.0.0.7"));
+    assert!(client_seen("forged, 1.2.3.4", "1.2.3.4"));
+
+    // Checked against the ACL once resolved, the stub ACL only allows loopback addresses
+    server.set_acl(Some("loopback.auto".to_string()));
+    let forbidden = get(addr, "GET /metrics HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\n\r\n");
+    let allowed = get(addr, "GET /metrics HTTP/1.1\r\n\r\n");
+    server.stop();
+    assert!(forbidden.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{:?}", forbidden);
+    assert!(allowed.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}", allowed);
+}